@@ -0,0 +1,27 @@
+#![no_main]
+
+use hch_cgpt::stream::SseDecoder;
+use libfuzzer_sys::fuzz_target;
+
+/// Decode a stream fed in pieces of the given size, keeping only the outcome of
+/// every chunk so results can be compared
+fn decode(stream: &[u8], size: usize) -> Vec<Result<String, ()>> {
+    let mut decoder = SseDecoder::new();
+    let mut chunks: Vec<_> = stream.chunks(size).flat_map(|p| decoder.feed(p)).collect();
+    chunks.extend(decoder.finish());
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.map(|c| c.id).map_err(|_| ()))
+        .collect()
+}
+
+// Feeding the stream in pieces of any size must decode exactly like feeding it at
+// once, without panicking
+fuzz_target!(|data: &[u8]| {
+    let Some((&size, stream)) = data.split_first() else {
+        return;
+    };
+    let whole = decode(stream, stream.len().max(1));
+    let pieces = decode(stream, usize::from(size).max(1));
+    assert_eq!(whole, pieces);
+});
@@ -0,0 +1,104 @@
+use crate::openai::ChatLog;
+use regex::Regex;
+
+/// A pattern replaced everywhere it matches in the contents of a log
+#[derive(Debug, Clone)]
+pub struct RedactionRule {
+    /// The pattern to replace
+    pattern: Regex,
+    /// The replacement, which may refer to groups of the pattern like `$1`
+    replacement: String,
+}
+
+impl RedactionRule {
+    /// Create a rule replacing the matches of a regex, failing if the regex is
+    /// invalid
+    pub fn new(
+        pattern: &str,
+        replacement: impl Into<String>,
+    ) -> Result<RedactionRule, regex::Error> {
+        Ok(RedactionRule {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.into(),
+        })
+    }
+
+    /// Apply the rule to a text
+    pub fn apply(&self, text: &str) -> String {
+        self.pattern
+            .replace_all(text, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Rules redacting the usual secrets and personal data of a session: api keys
+/// as `<api-key>`, emails as `<email>`, phone numbers as `<phone>` and long hex
+/// strings such as tokens and hashes as `<token>`. They are applied in this
+/// order, so keys are redacted before their hex parts could be.
+pub fn default_rules() -> Vec<RedactionRule> {
+    [
+        (r"\b(sk|pk|rk)-[A-Za-z0-9_-]{16,}", "<api-key>"),
+        (r"\bBearer\s+[A-Za-z0-9._~+/-]{16,}=*", "Bearer <api-key>"),
+        (
+            r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b",
+            "<email>",
+        ),
+        (
+            r"\+?\b\d{1,3}[\s.-]?\(?\d{2,4}\)?[\s.-]?\d{3,4}[\s.-]?\d{3,4}\b",
+            "<phone>",
+        ),
+        (r"\b[0-9a-fA-F]{32,}\b", "<token>"),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| {
+        RedactionRule::new(pattern, replacement).expect("the default rules are valid")
+    })
+    .collect()
+}
+
+impl ChatLog {
+    /// A copy of the log with the rules applied in order to the content of every
+    /// entry, to share a session without its secrets, see [`default_rules`].
+    /// The log itself is left untouched.
+    pub fn anonymize(&self, rules: &[RedactionRule]) -> ChatLog {
+        let mut log = self.clone();
+        for entry in log.entries_mut() {
+            for rule in rules {
+                entry.content = rule.apply(&entry.content);
+            }
+        }
+        log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test redacting secrets and personal data with the default rules
+    #[test]
+    fn test_anonymize() {
+        let mut log = ChatLog::new();
+        log.push_user(
+            "I'm jane.doe@example.com, call me at +1 555-123-4567. \
+             My key is sk-proj-abcdefghijklmnop1234 and the hash is \
+             0123456789abcdef0123456789abcdef.",
+        );
+        log.push_assistant("Version 1.2.3 was released in 2024.");
+
+        let anonymized = log.anonymize(&default_rules());
+        assert_eq!(
+            anonymized.entries()[0].content,
+            "I'm <email>, call me at <phone>. My key is <api-key> and the hash is <token>."
+        );
+        assert_eq!(
+            anonymized.entries()[1].content,
+            "Version 1.2.3 was released in 2024."
+        );
+        assert!(log.entries()[0].content.contains("jane.doe"));
+
+        let rule = RedactionRule::new(r"Jane (\w+)", "J. $1").unwrap();
+        assert_eq!(rule.apply("Jane Doe"), "J. Doe");
+        assert!(RedactionRule::new("(", "").is_err());
+    }
+}
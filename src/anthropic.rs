@@ -0,0 +1,97 @@
+use crate::openai::{ChatLog, ChatRole};
+use serde::{Deserialize, Serialize};
+
+/// The roles of the messages of the Anthropic api, which takes the system
+/// prompt separately
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnthropicRole {
+    /// The user
+    #[serde(rename = "user")]
+    User,
+    /// The assistant
+    #[serde(rename = "assistant")]
+    Assistant,
+}
+
+/// A message in the format of the Anthropic messages api
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AnthropicMessage {
+    /// The role of the message
+    pub role: AnthropicRole,
+    /// The text of the message
+    pub content: String,
+}
+
+impl ChatLog {
+    /// Convert the log to the Anthropic messages format: the system prompt for
+    /// the top-level `system` field, and the user and assistant turns.
+    ///
+    /// The contents of all the system and developer entries are joined into the
+    /// system prompt, and consecutive turns of the same role are merged, since
+    /// the Anthropic api expects the roles to alternate.
+    pub fn to_anthropic(&self) -> (Option<String>, Vec<AnthropicMessage>) {
+        let mut system: Option<String> = None;
+        let mut messages: Vec<AnthropicMessage> = Vec::new();
+        for entry in self.entries() {
+            let role = match entry.role {
+                ChatRole::System | ChatRole::Developer => {
+                    match &mut system {
+                        Some(system) => {
+                            system.push_str("\n\n");
+                            system.push_str(&entry.content);
+                        }
+                        None => system = Some(entry.content.clone()),
+                    }
+                    continue;
+                }
+                // Tool results are sent in user turns, here as plain text
+                ChatRole::User | ChatRole::Tool => AnthropicRole::User,
+                ChatRole::Assistant => AnthropicRole::Assistant,
+            };
+            match messages.last_mut() {
+                Some(last) if last.role == role => {
+                    last.content.push_str("\n\n");
+                    last.content.push_str(&entry.content);
+                }
+                _ => messages.push(AnthropicMessage {
+                    role,
+                    content: entry.content.clone(),
+                }),
+            }
+        }
+        (system, messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Test pulling out the system prompt and merging repeated roles
+    #[test]
+    fn test_to_anthropic() {
+        let log: ChatLog = [
+            (ChatRole::System, "You are a pirate."),
+            (ChatRole::User, "Hello"),
+            (ChatRole::User, "Are you there?"),
+            (ChatRole::Assistant, "Arr!"),
+        ]
+        .into_iter()
+        .collect();
+
+        let (system, messages) = log.to_anthropic();
+        assert_eq!(system.as_deref(), Some("You are a pirate."));
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            json!([
+                {"role": "user", "content": "Hello\n\nAre you there?"},
+                {"role": "assistant", "content": "Arr!"}
+            ])
+        );
+
+        let (system, messages) = ChatLog::new().to_anthropic();
+        assert_eq!(system, None);
+        assert!(messages.is_empty());
+    }
+}
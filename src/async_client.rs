@@ -0,0 +1,171 @@
+use crate::error::OpenAIError;
+use crate::openai::{
+    ChatCompletionRequest, ChatCompletionResponse, ChatLog, CHAT_COMPLETIONS_URL,
+    DEFAULT_USER_AGENT,
+};
+use crate::stream::{SseDecoder, StreamAccumulator, StreamEvent};
+use futures_util::{future, stream, FutureExt, Stream, StreamExt};
+
+/// Async OpenAI api clients, for use from an async runtime
+pub struct AsyncOpenAI {
+    /// HTTP client
+    client: reqwest::Client,
+    /// OpenAI api key
+    api_key: String,
+}
+
+impl AsyncOpenAI {
+    /// Create a new async OpenAI client, sending the [`DEFAULT_USER_AGENT`]
+    pub fn new(api_key: String) -> AsyncOpenAI {
+        let client = reqwest::Client::builder()
+            .user_agent(DEFAULT_USER_AGENT)
+            .build()
+            .expect("the default HTTP client configuration is valid");
+        AsyncOpenAI { client, api_key }
+    }
+
+    /// Complete a chat
+    pub async fn complete_chat(
+        &self,
+        chat: ChatLog,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let request = ChatCompletionRequest::from(chat);
+        self.complete_request(&request).await
+    }
+
+    /// Complete a request
+    pub async fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        for tool in request.tools() {
+            tool.validate()?;
+        }
+        let response = self
+            .client
+            .post(CHAT_COMPLETIONS_URL)
+            .bearer_auth(&self.api_key)
+            .json(request)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(OpenAIError::api(status.as_u16(), &body));
+        }
+        let mut response = ChatCompletionResponse::parse_completion(&body)?;
+        if let Some(prefill) = request.prefill() {
+            response.join_prefill(prefill);
+        }
+        Ok(response)
+    }
+
+    /// Stream a chat, yielding the pieces of the content as they arrive. The
+    /// request is sent when the stream is first polled, and dropping the stream
+    /// cancels it.
+    pub fn stream_chat(
+        &self,
+        log: ChatLog,
+    ) -> impl Stream<Item = Result<String, OpenAIError>> {
+        let request = ChatCompletionRequest::from(log).streaming();
+        let response = self
+            .client
+            .post(CHAT_COMPLETIONS_URL)
+            .bearer_auth(&self.api_key)
+            .json(&request)
+            .send();
+
+        async move {
+            let response = response.await?;
+            let status = response.status();
+            if !status.is_success() {
+                let body = response.text().await?;
+                return Err(OpenAIError::api(status.as_u16(), &body));
+            }
+            Ok(response.bytes_stream())
+        }
+        .into_stream()
+        .flat_map(|response| match response {
+            Ok(bytes) => decode_tokens(bytes).left_stream(),
+            Err(e) => stream::once(future::ready(Err(e))).right_stream(),
+        })
+    }
+}
+
+/// Decode the tokens carried by a stream of server-sent event bytes, flushing
+/// the decoder at the end of the bytes so a last event without a trailing
+/// blank line still arrives. The chunks go through a [`StreamAccumulator`] like
+/// those of the blocking client.
+fn decode_tokens(
+    bytes: impl Stream<Item = reqwest::Result<bytes::Bytes>>,
+) -> impl Stream<Item = Result<String, OpenAIError>> {
+    let state = Some((Box::pin(bytes), SseDecoder::new(), StreamAccumulator::new()));
+    stream::unfold(state, |state| async move {
+        let (mut bytes, mut decoder, mut accumulator) = state?;
+        let (chunks, done) = match bytes.next().await {
+            Some(Ok(piece)) => (decoder.feed(&piece), false),
+            Some(Err(e)) => (vec![Err(OpenAIError::from(e))], false),
+            None => (decoder.finish().into_iter().collect(), true),
+        };
+        let mut tokens = Vec::new();
+        for chunk in chunks {
+            match chunk {
+                Ok(chunk) => {
+                    tokens.extend(accumulator.events(&chunk).into_iter().filter_map(
+                        |event| match event {
+                            StreamEvent::Token { index: 0, text } => Some(Ok(text)),
+                            _ => None,
+                        },
+                    ))
+                }
+                Err(e) => tokens.push(Err(e)),
+            }
+        }
+        let state = (!done).then_some((bytes, decoder, accumulator));
+        Some((tokens, state))
+    })
+    .flat_map(stream::iter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test decoding tokens from bytes split at arbitrary places
+    #[test]
+    fn test_decode_tokens() {
+        let body = concat!(
+            "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,",
+            "\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"héllo\"},",
+            "\"finish_reason\":null}]}\n\ndata: [DONE]\n\n"
+        )
+        .as_bytes();
+        // Split in the middle of the two bytes of `é`
+        let split = body.iter().position(|&b| b == 0xc3).unwrap() + 1;
+        let parts = vec![
+            Ok(bytes::Bytes::copy_from_slice(&body[..split])),
+            Ok(bytes::Bytes::copy_from_slice(&body[split..])),
+        ];
+
+        let tokens: Vec<Result<String, OpenAIError>> =
+            decode_tokens(stream::iter(parts))
+                .collect()
+                .now_or_never()
+                .unwrap();
+
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].as_ref().unwrap(), "héllo");
+
+        // A last event without its blank line is flushed at the end
+        let unterminated = body.strip_suffix(b"\n\ndata: [DONE]\n\n").unwrap();
+        let parts = vec![Ok(bytes::Bytes::copy_from_slice(unterminated))];
+        let tokens: Vec<Result<String, OpenAIError>> =
+            decode_tokens(stream::iter(parts))
+                .collect()
+                .now_or_never()
+                .unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].as_ref().unwrap(), "héllo");
+    }
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+/// The severity of the content detected in a category by the Azure content
+/// filters
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterSeverity {
+    /// Nothing was detected
+    Safe,
+    /// Low severity content
+    Low,
+    /// Medium severity content
+    Medium,
+    /// High severity content
+    High,
+}
+
+/// The verdict of the Azure content filters for one category
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FilterCategoryResult {
+    /// Whether the content was blocked because of this category
+    #[serde(default)]
+    pub filtered: bool,
+    /// The severity of the content, for the harm categories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<FilterSeverity>,
+    /// Whether the content was detected, for the detection categories such as
+    /// jailbreaks and protected material
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected: Option<bool>,
+}
+
+/// The verdicts of the Azure content filters for a prompt or a choice. The
+/// categories that were not evaluated are `None`, and unknown ones are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ContentFilterResults {
+    /// Hateful content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hate: Option<FilterCategoryResult>,
+    /// Sexual content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sexual: Option<FilterCategoryResult>,
+    /// Violent content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violence: Option<FilterCategoryResult>,
+    /// Content about self harm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_harm: Option<FilterCategoryResult>,
+    /// Profanity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profanity: Option<FilterCategoryResult>,
+    /// Attempts to jailbreak the model, only evaluated on prompts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jailbreak: Option<FilterCategoryResult>,
+    /// Known text such as song lyrics, only evaluated on completions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_material_text: Option<FilterCategoryResult>,
+    /// Known source code, only evaluated on completions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_material_code: Option<FilterCategoryResult>,
+}
+
+impl ContentFilterResults {
+    /// The evaluated categories with their verdicts, by name
+    pub fn categories(&self) -> Vec<(&'static str, &FilterCategoryResult)> {
+        [
+            ("hate", &self.hate),
+            ("sexual", &self.sexual),
+            ("violence", &self.violence),
+            ("self_harm", &self.self_harm),
+            ("profanity", &self.profanity),
+            ("jailbreak", &self.jailbreak),
+            ("protected_material_text", &self.protected_material_text),
+            ("protected_material_code", &self.protected_material_code),
+        ]
+        .into_iter()
+        .filter_map(|(name, result)| Some((name, result.as_ref()?)))
+        .collect()
+    }
+
+    /// The categories because of which the content was blocked
+    pub fn filtered(&self) -> Vec<&'static str> {
+        let categories = self.categories().into_iter();
+        let filtered = categories.filter(|(_, result)| result.filtered);
+        filtered.map(|(name, _)| name).collect()
+    }
+}
+
+/// The verdicts of the Azure content filters for one prompt of a request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PromptFilterResult {
+    /// The index of the prompt, always 0 for chat completions
+    pub prompt_index: usize,
+    /// The verdicts for the prompt
+    #[serde(default)]
+    pub content_filter_results: ContentFilterResults,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::ChatCompletionResponse;
+
+    /// Test reading the filter results of an Azure response
+    #[test]
+    fn test_azure_filter_results() {
+        let response = ChatCompletionResponse::parse(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o",
+                "prompt_filter_results": [{
+                    "prompt_index": 0,
+                    "content_filter_results": {
+                        "hate": {"filtered": false, "severity": "safe"},
+                        "jailbreak": {"filtered": true, "detected": true},
+                        "custom_blocklists": []
+                    }
+                }],
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": ""},
+                    "finish_reason": "content_filter",
+                    "content_filter_results": {
+                        "violence": {"filtered": true, "severity": "medium"}
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let prompt = &response.prompt_filter_results.as_ref().unwrap()[0];
+        assert_eq!(prompt.content_filter_results.filtered(), ["jailbreak"]);
+        assert_eq!(prompt.content_filter_results.categories().len(), 2);
+        let choice = response.choices[0].content_filter_results.as_ref().unwrap();
+        let violence = choice.violence.as_ref().unwrap();
+        assert_eq!(violence.severity, Some(FilterSeverity::Medium));
+        assert!(FilterSeverity::Medium > FilterSeverity::Low);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::error::OpenAIError;
+use crate::openai::{ChatCompletionRequest, ChatCompletionResponse, CompletionUsage};
+use crate::tokens::{TokenCounter, DEFAULT_COUNTER};
+
+/// Something that can complete chat requests, such as the blocking
+/// [`crate::client::OpenAI`] client or a scripted mock in tests
+pub trait ChatBackend {
+    /// Complete a request
+    fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError>;
+
+    /// Complete a request, calling `on_token` with the pieces of the reply as
+    /// they arrive, and return the usage of the completion if it is known.
+    /// Backends that cannot stream complete the request at once and pass the
+    /// whole reply as a single piece.
+    fn stream_request(
+        &self,
+        request: &ChatCompletionRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Option<CompletionUsage>, OpenAIError> {
+        let response = self.complete_request(request)?;
+        on_token(response.reply()?);
+        Ok(response.usage)
+    }
+
+    /// The counter used to estimate the tokens of requests to this backend
+    fn token_counter(&self) -> &dyn TokenCounter {
+        DEFAULT_COUNTER
+    }
+}
@@ -0,0 +1,127 @@
+use crate::openai::ChatLog;
+
+/// A builder of chat logs, adding one message at a time
+#[derive(Debug, Default)]
+pub struct ChatLogBuilder {
+    /// The log being built
+    log: ChatLog,
+}
+
+impl ChatLogBuilder {
+    /// Start building an empty log
+    pub fn new() -> ChatLogBuilder {
+        ChatLogBuilder::default()
+    }
+
+    /// Add a system message
+    pub fn system(mut self, content: impl Into<String>) -> ChatLogBuilder {
+        self.log.push_system(content);
+        self
+    }
+
+    /// Add a user message
+    pub fn user(mut self, content: impl Into<String>) -> ChatLogBuilder {
+        self.log.push_user(content);
+        self
+    }
+
+    /// Add an assistant message
+    pub fn assistant(mut self, content: impl Into<String>) -> ChatLogBuilder {
+        self.log.push_assistant(content);
+        self
+    }
+
+    /// Finish building the log
+    pub fn build(self) -> ChatLog {
+        self.log
+    }
+}
+
+/// A builder of few-shot prompts: a system prompt, then example user/assistant
+/// pairs, then the real query
+#[derive(Debug)]
+pub struct FewShotBuilder {
+    /// The system prompt
+    system: String,
+    /// The example (input, output) pairs, in order
+    examples: Vec<(String, String)>,
+}
+
+impl FewShotBuilder {
+    /// Start a few-shot prompt with the given system prompt
+    pub fn new(system: impl Into<String>) -> FewShotBuilder {
+        FewShotBuilder {
+            system: system.into(),
+            examples: Vec::new(),
+        }
+    }
+
+    /// Add an example of an input and the output expected for it
+    pub fn example(
+        mut self,
+        input: impl Into<String>,
+        output: impl Into<String>,
+    ) -> FewShotBuilder {
+        self.examples.push((input.into(), output.into()));
+        self
+    }
+
+    /// Add several examples at once
+    pub fn examples<I, O>(
+        mut self,
+        examples: impl IntoIterator<Item = (I, O)>,
+    ) -> FewShotBuilder
+    where
+        I: Into<String>,
+        O: Into<String>,
+    {
+        for (input, output) in examples {
+            self = self.example(input, output);
+        }
+        self
+    }
+
+    /// Build the log, ending with the real query as the last user message
+    pub fn build(self, query: impl Into<String>) -> ChatLog {
+        let builder = ChatLogBuilder::new().system(self.system);
+        self.examples
+            .into_iter()
+            .fold(builder, |builder, (input, output)| {
+                builder.user(input).assistant(output)
+            })
+            .user(query)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::ChatRole;
+
+    /// Test building a few-shot prompt
+    #[test]
+    fn test_few_shot() {
+        let log = FewShotBuilder::new("Translate to French.")
+            .example("cat", "chat")
+            .examples([("dog", "chien")])
+            .build("horse");
+
+        let entries = log.entries();
+        let roles: Vec<&ChatRole> = entries.iter().map(|entry| &entry.role).collect();
+        assert_eq!(
+            roles,
+            vec![
+                &ChatRole::System,
+                &ChatRole::User,
+                &ChatRole::Assistant,
+                &ChatRole::User,
+                &ChatRole::Assistant,
+                &ChatRole::User
+            ]
+        );
+        assert_eq!(entries[2].content, "chat");
+        assert_eq!(entries[5].content, "horse");
+        assert_eq!(log.is_well_formed(), Ok(()));
+    }
+}
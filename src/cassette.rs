@@ -0,0 +1,173 @@
+use crate::backend::ChatBackend;
+use crate::error::OpenAIError;
+use crate::openai::{ChatCompletionRequest, ChatCompletionResponse};
+use crate::tokens::TokenCounter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A recorded request and its response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Recording {
+    /// The body of the request, kept to make cassettes readable
+    request: serde_json::Value,
+    /// The response to the request
+    response: serde_json::Value,
+}
+
+/// Recordings keyed by the hex [`ChatCompletionRequest::content_hash`] of their
+/// request, sorted so cassettes diff well
+type Cassette = BTreeMap<String, Recording>;
+
+/// The key of a request in a cassette
+fn key(request: &ChatCompletionRequest) -> String {
+    format!("{:016x}", request.content_hash())
+}
+
+/// Read a cassette file
+fn load(path: &Path) -> Result<Cassette, OpenAIError> {
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| OpenAIError::decode(e, &json))
+}
+
+/// A backend that completes requests with another one, usually the
+/// [`crate::client::OpenAI`] client, and records every request and response in a
+/// JSON cassette file for a [`ReplayBackend`] to serve later. The file is
+/// rewritten after every request so nothing is lost if the program stops.
+pub struct RecordingBackend<B> {
+    /// The backend that completes the requests
+    inner: B,
+    /// The cassette file
+    path: PathBuf,
+    /// The recordings so far, including those already in the file
+    cassette: Mutex<Cassette>,
+}
+
+impl<B: ChatBackend> RecordingBackend<B> {
+    /// Record the requests completed by `inner` in the cassette at `path`,
+    /// keeping the recordings already in it
+    pub fn new(
+        inner: B,
+        path: impl Into<PathBuf>,
+    ) -> Result<RecordingBackend<B>, OpenAIError> {
+        let path = path.into();
+        let cassette = if path.exists() {
+            load(&path)?
+        } else {
+            Cassette::new()
+        };
+        Ok(RecordingBackend {
+            inner,
+            path,
+            cassette: Mutex::new(cassette),
+        })
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for RecordingBackend<B> {
+    fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = self.inner.complete_request(request)?;
+        let recording = Recording {
+            request: serde_json::to_value(request).expect("requests always serialize"),
+            response: serde_json::to_value(&response)
+                .expect("responses always serialize"),
+        };
+        let mut cassette = self.cassette.lock().unwrap_or_else(|e| e.into_inner());
+        cassette.insert(key(request), recording);
+        let json = serde_json::to_string_pretty(&*cassette)
+            .expect("cassettes always serialize");
+        fs::write(&self.path, json)?;
+        Ok(response)
+    }
+
+    fn token_counter(&self) -> &dyn TokenCounter {
+        self.inner.token_counter()
+    }
+}
+
+/// A backend that serves the responses recorded by a [`RecordingBackend`],
+/// failing with [`OpenAIError::NotRecorded`] on any other request. Requests are
+/// matched on their whole body, so changing a prompt or a setting needs a new
+/// recording.
+pub struct ReplayBackend {
+    /// The recordings
+    cassette: Cassette,
+}
+
+impl ReplayBackend {
+    /// Replay the cassette at `path`
+    pub fn new(path: impl AsRef<Path>) -> Result<ReplayBackend, OpenAIError> {
+        Ok(ReplayBackend {
+            cassette: load(path.as_ref())?,
+        })
+    }
+}
+
+impl ChatBackend for ReplayBackend {
+    fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let key = key(request);
+        let recording = self
+            .cassette
+            .get(&key)
+            .ok_or(OpenAIError::NotRecorded(key))?;
+        serde_json::from_value(recording.response.clone())
+            .map_err(|e| OpenAIError::decode(e, &recording.response.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::openai::ChatLog;
+    use crate::test_support::completion_body;
+
+    /// A backend answering every request with its last message, reversed
+    struct ReverseBackend;
+
+    impl ChatBackend for ReverseBackend {
+        fn complete_request(
+            &self,
+            request: &ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, OpenAIError> {
+            let last = request.messages().entries().last().unwrap();
+            let reply: String = last.content.chars().rev().collect();
+            ChatCompletionResponse::parse(&completion_body("gpt-4o", &reply, "stop"))
+        }
+    }
+
+    /// Test replaying recorded responses and rejecting unrecorded requests
+    #[test]
+    fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.json");
+        let request = |content: &str| {
+            let mut log = ChatLog::new();
+            log.push_user(content);
+            ChatCompletionRequest::new(Model::Gpt4o, log)
+        };
+
+        let recorder = RecordingBackend::new(ReverseBackend, &path).unwrap();
+        recorder.complete_request(&request("hello")).unwrap();
+        let recorder = RecordingBackend::new(ReverseBackend, &path).unwrap();
+        recorder.complete_request(&request("world")).unwrap();
+
+        let replay = ReplayBackend::new(&path).unwrap();
+        let response = replay.complete_request(&request("hello")).unwrap();
+        assert_eq!(response.reply().unwrap(), "olleh");
+        let response = replay.complete_request(&request("world")).unwrap();
+        assert_eq!(response.reply().unwrap(), "dlrow");
+        assert!(matches!(
+            replay.complete_request(&request("other")),
+            Err(OpenAIError::NotRecorded(_))
+        ));
+    }
+}
@@ -0,0 +1,2058 @@
+use crate::backend::ChatBackend;
+use crate::config::ClientConfig;
+use crate::embeddings::{batch_inputs, EmbeddingRequest, EmbeddingResponse};
+use crate::error::{ApiErrorCode, OpenAIError};
+use crate::metrics::UsageLog;
+use crate::model::Model;
+use crate::openai::{
+    api_messages, check_system_placement, default_model, looks_like_misplaced_system,
+    ChatCompletionRequest, ChatCompletionResponse, ChatEntry, ChatLog, ChatRole,
+    CompletionUsage, FinishReason, JsonReply, DEFAULT_BASE_URL, DEFAULT_USER_AGENT,
+};
+use crate::provider::ProviderProfile;
+use crate::rate_limit::RateLimiter;
+use crate::stream::{read_chunks_limited, RawEvents, StreamAccumulator, StreamEvent};
+use crate::text::strip_role_prefix;
+use crate::tokens::{
+    count_schema_tokens, count_tools_tokens_with, default_counter, TokenCounter,
+};
+use crate::tools::{FunctionDefinition, Tool};
+#[cfg(feature = "regex")]
+use regex::Regex;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+use std::ops::ControlFlow;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The delay before the first retry, doubled on every following retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// How many times a truncated reply is continued by [`OnTruncation::Continue`]
+const MAX_CONTINUATIONS: u32 = 4;
+
+/// How long a request abandoned by the watchdog may keep running before the
+/// HTTP client itself gives up on it
+const WATCHDOG_GRACE: Duration = Duration::from_secs(30);
+
+/// How long a completion took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// The time from sending the request to receiving the whole response
+    pub total: Duration,
+    /// The time from sending the request to receiving the first token, only
+    /// measured when streaming
+    pub time_to_first_token: Option<Duration>,
+}
+
+/// What the client does with a reply cut at the token limit, that is with a
+/// first choice finished with [`FinishReason::Length`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnTruncation {
+    /// Return the partial reply as is, leaving the caller to check the finish
+    /// reason
+    #[default]
+    ReturnPartial,
+    /// Fail with [`OpenAIError::Truncated`] holding the partial content
+    Error,
+    /// Request the rest of the reply by sending the partial one as a prefill, up
+    /// to a few times, and return the joined reply with the usage of all the
+    /// calls. A reply still truncated after that fails with
+    /// [`OpenAIError::Truncated`] holding the joined content.
+    Continue,
+}
+
+/// A plain completion request borrowing its messages
+#[derive(Serialize)]
+struct BorrowedRequest<'a> {
+    /// The model used for the completion
+    model: &'a Model,
+    /// The chat log
+    #[serde(serialize_with = "api_messages")]
+    messages: &'a [ChatEntry],
+    /// The sampling temperature, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+}
+
+/// A request body about to be sent, with what the client needs to know about it
+struct Outgoing<'a, T> {
+    /// The serialized body of the request
+    body: &'a T,
+    /// The messages of the request, to estimate its tokens
+    messages: &'a [ChatEntry],
+    /// The tools of the request, whose definitions count as prompt tokens
+    tools: &'a [Tool],
+    /// The legacy functions of the request, which count like tools
+    functions: &'a [FunctionDefinition],
+    /// The model of the request
+    model: &'a Model,
+    /// The prefill to join with the reply
+    prefill: Option<&'a str>,
+    /// Whether to record the usage of the reply in the usage log
+    record_usage: bool,
+}
+
+impl<'a> Outgoing<'a, ChatCompletionRequest> {
+    /// The outgoing version of a prepared request
+    fn of(request: &'a ChatCompletionRequest) -> Outgoing<'a, ChatCompletionRequest> {
+        Outgoing {
+            body: request,
+            messages: request.messages().entries(),
+            tools: request.tools(),
+            functions: request.functions(),
+            model: request.model(),
+            prefill: request.prefill(),
+            record_usage: true,
+        }
+    }
+}
+
+/// A callback receiving the previous and the new system fingerprint of a model
+type FingerprintHook = Box<dyn Fn(&str, &str) + Send + Sync>;
+
+/// A callback rewriting the content of an outgoing message given its role
+type ContentTransform = Box<dyn Fn(&ChatRole, &str) -> String + Send + Sync>;
+
+/// OpenAI api clients
+pub struct OpenAI {
+    /// HTTP client
+    client: reqwest::blocking::Client,
+    /// OpenAI api key
+    api_key: String,
+    /// Called when the system fingerprint of a model changes
+    on_fingerprint_change: Option<FingerprintHook>,
+    /// The last system fingerprint seen for each model
+    fingerprints: Mutex<HashMap<String, String>>,
+    /// How many times a failed request is retried
+    max_retries: usize,
+    /// Whether system messages are sent with the developer role
+    use_developer_role: bool,
+    /// Whether role labels echoed by the model are stripped from replies
+    clean_response: bool,
+    /// The latency of the last completed request, shared with streaming workers
+    last_latency: Arc<Mutex<Option<LatencyReport>>>,
+    /// Counts tokens for the trimming and budget helpers
+    token_counter: Box<dyn TokenCounter + Send + Sync>,
+    /// Throttles requests to stay under the rate limits, if set
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    /// Whether a request over the rate limit fails instead of waiting
+    fail_when_rate_limited: bool,
+    /// The exact snapshot every response must come from, if any
+    require_model: Option<String>,
+    /// How the responses of the provider are decoded
+    provider: ProviderProfile,
+    /// How long a completion may take before it fails, if limited
+    timeout: Option<Duration>,
+    /// What is done with replies cut at the token limit
+    on_truncation: OnTruncation,
+    /// The base url the endpoints are relative to
+    base_url: String,
+    /// The organization the requests are billed to, if not the default one
+    organization: Option<String>,
+    /// The project the requests are billed to, if not the default one
+    project: Option<String>,
+    /// The model of the chats completed without one, if not the default model
+    model: Option<Model>,
+    /// The temperature of the requests that set none, if any
+    temperature: Option<f64>,
+    /// Whether identical requests of a batch are only sent once
+    deduplicate_batches: bool,
+    /// Whether to warn about system prompts sent as user messages
+    warn_system_as_user: bool,
+    /// Rewrites the content of the outgoing messages, if set
+    transform: Option<ContentTransform>,
+    /// The maximum bytes of a response body, if limited
+    max_response_bytes: Option<usize>,
+    /// Where the usage of every completion is recorded, if anywhere
+    usage_log: Option<Arc<UsageLog>>,
+}
+
+/// Builds [`OpenAI`] clients with a custom HTTP configuration
+pub struct OpenAIBuilder {
+    /// OpenAI api key
+    api_key: String,
+    /// The `User-Agent` header sent with every request
+    user_agent: String,
+    /// How the responses of the provider are decoded
+    provider: ProviderProfile,
+    /// What is done with replies cut at the token limit
+    on_truncation: OnTruncation,
+    /// The base url the endpoints are relative to
+    base_url: String,
+    /// The organization the requests are billed to, if not the default one
+    organization: Option<String>,
+    /// The project the requests are billed to, if not the default one
+    project: Option<String>,
+    /// Whether invalid TLS certificates are accepted
+    danger_accept_invalid_certs: bool,
+}
+
+impl OpenAIBuilder {
+    /// Send the given `User-Agent` header instead of [`DEFAULT_USER_AGENT`], to
+    /// identify the application in proxies and provider logs
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> OpenAIBuilder {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Decode responses with the quirks of the given provider, by default the
+    /// strict [`ProviderProfile::OpenAI`]
+    pub fn provider(mut self, provider: ProviderProfile) -> OpenAIBuilder {
+        self.provider = provider;
+        self
+    }
+
+    /// Choose what is done with replies cut at the token limit, by default
+    /// [`OnTruncation::ReturnPartial`]
+    pub fn on_truncation(mut self, on_truncation: OnTruncation) -> OpenAIBuilder {
+        self.on_truncation = on_truncation;
+        self
+    }
+
+    /// Send requests to another server implementing the OpenAI api, such as a
+    /// proxy or a local model, instead of [`DEFAULT_BASE_URL`]. Endpoints are
+    /// appended to it, so it usually ends with `/v1`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> OpenAIBuilder {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Bill requests to the given organization with the `OpenAI-Organization`
+    /// header, for accounts that belong to several
+    pub fn organization(mut self, organization: impl Into<String>) -> OpenAIBuilder {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    /// Bill requests to the given project of the organization with the
+    /// `OpenAI-Project` header, which also applies the rate limits of the
+    /// project
+    pub fn project(mut self, project: impl Into<String>) -> OpenAIBuilder {
+        self.project = Some(project.into());
+        self
+    }
+
+    /// **Dangerous:** accept any TLS certificate, including self-signed,
+    /// expired and forged ones, so anyone on the network can read and change
+    /// the requests, api key included. Only meant for local development
+    /// against a server with a self-signed certificate; never enable it for
+    /// the OpenAI api or any other remote server. Disabled by default, only
+    /// settable here and not from a [`ClientConfig`], and warned about
+    /// through the `log` crate when the client is built.
+    pub fn danger_accept_invalid_certs(mut self, enabled: bool) -> OpenAIBuilder {
+        self.danger_accept_invalid_certs = enabled;
+        self
+    }
+
+    /// Build the client, failing if the HTTP client cannot be configured
+    pub fn build(self) -> Result<OpenAI, OpenAIError> {
+        if self.danger_accept_invalid_certs {
+            log::warn!(
+                "TLS certificates are not verified for {}, only use this for local \
+                 development",
+                self.base_url
+            );
+        }
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(self.user_agent)
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs)
+            .build()?;
+        let mut openai = OpenAI::with_client(client, self.api_key);
+        openai.provider = self.provider;
+        openai.on_truncation = self.on_truncation;
+        openai.base_url = self.base_url;
+        openai.organization = self.organization;
+        openai.project = self.project;
+        Ok(openai)
+    }
+}
+
+impl OpenAI {
+    /// Create a new OpenAI client
+    pub fn new(api_key: String) -> OpenAI {
+        OpenAI::builder(api_key)
+            .build()
+            .expect("the default HTTP client configuration is valid")
+    }
+
+    /// Create a client with the settings of a configuration, typically read
+    /// with [`ClientConfig::from_toml_file`]. The api key is passed separately,
+    /// usually from the `OPENAI_API_KEY` environment variable, so it never has
+    /// to be written in the file.
+    pub fn from_config(
+        config: ClientConfig,
+        api_key: String,
+    ) -> Result<OpenAI, OpenAIError> {
+        let mut builder = OpenAI::builder(api_key);
+        if let Some(base_url) = config.base_url {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(organization) = config.organization {
+            builder = builder.organization(organization);
+        }
+        if let Some(project) = config.project {
+            builder = builder.project(project);
+        }
+        let mut openai = builder.build()?;
+        if let Some(model) = config.model {
+            openai = openai.with_model(model);
+        }
+        if let Some(timeout) = config.timeout {
+            openai = openai.with_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(retries) = config.retries {
+            openai = openai.with_retries(retries);
+        }
+        if let Some(temperature) = config.temperature {
+            openai = openai.with_temperature(temperature);
+        }
+        Ok(openai)
+    }
+
+    /// Start building a client with a custom HTTP configuration
+    pub fn builder(api_key: String) -> OpenAIBuilder {
+        OpenAIBuilder {
+            api_key,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            provider: ProviderProfile::OpenAI,
+            on_truncation: OnTruncation::ReturnPartial,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            organization: None,
+            project: None,
+            danger_accept_invalid_certs: false,
+        }
+    }
+
+    /// Create a client around a configured HTTP client
+    fn with_client(client: reqwest::blocking::Client, api_key: String) -> OpenAI {
+        OpenAI {
+            client,
+            api_key,
+            on_fingerprint_change: None,
+            fingerprints: Mutex::new(HashMap::new()),
+            max_retries: 0,
+            use_developer_role: false,
+            clean_response: false,
+            last_latency: Arc::new(Mutex::new(None)),
+            token_counter: default_counter(),
+            rate_limiter: None,
+            fail_when_rate_limited: false,
+            require_model: None,
+            provider: ProviderProfile::OpenAI,
+            timeout: None,
+            on_truncation: OnTruncation::ReturnPartial,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            organization: None,
+            project: None,
+            model: None,
+            temperature: None,
+            deduplicate_batches: false,
+            warn_system_as_user: false,
+            transform: None,
+            max_response_bytes: None,
+            usage_log: None,
+        }
+    }
+
+    /// Complete chats with the given model instead of the process-wide
+    /// [`default_model`]. Requests built by the caller keep their own model.
+    pub fn with_model(mut self, model: impl Into<Model>) -> OpenAI {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// The model chats are completed with
+    pub fn model(&self) -> Model {
+        self.model.clone().unwrap_or_else(default_model)
+    }
+
+    /// Sample with the given temperature the requests that do not set one
+    pub fn with_temperature(mut self, temperature: f64) -> OpenAI {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Start a post request to an endpoint relative to the base url, with the
+    /// authentication, organization and project headers
+    fn post(&self, endpoint: &str) -> reqwest::blocking::RequestBuilder {
+        self.request(reqwest::Method::POST, endpoint)
+    }
+
+    /// Start a request to an endpoint relative to the base url, with the
+    /// authentication, organization and project headers
+    fn request(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+    ) -> reqwest::blocking::RequestBuilder {
+        let url = format!("{}/{}", self.base_url.trim_end_matches('/'), endpoint);
+        let mut request = self.client.request(method, url).bearer_auth(&self.api_key);
+        if let Some(organization) = &self.organization {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = &self.project {
+            request = request.header("OpenAI-Project", project);
+        }
+        request
+    }
+
+    /// Fail completions that take longer than `timeout` with
+    /// [`OpenAIError::Timeout`]. The request runs on a watchdog thread, so the
+    /// call returns on time even when the HTTP client cannot be interrupted,
+    /// such as behind some proxies. An abandoned request keeps running in the
+    /// background until it finishes or the HTTP client times it out shortly
+    /// after, and its response is dropped. Timeouts are not retried, so that
+    /// abandoned requests do not pile up on a hung server.
+    pub fn with_timeout(mut self, timeout: Duration) -> OpenAI {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Record the usage, cost and latency of every completion in a log, which
+    /// can be shared with other clients and exported as CSV. Streams are
+    /// recorded too, those sent through a channel once their worker is done.
+    /// Calls without usage and [`OpenAI::count_prompt_tokens`] are skipped.
+    pub fn with_usage_log(mut self, log: Arc<UsageLog>) -> OpenAI {
+        self.usage_log = Some(log);
+        self
+    }
+
+    /// Fail with [`OpenAIError::ResponseTooLarge`] instead of reading more than
+    /// `max_bytes` bytes of a response body, to protect the process from an
+    /// untrusted compatible server sending an endless body. Streamed replies
+    /// are limited too, the chunks before the limit are still handled.
+    /// Unlimited by default.
+    pub fn with_max_response_bytes(mut self, max_bytes: usize) -> OpenAI {
+        self.max_response_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Fail with [`OpenAIError::ModelMismatch`] when a response reports another
+    /// model than the given snapshot, such as `gpt-4o-2024-08-06`. This catches
+    /// an alias silently resolving to a new snapshot in reproducible pipelines.
+    pub fn require_model(mut self, snapshot: impl Into<String>) -> OpenAI {
+        self.require_model = Some(snapshot.into());
+        self
+    }
+
+    /// Check that a response comes from the required snapshot, if any
+    fn check_model(
+        &self,
+        response: &ChatCompletionResponse,
+    ) -> Result<(), OpenAIError> {
+        match &self.require_model {
+            Some(expected) if *expected != response.model => {
+                Err(OpenAIError::ModelMismatch {
+                    expected: expected.clone(),
+                    got: response.model.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Throttle requests to stay under the given requests and tokens per minute,
+    /// over a rolling window. The tokens of a request are estimated from its
+    /// messages with the token counter of the client. Requests over the limit
+    /// block until they fit, see [`OpenAI::fail_when_rate_limited`].
+    pub fn with_rate_limit(
+        mut self,
+        requests_per_minute: u32,
+        tokens_per_minute: u32,
+    ) -> OpenAI {
+        let limiter = RateLimiter::new(requests_per_minute, tokens_per_minute);
+        self.rate_limiter = Some(Mutex::new(limiter));
+        self
+    }
+
+    /// Fail requests over the rate limit with [`OpenAIError::RateLimited`]
+    /// instead of blocking until they fit
+    pub fn fail_when_rate_limited(mut self, enabled: bool) -> OpenAI {
+        self.fail_when_rate_limited = enabled;
+        self
+    }
+
+    /// Wait until the request fits in the rate limit, if there is one
+    fn throttle(
+        &self,
+        messages: &[ChatEntry],
+        tools: &[Tool],
+        functions: &[FunctionDefinition],
+        model: &Model,
+    ) -> Result<(), OpenAIError> {
+        let Some(limiter) = &self.rate_limiter else {
+            return Ok(());
+        };
+        let counter = self.token_counter();
+        let tokens: usize = messages
+            .iter()
+            .map(|entry| counter.count(&entry.content, model))
+            .sum::<usize>()
+            + count_tools_tokens_with(counter, tools, model)
+            + count_schema_tokens(counter, functions, model);
+        let tokens = u32::try_from(tokens).unwrap_or(u32::MAX);
+        loop {
+            let acquired = limiter
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .try_acquire(tokens, Instant::now());
+            match acquired {
+                Ok(()) => return Ok(()),
+                Err(retry_after) if self.fail_when_rate_limited => {
+                    return Err(OpenAIError::RateLimited { retry_after });
+                }
+                Err(wait) => thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Count tokens with the given counter instead of the default one, for
+    /// example a [`crate::tokens::HeuristicCounter`] to avoid loading tiktoken
+    pub fn with_token_counter(
+        mut self,
+        counter: impl TokenCounter + Send + Sync + 'static,
+    ) -> OpenAI {
+        self.token_counter = Box::new(counter);
+        self
+    }
+
+    /// The token counter of the client
+    pub fn token_counter(&self) -> &dyn TokenCounter {
+        &*self.token_counter
+    }
+
+    /// The latency of the last request that completed successfully, including
+    /// the time to first token if it was streamed
+    pub fn last_latency(&self) -> Option<LatencyReport> {
+        *self.last_latency.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send system messages with the `developer` role, for newer models and
+    /// compatible providers that expect it. Logs are left untouched, only the
+    /// outgoing requests are changed.
+    pub fn use_developer_role(mut self, enabled: bool) -> OpenAI {
+        self.use_developer_role = enabled;
+        self
+    }
+
+    /// Strip a leading `Assistant:` or `AI:` label from the content of every
+    /// choice, see [`strip_role_prefix`] for the exact patterns. Disabled by
+    /// default since it can rarely strip legitimate content.
+    pub fn clean_response(mut self, enabled: bool) -> OpenAI {
+        self.clean_response = enabled;
+        self
+    }
+
+    /// Send identical requests of a [`OpenAI::complete_batch`] only once and
+    /// share the response among them. Requests are identical when their
+    /// [`ChatCompletionRequest::content_hash`] is.
+    ///
+    /// Disabled by default: with a temperature above 0, identical requests are
+    /// usually meant as independent samples, and deduplicating them returns
+    /// the same reply for all of them.
+    pub fn deduplicate_batches(mut self, enabled: bool) -> OpenAI {
+        self.deduplicate_batches = enabled;
+        self
+    }
+
+    /// Warn through the `log` crate when the first message of a request is a
+    /// user message starting with "You are", which is usually a system prompt
+    /// pushed with [`ChatLog::push_user`]. The heuristic has false positives,
+    /// such as a user quoting someone, so it is disabled by default; see
+    /// [`ChatLog::assert_first_is_system`] for a strict check in tests.
+    pub fn warn_system_as_user(mut self, enabled: bool) -> OpenAI {
+        self.warn_system_as_user = enabled;
+        self
+    }
+
+    /// Rewrite the content of every outgoing message with a callback given its
+    /// role and content, for example to wrap user messages in a template,
+    /// translate the system prompt or redact personal data. Only the requests
+    /// are changed, the logs they were built from are left intact. A prefill is
+    /// sent untransformed, so replies still start with the prefill that was set.
+    pub fn with_transform(
+        mut self,
+        transform: impl Fn(&ChatRole, &str) -> String + Send + Sync + 'static,
+    ) -> OpenAI {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Apply the client settings to an outgoing request
+    fn prepare<'r>(
+        &self,
+        request: &'r ChatCompletionRequest,
+    ) -> Cow<'r, ChatCompletionRequest> {
+        let mut request = Cow::Borrowed(request);
+        let entries = request.messages().entries();
+        let has_system = entries.iter().any(|entry| entry.role == ChatRole::System);
+        if self.use_developer_role && has_system {
+            request = Cow::Owned(request.into_owned().with_developer_role());
+        }
+        if let (Some(temperature), None) = (self.temperature, request.temperature()) {
+            request = Cow::Owned(request.into_owned().with_temperature(temperature));
+        }
+        if let Some(transform) = &self.transform {
+            request = Cow::Owned(request.into_owned().map_contents(transform));
+        }
+        request
+    }
+
+    /// Retry requests failing with HTTP timeouts, connection errors, rate limits
+    /// or server errors up to `max_retries` times, with exponential backoff.
+    /// The watchdog timeouts of [`OpenAI::with_timeout`] are not retried.
+    ///
+    /// Every logical request is sent with an `Idempotency-Key` header that is
+    /// reused by all its retries, so the server can deduplicate a request that
+    /// went through but whose response was lost, instead of billing it twice.
+    pub fn with_retries(mut self, max_retries: usize) -> OpenAI {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set a callback that is called with the old and the new fingerprint whenever
+    /// the `system_fingerprint` reported for a model differs from the last one
+    /// seen by this client. This usually means the backend serving the model was
+    /// changed, which can shift its output.
+    pub fn on_fingerprint_change(
+        mut self,
+        hook: impl Fn(&str, &str) + Send + Sync + 'static,
+    ) -> OpenAI {
+        self.on_fingerprint_change = Some(Box::new(hook));
+        self
+    }
+
+    /// Remember the fingerprint of a response, firing the hook if it changed
+    fn record_fingerprint(&self, response: &ChatCompletionResponse) {
+        let Some(fingerprint) = &response.system_fingerprint else {
+            return;
+        };
+        let mut fingerprints =
+            self.fingerprints.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = fingerprints.insert(response.model.clone(), fingerprint.clone());
+        if let (Some(hook), Some(previous)) = (&self.on_fingerprint_change, previous) {
+            if previous != *fingerprint {
+                hook(&previous, fingerprint);
+            }
+        }
+    }
+
+    /// Complete a chat
+    pub fn complete_chat(
+        &self,
+        chat: ChatLog,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let request = ChatCompletionRequest::new(self.model(), chat);
+        self.complete_request(&request)
+    }
+
+    /// Complete a chat in JSON mode and decode the reply, see
+    /// [`ChatCompletionRequest::with_json_mode`]. With `repair`, a reply cut
+    /// short is closed before decoding, as described in
+    /// [`ChatCompletionResponse::json_reply`], and the result tells whether it
+    /// was; the repaired value may be semantically incomplete.
+    pub fn complete_chat_json<T: DeserializeOwned>(
+        &self,
+        chat: ChatLog,
+        repair: bool,
+    ) -> Result<JsonReply<T>, OpenAIError> {
+        let request = ChatCompletionRequest::new(self.model(), chat).with_json_mode();
+        self.complete_request(&request)?.json_reply(repair)
+    }
+
+    /// Complete a chat borrowed from the caller with the model of the client, so a
+    /// long history can be sent every turn without cloning it. The messages are
+    /// only copied when they must be rewritten for [`OpenAI::use_developer_role`]
+    /// or [`OpenAI::with_transform`].
+    pub fn complete_chat_ref(
+        &self,
+        messages: &[ChatEntry],
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let has_system = messages.iter().any(|entry| entry.role == ChatRole::System);
+        if (self.use_developer_role && has_system) || self.transform.is_some() {
+            return self.complete_chat(ChatLog::from(messages.to_vec()));
+        }
+        let model = self.model();
+        let body = BorrowedRequest {
+            model: &model,
+            messages,
+            temperature: self.temperature,
+        };
+        let response = self.send_with_retries(Outgoing {
+            body: &body,
+            messages,
+            tools: &[],
+            functions: &[],
+            model: &model,
+            prefill: None,
+            record_usage: true,
+        })?;
+        self.handle_truncation(response, || {
+            ChatCompletionRequest::new(model.clone(), ChatLog::from(messages.to_vec()))
+        })
+    }
+
+    /// Complete a request, retrying it with the same idempotency key if allowed
+    pub fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = self.send_prepared(request)?;
+        self.handle_truncation(response, || request.clone())
+    }
+
+    /// Complete a batch of requests one after the other. The results are in
+    /// the order of the requests, and a failed request does not stop the
+    /// batch. See [`OpenAI::deduplicate_batches`] to send identical requests
+    /// once.
+    pub fn complete_batch(
+        &self,
+        requests: &[ChatCompletionRequest],
+    ) -> Vec<Result<ChatCompletionResponse, OpenAIError>> {
+        if self.deduplicate_batches {
+            complete_deduplicated(requests, |request| self.complete_request(request))
+        } else {
+            requests
+                .iter()
+                .map(|request| self.complete_request(request))
+                .collect()
+        }
+    }
+
+    /// Complete the same chat with every model, one after the other, for
+    /// example to evaluate them side by side with
+    /// [`crate::eval::comparison_table`]. Every call goes through the rate
+    /// limit and retries of the client, and a failed call does not stop the
+    /// others.
+    pub fn compare_models(
+        &self,
+        log: &ChatLog,
+        models: &[Model],
+    ) -> Vec<(Model, Result<ChatCompletionResponse, OpenAIError>)> {
+        models
+            .iter()
+            .map(|model| {
+                let request = ChatCompletionRequest::new(model.clone(), log.clone());
+                (model.clone(), self.complete_request(&request))
+            })
+            .collect()
+    }
+
+    /// Send a request with the client settings applied
+    fn send_prepared(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let request = self.prepare(request);
+        self.send_with_retries(Outgoing::of(&request))
+    }
+
+    /// Apply the truncation policy to a response. The request is only built if
+    /// the reply must be continued.
+    fn handle_truncation(
+        &self,
+        mut response: ChatCompletionResponse,
+        request: impl FnOnce() -> ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let truncated = |response: &ChatCompletionResponse| {
+            let choice = response.choices.first();
+            choice.is_some_and(|choice| choice.finish_reason == FinishReason::Length)
+        };
+        if !truncated(&response) {
+            return Ok(response);
+        }
+        match self.on_truncation {
+            OnTruncation::ReturnPartial => Ok(response),
+            OnTruncation::Error => {
+                Err(OpenAIError::Truncated(response.reply()?.to_string()))
+            }
+            OnTruncation::Continue => {
+                let request = request();
+                for _ in 0..MAX_CONTINUATIONS {
+                    let partial = response.reply()?.to_string();
+                    let continuation = request.clone().with_prefill(partial);
+                    let next = self.send_prepared(&continuation)?;
+                    let usage = match (&response.usage, &next.usage) {
+                        (Some(before), Some(after)) => Some(before.combine(after)),
+                        _ => None,
+                    };
+                    response = ChatCompletionResponse { usage, ..next };
+                    if !truncated(&response) {
+                        return Ok(response);
+                    }
+                }
+                Err(OpenAIError::Truncated(response.reply()?.to_string()))
+            }
+        }
+    }
+
+    /// Send a request, retrying it with the same idempotency key if allowed
+    fn send_with_retries<T: Serialize>(
+        &self,
+        outgoing: Outgoing<'_, T>,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        for tool in outgoing.tools {
+            tool.validate()?;
+        }
+        if let Err(e) = check_system_placement(outgoing.messages) {
+            log::warn!("{}", e);
+        }
+        if self.warn_system_as_user && looks_like_misplaced_system(outgoing.messages) {
+            log::warn!("the first message looks like a system prompt sent as user");
+        }
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+        let mut attempt = 0;
+        loop {
+            match self.send_request(&outgoing, &idempotency_key) {
+                Err(e) if attempt < self.max_retries && e.is_retryable() => {
+                    std::thread::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt as u32));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Ask the server for the exact amount of prompt tokens of a request, by
+    /// sending it with a token limit of zero so no reply is generated. This is
+    /// still a real, if cheap, api call billed for its prompt tokens, and it is
+    /// left out of the usage log.
+    pub fn count_prompt_tokens(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<u32, OpenAIError> {
+        // A reply cut at zero tokens is expected, so skip the truncation policy
+        let request = request.clone().with_max_tokens(0);
+        let request = self.prepare(&request);
+        let response = self.send_with_retries(Outgoing {
+            record_usage: false,
+            ..Outgoing::of(&request)
+        })?;
+        let usage = response.usage.ok_or(OpenAIError::NoUsage)?;
+        Ok(usage.prompt_tokens)
+    }
+
+    /// Send a single completion request
+    fn send_request<T: Serialize>(
+        &self,
+        outgoing: &Outgoing<'_, T>,
+        idempotency_key: &str,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        self.throttle(
+            outgoing.messages,
+            outgoing.tools,
+            outgoing.functions,
+            outgoing.model,
+        )?;
+
+        // Make post request to OpenAI
+        let start = Instant::now();
+        let request = self
+            .post("chat/completions")
+            .header("Idempotency-Key", idempotency_key)
+            .json(outgoing.body)
+            .build()?;
+        let max_bytes = self.max_response_bytes;
+        let (status, body) = match self.timeout {
+            Some(timeout) => {
+                execute_with_watchdog(&self.client, request, timeout, max_bytes)?
+            }
+            None => read_response(self.client.execute(request)?, max_bytes)?,
+        };
+        if !status.is_success() {
+            return Err(OpenAIError::api(status.as_u16(), &body));
+        }
+
+        let mut response = self.provider.parse_completion(&body)?;
+        self.check_model(&response)?;
+        if self.clean_response {
+            for choice in &mut response.choices {
+                let content = &mut choice.message.content;
+                *content = strip_role_prefix(content).to_string();
+            }
+        }
+        if let Some(prefill) = outgoing.prefill {
+            response.join_prefill(prefill);
+        }
+        let latency = start.elapsed();
+        record_latency(&self.last_latency, latency, None);
+        self.record_fingerprint(&response);
+        if outgoing.record_usage {
+            let finish_reason =
+                response.choices.first().map(|c| c.finish_reason.clone());
+            log_usage(
+                self.usage_log.as_deref(),
+                &response.model,
+                response.usage.as_ref(),
+                latency,
+                finish_reason,
+            );
+        }
+        Ok(response)
+    }
+
+    /// Check the api key with a cheap authenticated request to the models
+    /// endpoint, to fail fast on startup instead of on the first completion. A
+    /// rejected key fails with [`OpenAIError::InvalidKey`], other failures with
+    /// their usual error. The list of models is discarded.
+    pub fn validate_key(&self) -> Result<(), OpenAIError> {
+        let response = self.request(reqwest::Method::GET, "models").send()?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let (_, body) = read_response(response, self.max_response_bytes)?;
+        match OpenAIError::api(status.as_u16(), &body) {
+            OpenAIError::Api {
+                status: 401,
+                message,
+                ..
+            }
+            | OpenAIError::Api {
+                message,
+                code: Some(ApiErrorCode::InvalidApiKey),
+                ..
+            } => Err(OpenAIError::InvalidKey(message)),
+            error => Err(error),
+        }
+    }
+
+    /// Embed texts with the given embedding model, in a single request
+    pub fn embed(
+        &self,
+        inputs: &[String],
+        model: &str,
+    ) -> Result<Vec<Vec<f32>>, OpenAIError> {
+        let request = EmbeddingRequest {
+            model,
+            input: inputs,
+        };
+        let response = self.post("embeddings").json(&request).send()?;
+        let (status, body) = read_response(response, self.max_response_bytes)?;
+        if !status.is_success() {
+            return Err(OpenAIError::api(status.as_u16(), &body));
+        }
+        let response: EmbeddingResponse =
+            serde_json::from_str(&body).map_err(|e| OpenAIError::decode(e, &body))?;
+        Ok(response.into_vectors())
+    }
+
+    /// Embed any amount of texts, splitting them into as many requests as the
+    /// per request limits need, see [`batch_inputs`]. Tokens are estimated with
+    /// the token counter of the client. The vectors are in the order of the
+    /// inputs.
+    pub fn embed_all(
+        &self,
+        inputs: &[String],
+        model: &str,
+    ) -> Result<Vec<Vec<f32>>, OpenAIError> {
+        let counter = self.token_counter();
+        let embedding_model = Model::from(model);
+        let tokens: Vec<usize> = inputs
+            .iter()
+            .map(|input| counter.count(input, &embedding_model))
+            .collect();
+        let mut vectors = Vec::with_capacity(inputs.len());
+        for batch in batch_inputs(&tokens)? {
+            vectors.extend(self.embed(&inputs[batch], model)?);
+        }
+        Ok(vectors)
+    }
+
+    /// Stream a chat on a worker thread, sending its tokens and finish reason
+    /// through a channel as they arrive. The handle of the worker resolves to the
+    /// usage of the completion once the stream ends. If the stream fails, the
+    /// error is sent as the last event and the handle resolves to a
+    /// [`OpenAIError::Stream`] describing it.
+    pub fn stream_chat_channel(
+        &self,
+        log: ChatLog,
+    ) -> (
+        Receiver<StreamEvent>,
+        JoinHandle<Result<CompletionUsage, OpenAIError>>,
+    ) {
+        self.stream_chat_channel_with_progress(log, |_| {})
+    }
+
+    /// Stream a chat like [`OpenAI::stream_chat_channel`], also calling
+    /// `on_progress` on the worker thread after every chunk with the amount of
+    /// content tokens of the first choice received so far, to drive a live
+    /// counter or progress bar
+    pub fn stream_chat_channel_with_progress(
+        &self,
+        log: ChatLog,
+        mut on_progress: impl FnMut(usize) + Send + 'static,
+    ) -> (
+        Receiver<StreamEvent>,
+        JoinHandle<Result<CompletionUsage, OpenAIError>>,
+    ) {
+        let request = ChatCompletionRequest::new(self.model(), log);
+        // Build the request here so the worker doesn't need the api key
+        let built = self.build_stream(&request);
+        let client = self.client.clone();
+        let latency = self.last_latency.clone();
+        let max_response_bytes = self.max_response_bytes;
+        let usage_log = self.usage_log.clone();
+        let model = request.model().name().to_string();
+
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let result = built.and_then(|request| {
+                let mut on_event = |event| {
+                    // The receiver may be gone, the usage is still worth returning
+                    let _ = sender.send(event);
+                };
+                let stream = send_stream(
+                    &client,
+                    request,
+                    max_response_bytes,
+                    &latency,
+                    &mut on_event,
+                    &mut on_progress,
+                    &mut |_| None,
+                );
+                stream.and_then(|reply| {
+                    log_usage(
+                        usage_log.as_deref(),
+                        &model,
+                        reply.usage.as_ref(),
+                        start.elapsed(),
+                        reply.finish_reason,
+                    );
+                    stream_usage(reply.usage)
+                })
+            });
+            result.map_err(|e| {
+                let message = e.to_string();
+                let _ = sender.send(StreamEvent::Error(e));
+                OpenAIError::Stream(message)
+            })
+        });
+        (receiver, handle)
+    }
+
+    /// Stream a chat and yield the raw data of every server-sent event, before
+    /// any decoding, to see exactly what a provider sends when its stream
+    /// misbehaves. The `[DONE]` sentinel is yielded too. A failed request or
+    /// error status is yielded as the only item. See
+    /// [`OpenAI::stream_request`] for decoded streams.
+    pub fn stream_raw(
+        &self,
+        log: ChatLog,
+    ) -> impl Iterator<Item = Result<String, OpenAIError>> {
+        let request = ChatCompletionRequest::new(self.model(), log);
+        let response = self.build_stream(&request).and_then(|request| {
+            let response = self.client.execute(request)?;
+            let status = response.status();
+            if !status.is_success() {
+                let (_, body) = read_response(response, self.max_response_bytes)?;
+                return Err(OpenAIError::api(status.as_u16(), &body));
+            }
+            Ok(response)
+        });
+        let (error, events) = match response {
+            Ok(response) => {
+                let events = RawEvents::new(BufReader::new(response));
+                let events = match self.max_response_bytes {
+                    Some(max_bytes) => events.with_max_bytes(max_bytes),
+                    None => events,
+                };
+                (None, Some(events))
+            }
+            Err(e) => (Some(Err(e)), None),
+        };
+        error.into_iter().chain(events.into_iter().flatten())
+    }
+
+    /// Build the streaming version of a request with the client settings,
+    /// waiting for the rate limit first
+    fn build_stream(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<reqwest::blocking::Request, OpenAIError> {
+        for tool in request.tools() {
+            tool.validate()?;
+        }
+        let request = request.clone().streaming();
+        let request = self.prepare(&request);
+        let entries = request.messages().entries();
+        self.throttle(
+            entries,
+            request.tools(),
+            request.functions(),
+            request.model(),
+        )?;
+        Ok(self.post("chat/completions").json(&*request).build()?)
+    }
+
+    /// Stream a request on the calling thread, calling `on_token` with every
+    /// piece of the content of the first choice as it arrives, and return the
+    /// whole message with its tool calls and the usage
+    pub fn stream_request(
+        &self,
+        request: &ChatCompletionRequest,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<(ChatEntry, CompletionUsage), OpenAIError> {
+        let built = self.build_stream(request)?;
+        let mut on_event = |event| {
+            if let StreamEvent::Token { index: 0, text } = event {
+                on_token(&text);
+            }
+        };
+        let start = Instant::now();
+        let reply = send_stream(
+            &self.client,
+            built,
+            self.max_response_bytes,
+            &self.last_latency,
+            &mut on_event,
+            &mut |_| {},
+            &mut |_| None,
+        )?;
+        log_usage(
+            self.usage_log.as_deref(),
+            request.model().name(),
+            reply.usage.as_ref(),
+            start.elapsed(),
+            reply.finish_reason,
+        );
+        Ok((reply.entry, stream_usage(reply.usage)?))
+    }
+
+    /// Stream a request like [`OpenAI::stream_request`], but stop as soon as
+    /// the content so far matches `stop`, for example when the model starts
+    /// repeating itself or writes a sentinel. The connection is then closed and
+    /// the content is cut at the end of the match, or at its start if
+    /// `include_match` is not set. Tokens already passed to `on_token` are not
+    /// taken back, so they can run past the cut.
+    ///
+    /// The match is tested against the whole content after every chunk, unlike
+    /// the stop sequences of the api, which are literal strings checked on the
+    /// server. Returns the usage only if the stream ran to the end, since the
+    /// api only sends it in the last chunk.
+    #[cfg(feature = "regex")]
+    pub fn stream_request_until(
+        &self,
+        request: &ChatCompletionRequest,
+        stop: &Regex,
+        include_match: bool,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<(ChatEntry, Option<CompletionUsage>), OpenAIError> {
+        let built = self.build_stream(request)?;
+        let mut on_event = |event| {
+            if let StreamEvent::Token { index: 0, text } = event {
+                on_token(&text);
+            }
+        };
+        let mut stopped = false;
+        let mut stop_at = |content: &str| {
+            let found = stop.find(content)?;
+            stopped = true;
+            Some(if include_match {
+                found.end()
+            } else {
+                found.start()
+            })
+        };
+        let start = Instant::now();
+        let reply = send_stream(
+            &self.client,
+            built,
+            self.max_response_bytes,
+            &self.last_latency,
+            &mut on_event,
+            &mut |_| {},
+            &mut stop_at,
+        )?;
+        log_usage(
+            self.usage_log.as_deref(),
+            request.model().name(),
+            reply.usage.as_ref(),
+            start.elapsed(),
+            reply.finish_reason,
+        );
+        if stopped {
+            return Ok((reply.entry, reply.usage));
+        }
+        Ok((reply.entry, Some(stream_usage(reply.usage)?)))
+    }
+}
+
+impl ChatBackend for OpenAI {
+    fn complete_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        OpenAI::complete_request(self, request)
+    }
+
+    fn stream_request(
+        &self,
+        request: &ChatCompletionRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Option<CompletionUsage>, OpenAIError> {
+        let (_, usage) = OpenAI::stream_request(self, request, on_token)?;
+        Ok(Some(usage))
+    }
+
+    fn token_counter(&self) -> &dyn TokenCounter {
+        OpenAI::token_counter(self)
+    }
+}
+
+/// Complete the requests of a batch, calling `complete` once for every
+/// distinct request and copying its result to the positions of the others
+fn complete_deduplicated(
+    requests: &[ChatCompletionRequest],
+    mut complete: impl FnMut(
+        &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError>,
+) -> Vec<Result<ChatCompletionResponse, OpenAIError>> {
+    let mut first_of: HashMap<u64, usize> = HashMap::new();
+    let mut results: Vec<Result<ChatCompletionResponse, OpenAIError>> =
+        Vec::with_capacity(requests.len());
+    for (index, request) in requests.iter().enumerate() {
+        let hash = request.content_hash();
+        let result = match first_of.get(&hash) {
+            Some(&first) => match &results[first] {
+                Ok(response) => Ok(response.clone()),
+                Err(e) => Err(OpenAIError::BatchDuplicate {
+                    index: first,
+                    message: e.to_string(),
+                }),
+            },
+            None => {
+                first_of.insert(hash, index);
+                complete(request)
+            }
+        };
+        results.push(result);
+    }
+    results
+}
+
+/// Store the latency of a completed request
+fn record_latency(
+    latency: &Mutex<Option<LatencyReport>>,
+    total: Duration,
+    time_to_first_token: Option<Duration>,
+) {
+    let mut latency = latency.lock().unwrap_or_else(|e| e.into_inner());
+    *latency = Some(LatencyReport {
+        total,
+        time_to_first_token,
+    });
+}
+
+/// Read the status and the body of a response, failing with
+/// [`OpenAIError::ResponseTooLarge`] past `max_bytes` bytes if limited
+fn read_response(
+    response: reqwest::blocking::Response,
+    max_bytes: Option<usize>,
+) -> Result<(StatusCode, String), OpenAIError> {
+    let status = response.status();
+    let Some(max_bytes) = max_bytes else {
+        return Ok((status, response.text()?));
+    };
+    let mut body = Vec::new();
+    // One byte more than allowed tells a body at the limit from a longer one
+    let limit = u64::try_from(max_bytes)
+        .unwrap_or(u64::MAX)
+        .saturating_add(1);
+    response.take(limit).read_to_end(&mut body)?;
+    if body.len() > max_bytes {
+        return Err(OpenAIError::ResponseTooLarge(max_bytes));
+    }
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Send a request on a worker thread, failing with [`OpenAIError::Timeout`] if
+/// it is not answered within `timeout`. The worker owns the built request,
+/// including its `Authorization` header, until the request finishes. It is
+/// bounded by an HTTP timeout of [`WATCHDOG_GRACE`] past the watchdog one, so
+/// an abandoned request is not left running forever.
+fn execute_with_watchdog(
+    client: &reqwest::blocking::Client,
+    mut request: reqwest::blocking::Request,
+    timeout: Duration,
+    max_bytes: Option<usize>,
+) -> Result<(StatusCode, String), OpenAIError> {
+    *request.timeout_mut() = Some(timeout + WATCHDOG_GRACE);
+    let client = client.clone();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = client
+            .execute(request)
+            .map_err(OpenAIError::from)
+            .and_then(|response| read_response(response, max_bytes));
+        // The watchdog may have given up already, then the result is dropped
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(OpenAIError::Timeout(timeout)),
+    }
+}
+
+/// What a stream produced once it ended or was stopped
+struct StreamedReply {
+    /// The streamed message
+    entry: ChatEntry,
+    /// The usage, sent in the last chunk
+    usage: Option<CompletionUsage>,
+    /// Why the first choice finished, if the stream got that far
+    finish_reason: Option<FinishReason>,
+}
+
+/// Send a streaming request, passing on its events and returning the streamed
+/// message with its usage and finish reason. The stream and error bodies are
+/// read up to `max_bytes`. Progress counts the tokens of the first choice.
+fn send_stream(
+    client: &reqwest::blocking::Client,
+    request: reqwest::blocking::Request,
+    max_bytes: Option<usize>,
+    latency: &Mutex<Option<LatencyReport>>,
+    on_event: &mut dyn FnMut(StreamEvent),
+    on_progress: &mut dyn FnMut(usize),
+    stop_at: &mut dyn FnMut(&str) -> Option<usize>,
+) -> Result<StreamedReply, OpenAIError> {
+    let start = Instant::now();
+    let mut first_token = None;
+    let response = client.execute(request)?;
+    let status = response.status();
+    if !status.is_success() {
+        let (_, body) = read_response(response, max_bytes)?;
+        return Err(OpenAIError::api(status.as_u16(), &body));
+    }
+
+    let mut usage = None;
+    let mut accumulator = StreamAccumulator::new();
+    let mut received = 0;
+    let mut cut = None;
+    read_chunks_limited(BufReader::new(response), max_bytes, |chunk| {
+        for event in accumulator.events(&chunk) {
+            if matches!(event, StreamEvent::Token { index: 0, .. }) {
+                first_token.get_or_insert_with(|| start.elapsed());
+                received += 1;
+            }
+            on_event(event);
+        }
+        on_progress(received);
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        cut = stop_at(accumulator.content());
+        match cut {
+            Some(_) => ControlFlow::Break(()),
+            None => ControlFlow::Continue(()),
+        }
+    })?;
+    for event in accumulator.finish() {
+        on_event(event);
+    }
+    record_latency(latency, start.elapsed(), first_token);
+    let finish_reason = accumulator.finish_reason().cloned();
+    let mut entry = accumulator.into_entry();
+    if let Some(cut) = cut {
+        entry.content.truncate(cut);
+    }
+    Ok(StreamedReply {
+        entry,
+        usage,
+        finish_reason,
+    })
+}
+
+/// Record the usage of a completion in the usage log, if there is one
+fn log_usage(
+    usage_log: Option<&UsageLog>,
+    model: &str,
+    usage: Option<&CompletionUsage>,
+    latency: Duration,
+    finish_reason: Option<FinishReason>,
+) {
+    if let (Some(log), Some(usage)) = (usage_log, usage) {
+        log.record_completion(model, usage, latency, finish_reason);
+    }
+}
+
+/// The usage a finished stream must have sent in its last chunk
+fn stream_usage(
+    usage: Option<CompletionUsage>,
+) -> Result<CompletionUsage, OpenAIError> {
+    usage.ok_or_else(|| {
+        OpenAIError::Stream("the stream ended without usage".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ToolError;
+    use crate::test_support::*;
+
+    /// Test that borrowed requests serialize like owned ones
+    #[test]
+    fn test_borrowed_request() {
+        let log = ChatLog::from(vec![ChatEntry::new(ChatRole::User, "Hello")]);
+        let owned = ChatCompletionRequest::new(Model::Gpt4o, log.clone());
+        let borrowed = BorrowedRequest {
+            model: &Model::Gpt4o,
+            messages: log.entries(),
+            temperature: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&borrowed).unwrap(),
+            serde_json::to_value(&owned).unwrap()
+        );
+    }
+
+    /// Test that the user agent defaults to the crate version
+    #[test]
+    fn test_user_agent() {
+        assert_eq!(
+            DEFAULT_USER_AGENT,
+            format!("hch-cgpt/{}", env!("CARGO_PKG_VERSION"))
+        );
+        let builder = OpenAI::builder("key".to_string());
+        assert_eq!(builder.user_agent, DEFAULT_USER_AGENT);
+        let builder = builder.user_agent("my-app/1.0");
+        assert_eq!(builder.user_agent, "my-app/1.0");
+        assert!(builder.build().is_ok());
+    }
+
+    /// Test creating a client from a configuration
+    #[test]
+    fn test_from_config() {
+        let config = ClientConfig {
+            model: Some(Model::Gpt4o),
+            base_url: Some("http://localhost:8080/v1/".to_string()),
+            organization: Some("org-123".to_string()),
+            project: Some("proj_abc".to_string()),
+            timeout: Some(30),
+            retries: Some(2),
+            temperature: Some(0.2),
+        };
+        let openai = OpenAI::from_config(config, "key".to_string()).unwrap();
+        assert_eq!(openai.model(), Model::Gpt4o);
+        assert_eq!(openai.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(openai.max_retries, 2);
+
+        let request = openai.post("chat/completions").build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8080/v1/chat/completions"
+        );
+        assert_eq!(request.headers()["OpenAI-Organization"], "org-123");
+        assert_eq!(request.headers()["OpenAI-Project"], "proj_abc");
+        let plain = OpenAI::new("key".to_string());
+        let request = plain.post("chat/completions").build().unwrap();
+        assert!(request.headers().get("OpenAI-Organization").is_none());
+        assert!(request.headers().get("OpenAI-Project").is_none());
+
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new());
+        assert_eq!(openai.prepare(&request).temperature(), Some(0.2));
+        let request = request.with_temperature(1.0);
+        assert_eq!(openai.prepare(&request).temperature(), Some(1.0));
+    }
+
+    /// Test that the transform rewrites outgoing messages by role only
+    #[test]
+    fn test_transform() {
+        let openai =
+            OpenAI::new("key".to_string()).with_transform(|role, content| match role {
+                ChatRole::User => format!("<input>{}</input>", content),
+                ChatRole::Assistant => content.to_uppercase(),
+                _ => content.to_string(),
+            });
+        let log: ChatLog = [(ChatRole::System, "Translate."), (ChatRole::User, "Hola")]
+            .into_iter()
+            .collect();
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log);
+
+        let prepared = openai.prepare(&request);
+        let contents: Vec<_> = prepared
+            .messages()
+            .entries()
+            .iter()
+            .map(|entry| entry.content.as_str())
+            .collect();
+        assert_eq!(contents, ["Translate.", "<input>Hola</input>"]);
+        assert_eq!(request.messages().entries()[1].content, "Hola");
+
+        let request = request.with_prefill("Hello");
+        let prepared = openai.prepare(&request);
+        let last = prepared.messages().entries().last().unwrap();
+        assert_eq!(last.content, "Hello");
+        assert_eq!(prepared.prefill(), Some("Hello"));
+    }
+
+    /// Test that the latency of the last request is exposed
+    #[test]
+    fn test_last_latency() {
+        let openai = OpenAI::new("key".to_string());
+        assert_eq!(openai.last_latency(), None);
+
+        let total = Duration::from_millis(120);
+        let first = Some(Duration::from_millis(30));
+        record_latency(&openai.last_latency, total, first);
+        assert_eq!(
+            openai.last_latency(),
+            Some(LatencyReport {
+                total,
+                time_to_first_token: first
+            })
+        );
+    }
+
+    /// Test that the watchdog gives up on a request that is never answered
+    #[test]
+    fn test_watchdog_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let client = reqwest::blocking::Client::new();
+        let request = client.get(url).build().unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let result = execute_with_watchdog(&client, request, timeout, None);
+        assert!(matches!(result, Err(OpenAIError::Timeout(t)) if t == timeout));
+        assert!(!result.unwrap_err().is_retryable());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Test the truncation policies that need no further request
+    #[test]
+    fn test_on_truncation() {
+        let response = |finish_reason: &str| {
+            let body = completion_body("gpt-4o", "Once upon", finish_reason);
+            ChatCompletionResponse::parse(&body).unwrap()
+        };
+        let request = || -> ChatCompletionRequest { unreachable!() };
+
+        let openai = OpenAI::new("key".to_string());
+        let partial = openai.handle_truncation(response("length"), request);
+        assert_eq!(partial.unwrap().reply().unwrap(), "Once upon");
+
+        let openai = OpenAI::builder("key".to_string())
+            .on_truncation(OnTruncation::Error)
+            .build()
+            .unwrap();
+        let truncated = openai.handle_truncation(response("length"), request);
+        assert!(
+            matches!(truncated, Err(OpenAIError::Truncated(p)) if p == "Once upon")
+        );
+        assert!(openai.handle_truncation(response("stop"), request).is_ok());
+    }
+
+    /// Test continuing truncated replies with prefills until they finish, and
+    /// giving up once the continuations run out
+    #[test]
+    fn test_on_truncation_continue() {
+        let client = |url| {
+            OpenAI::builder("key".to_string())
+                .base_url(url)
+                .on_truncation(OnTruncation::Continue)
+                .build()
+                .unwrap()
+        };
+        let json = "application/json";
+        let (url, requests) = serve_sequence(vec![
+            (
+                "200 OK",
+                json,
+                completion_body_with_usage("gpt-4o", "Once upon", "length", 10, 2),
+            ),
+            (
+                "200 OK",
+                json,
+                completion_body_with_usage("gpt-4o", " a time", "length", 12, 3),
+            ),
+            (
+                "200 OK",
+                json,
+                completion_body_with_usage("gpt-4o", ", the end.", "stop", 15, 4),
+            ),
+        ]);
+        let log: ChatLog = [(ChatRole::User, "Tell a story")].into_iter().collect();
+
+        let response = client(url).complete_chat(log.clone()).unwrap();
+        assert_eq!(response.reply().unwrap(), "Once upon a time, the end.");
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 37);
+        assert_eq!(usage.completion_tokens, 9);
+        assert_eq!(usage.total_tokens, 46);
+        let prefills: Vec<_> = requests
+            .iter()
+            .take(3)
+            .map(|request| {
+                let messages = &request.json()["messages"];
+                let last = &messages[messages.as_array().unwrap().len() - 1];
+                (last["role"].clone(), last["content"].clone())
+            })
+            .collect();
+        assert_eq!(
+            prefills,
+            [
+                ("user".into(), "Tell a story".into()),
+                ("assistant".into(), "Once upon".into()),
+                ("assistant".into(), "Once upon a time".into()),
+            ]
+        );
+
+        // Every call adds one digit, and the first call is not a continuation
+        let truncated = (0..=MAX_CONTINUATIONS)
+            .map(|digit| {
+                let body = completion_body("gpt-4o", &digit.to_string(), "length");
+                ("200 OK", json, body)
+            })
+            .collect();
+        let (url, requests) = serve_sequence(truncated);
+        let result = client(url).complete_chat(log);
+        assert!(
+            matches!(result, Err(OpenAIError::Truncated(content)) if content == "01234")
+        );
+        assert_eq!(requests.iter().count(), 5);
+    }
+
+    /// Test that identical requests of a batch are completed once
+    #[test]
+    fn test_complete_deduplicated() {
+        let request = |content: &str| {
+            let log = ChatLog::from(vec![ChatEntry::new(ChatRole::User, content)]);
+            ChatCompletionRequest::new(Model::Gpt4o, log)
+        };
+        let requests = [request("Hi"), request("Bye"), request("Hi")];
+
+        let mut calls = 0;
+        let results = complete_deduplicated(&requests, |request| {
+            calls += 1;
+            let content = &request.messages().entries()[0].content;
+            ChatCompletionResponse::parse(&completion_body("gpt-4o", content, "stop"))
+        });
+        assert_eq!(calls, 2);
+        let replies: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().unwrap().reply().unwrap())
+            .collect();
+        assert_eq!(replies, ["Hi", "Bye", "Hi"]);
+
+        let results = complete_deduplicated(&requests, |_| Err(OpenAIError::NoChoices));
+        assert!(matches!(results[0], Err(OpenAIError::NoChoices)));
+        assert!(matches!(
+            results[2],
+            Err(OpenAIError::BatchDuplicate { index: 0, .. })
+        ));
+    }
+
+    /// A stream saying "Hello" in two pieces, then its usage
+    fn hello_stream() -> String {
+        let chunk = |delta: &str, finish_reason: &str| {
+            format!(
+                "data: {{\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\
+                 \"model\":\"gpt-4o\",\"choices\":[{{\"index\":0,\"delta\":{},\
+                 \"finish_reason\":{}}}],\"usage\":null}}\n\n",
+                delta, finish_reason
+            )
+        };
+        [
+            chunk(r#"{"content":"Hel"}"#, "null"),
+            chunk(r#"{"content":"lo"}"#, "null"),
+            chunk("{}", r#""stop""#),
+            "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\
+             \"model\":\"gpt-4o\",\"choices\":[],\"usage\":{\"prompt_tokens\":5,\
+             \"completion_tokens\":2,\"total_tokens\":7}}\n\ndata: [DONE]\n\n"
+                .to_string(),
+        ]
+        .concat()
+    }
+
+    /// Test counting prompt tokens with a call limited to zero tokens
+    #[test]
+    fn test_count_prompt_tokens() {
+        let body = completion_body_with_usage("gpt-4o", "", "length", 12, 0);
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+
+        for (model, field) in [
+            (Model::Gpt4o, "max_tokens"),
+            (Model::O1, "max_completion_tokens"),
+        ] {
+            let (url, sent) = serve_capture("200 OK", "application/json", body.clone());
+            let request = ChatCompletionRequest::new(model, log.clone());
+            assert!(matches!(
+                test_client(url).count_prompt_tokens(&request),
+                Ok(12)
+            ));
+            assert_eq!(sent.recv().unwrap().json()[field], 0);
+        }
+
+        let body = completion_body("gpt-4o", "", "length");
+        let url = serve("200 OK", "application/json", body);
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log);
+        assert!(matches!(
+            test_client(url).count_prompt_tokens(&request),
+            Err(OpenAIError::NoUsage)
+        ));
+    }
+
+    /// Test that the retries of a request reuse its idempotency key and that
+    /// other requests get their own
+    #[test]
+    fn test_idempotency_key() {
+        let body = completion_body("gpt-4o", "Hi", "stop");
+        let (url, requests) = serve_sequence(vec![
+            (
+                "500 Internal Server Error",
+                "text/plain",
+                "oops".to_string(),
+            ),
+            ("200 OK", "application/json", body.clone()),
+            ("200 OK", "application/json", body),
+        ]);
+        let client = test_client(url).with_retries(1);
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+        client.complete_chat(log.clone()).unwrap();
+        client.complete_chat(log).unwrap();
+
+        let keys: Vec<String> = requests
+            .iter()
+            .take(3)
+            .map(|request| request.header("Idempotency-Key").unwrap().to_string())
+            .collect();
+        assert_eq!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    /// Test that every model of a comparison gets its own result
+    #[test]
+    fn test_compare_models() {
+        let body = completion_body("gpt-4o-mini", "Hi!", "stop");
+        // The server answers once, so the second call fails
+        let client = test_client(serve("200 OK", "application/json", body));
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+
+        let results = client.compare_models(&log, &[Model::Gpt4oMini, Model::Gpt4o]);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, Model::Gpt4oMini);
+        assert_eq!(results[0].1.as_ref().unwrap().reply().unwrap(), "Hi!");
+        assert_eq!(results[1].0, Model::Gpt4o);
+        assert!(results[1].1.is_err());
+    }
+
+    /// Test yielding the raw events of a stream, and the error of a failed one
+    #[test]
+    fn test_stream_raw() {
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+
+        let url = serve_stream(hello_stream());
+        let events: Vec<String> = test_client(url)
+            .stream_raw(log.clone())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+        assert!(events.iter().any(|data| data.contains("\"Hel")));
+
+        let url = serve(
+            "500 Internal Server Error",
+            "text/plain",
+            "oops".to_string(),
+        );
+        let events: Vec<_> = test_client(url).stream_raw(log).collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            Err(OpenAIError::Api { status: 500, .. })
+        ));
+    }
+
+    /// Test that bodies over the limit fail and bodies at the limit do not
+    #[test]
+    fn test_max_response_bytes() {
+        let client = |status, body: &str, max_bytes| {
+            let url = serve(status, "application/json", body.to_string());
+            test_client(url).with_max_response_bytes(max_bytes)
+        };
+        let models = r#"{"data": []}"#;
+        let rejected = r#"{"error": {"message": "Incorrect API key provided"}}"#;
+        assert!(client("200 OK", models, models.len())
+            .validate_key()
+            .is_ok());
+        assert!(matches!(
+            client("401 Unauthorized", rejected, 16).validate_key(),
+            Err(OpenAIError::ResponseTooLarge(16))
+        ));
+
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+        assert!(matches!(
+            client("200 OK", models, 4).complete_chat(log.clone()),
+            Err(OpenAIError::ResponseTooLarge(4))
+        ));
+        // A body at the limit is read, and here fails to decode as a completion
+        assert!(matches!(
+            client("200 OK", models, models.len()).complete_chat(log.clone()),
+            Err(OpenAIError::Decode { .. })
+        ));
+
+        let stream = |max_bytes| {
+            let url = serve_stream(hello_stream());
+            test_client(url).with_max_response_bytes(max_bytes)
+        };
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log.clone());
+        assert!(stream(hello_stream().len())
+            .stream_request(&request, |_| {})
+            .is_ok());
+        assert!(matches!(
+            stream(16).stream_request(&request, |_| {}),
+            Err(OpenAIError::ResponseTooLarge(16))
+        ));
+        let events: Vec<_> = stream(16).stream_raw(log).collect();
+        assert!(matches!(
+            events.last(),
+            Some(Err(OpenAIError::ResponseTooLarge(16)))
+        ));
+    }
+
+    /// Test that the tokens of legacy functions count against the rate limit
+    #[test]
+    fn test_throttle_functions() {
+        let openai = OpenAI::new("key".to_string())
+            .with_rate_limit(100, 50)
+            .fail_when_rate_limited(true);
+        let messages = [ChatEntry::new(ChatRole::User, "Hi")];
+        let function = Tool::function(
+            "lookup",
+            "Look up a word. ".repeat(20),
+            serde_json::json!({"type": "object", "properties": {}}),
+        )
+        .function;
+        let functions = [function];
+        let model = Model::Gpt4o;
+
+        openai.throttle(&messages, &[], &[], &model).unwrap();
+        openai.throttle(&messages, &[], &[], &model).unwrap();
+        assert!(matches!(
+            openai.throttle(&messages, &[], &functions, &model),
+            Err(OpenAIError::RateLimited { .. })
+        ));
+    }
+
+    /// Test that completions and streams are recorded in the usage log
+    #[test]
+    fn test_with_usage_log() {
+        let body = completion_body_with_usage("gpt-4o-mini", "Hi!", "length", 5, 2);
+        let usage_log = Arc::new(UsageLog::new());
+        let client = |url| test_client(url).with_usage_log(usage_log.clone());
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+
+        let url = serve("200 OK", "application/json", body.clone());
+        client(url).complete_chat(log.clone()).unwrap();
+        let url = serve_stream(hello_stream());
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log.clone());
+        client(url).stream_request(&request, |_| {}).unwrap();
+        let url = serve_stream(hello_stream());
+        let (_, handle) = client(url).stream_chat_channel(log);
+        handle.join().unwrap().unwrap();
+        // Token counting probes are not completions
+        let url = serve("200 OK", "application/json", body);
+        client(url).count_prompt_tokens(&request).unwrap();
+
+        let rows = usage_log.rows();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].model, "gpt-4o-mini");
+        assert_eq!(rows[0].finish_reason, Some(FinishReason::Length));
+        assert_eq!(rows[0].prompt_tokens, 5);
+        assert_eq!(rows[1].model, "gpt-4o");
+        assert_eq!(rows[1].finish_reason, Some(FinishReason::Stop));
+        assert_eq!(rows[2].finish_reason, Some(FinishReason::Stop));
+        assert!(rows.iter().all(|row| row.cost_usd.is_some()));
+    }
+
+    /// Test that requests with invalid tools fail before being sent
+    #[test]
+    fn test_invalid_tool() {
+        // Nothing listens there, so sending would fail with another error
+        let client = test_client("http://127.0.0.1:9".to_string());
+        let log: ChatLog = [(ChatRole::User, "Hello")].into_iter().collect();
+        let parameters = serde_json::json!({"properties": {}});
+        let tool = Tool::function("weather", "Get the weather", parameters);
+        let request =
+            ChatCompletionRequest::new(Model::Gpt4o, log).with_tools(vec![tool]);
+
+        assert!(matches!(
+            client.complete_request(&request),
+            Err(OpenAIError::InvalidTool(ToolError::MissingType { tool })) if tool == "weather"
+        ));
+        assert!(matches!(
+            client.stream_request(&request, |_| {}),
+            Err(OpenAIError::InvalidTool(_))
+        ));
+    }
+
+    /// Test that rejected keys are told apart from other failures
+    #[test]
+    fn test_validate_key() {
+        let client = |status, body: &str| {
+            test_client(serve(status, "application/json", body.to_string()))
+        };
+        let rejected = r#"{"error": {"message": "Incorrect API key provided"}}"#;
+        assert!(client("200 OK", r#"{"data": []}"#).validate_key().is_ok());
+        assert!(matches!(
+            client("401 Unauthorized", rejected).validate_key(),
+            Err(OpenAIError::InvalidKey(message)) if message == "Incorrect API key provided"
+        ));
+        assert!(matches!(
+            client("500 Internal Server Error", "oops").validate_key(),
+            Err(OpenAIError::Api { status: 500, .. })
+        ));
+    }
+
+    /// Test that stream progress reports the running count of tokens
+    #[test]
+    fn test_stream_progress() {
+        let url = serve_stream(hello_stream());
+        let client = reqwest::blocking::Client::new();
+        let request = client.get(url).build().unwrap();
+        let latency = Mutex::new(None);
+        let mut events = Vec::new();
+        let mut progress = Vec::new();
+        let reply = send_stream(
+            &client,
+            request,
+            None,
+            &latency,
+            &mut |event| events.push(event),
+            &mut |count| progress.push(count),
+            &mut |_| None,
+        )
+        .unwrap();
+        assert_eq!(progress, vec![1, 2, 2, 2]);
+        assert_eq!(reply.entry.content, "Hello");
+        assert_eq!(reply.usage.unwrap().total_tokens, 7);
+        assert_eq!(reply.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(events.len(), 3);
+    }
+
+    /// Test stopping a stream once its content matches a regex
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_stream_request_until() {
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new());
+        let stop = Regex::new("l+").unwrap();
+        for (include_match, content) in [(false, "He"), (true, "Hel")] {
+            let openai = test_client(serve_stream(hello_stream()));
+            let mut tokens = Vec::new();
+            let (entry, usage) = openai
+                .stream_request_until(&request, &stop, include_match, |token| {
+                    tokens.push(token.to_string())
+                })
+                .unwrap();
+            assert_eq!(entry.content, content);
+            assert_eq!(tokens, ["Hel"]);
+            assert_eq!(usage, None);
+        }
+
+        let openai = test_client(serve_stream(hello_stream()));
+        let never = Regex::new("bye").unwrap();
+        let (entry, usage) = openai
+            .stream_request_until(&request, &never, false, |_| {})
+            .unwrap();
+        assert_eq!(entry.content, "Hello");
+        assert_eq!(usage.unwrap().total_tokens, 7);
+    }
+
+    /// Test that responses from another snapshot are rejected
+    #[test]
+    fn test_require_model() {
+        let response = |model: &str| {
+            ChatCompletionResponse::parse(&completion_body(model, "Hi", "stop"))
+                .unwrap()
+        };
+        let snapshot = response("gpt-4o-2024-08-06");
+        let other = response("gpt-4o-2024-11-20");
+
+        let openai = OpenAI::new("key".to_string());
+        assert!(openai.check_model(&other).is_ok());
+        let openai = openai.require_model("gpt-4o-2024-08-06");
+        assert!(openai.check_model(&snapshot).is_ok());
+        assert!(matches!(
+            openai.check_model(&other),
+            Err(OpenAIError::ModelMismatch { got, .. }) if got == "gpt-4o-2024-11-20"
+        ));
+    }
+
+    /// Test that the fingerprint hook fires only when a fingerprint changes
+    #[test]
+    fn test_fingerprint_change() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+        let openai =
+            OpenAI::new("key".to_string()).on_fingerprint_change(move |old, new| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((old.to_string(), new.to_string()));
+            });
+
+        let response = |model: &str, fingerprint: &str| {
+            let mut response =
+                ChatCompletionResponse::parse(&completion_body(model, "Hi", "stop"))
+                    .unwrap();
+            response.system_fingerprint = Some(fingerprint.to_string());
+            response
+        };
+
+        openai.record_fingerprint(&response("gpt-4o", "fp_a"));
+        openai.record_fingerprint(&response("gpt-4o", "fp_a"));
+        openai.record_fingerprint(&response("gpt-4o-mini", "fp_x"));
+        openai.record_fingerprint(&response("gpt-4o", "fp_b"));
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(*changes, vec![("fp_a".to_string(), "fp_b".to_string())]);
+    }
+
+    use std::env;
+
+    /// Test the chat completion request
+    #[test]
+    fn test_chat_completion_request() {
+        // Read the key from environment variable
+        let key = env::var("OPENAI_KEY").expect("OPENAI_KEY must be set");
+
+        // Create a new OpenAI client
+        let openai = OpenAI::new(key);
+
+        // Create a new chat log
+        let log = ChatLog::from(vec![
+            ChatEntry::new(
+                ChatRole::System,
+                "You are an assistant that always says \"A\"",
+            ),
+            ChatEntry::new(
+                ChatRole::User,
+                "Please say \"A\". Do not say anything else, only \"A\".",
+            ),
+        ]);
+
+        // Complete the chat
+        let response = openai.complete_chat(log).expect("Failed to complete chat");
+
+        // Get the first choice
+        let choice = response.choices.first().expect("No choices");
+
+        // Get the message
+        let message = &choice.message;
+
+        // Check that the message is correct
+        assert_eq!(message.role, ChatRole::Assistant);
+
+        println!("Assistant: {}", message.content);
+    }
+}
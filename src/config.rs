@@ -0,0 +1,76 @@
+#[cfg(feature = "toml")]
+use crate::error::ConfigError;
+use crate::model::Model;
+use serde::Deserialize;
+#[cfg(feature = "toml")]
+use std::path::Path;
+
+/// The settings of a client, as read from a configuration file. The api key is
+/// deliberately not one of them: it should come from the environment, and a
+/// file that sets one is rejected like any other unknown setting.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The model of the chats completed without one
+    pub model: Option<Model>,
+    /// The base url of the api, such as the one of a proxy or a local server
+    pub base_url: Option<String>,
+    /// The organization the requests are billed to
+    pub organization: Option<String>,
+    /// The project of the organization the requests are billed to
+    pub project: Option<String>,
+    /// How long a completion may take, in seconds
+    pub timeout: Option<u64>,
+    /// How many times a failed request is retried
+    pub retries: Option<usize>,
+    /// The temperature of the requests that set none
+    pub temperature: Option<f64>,
+}
+
+#[cfg(feature = "toml")]
+impl ClientConfig {
+    /// Parse a configuration from TOML, such as
+    ///
+    /// ```toml
+    /// model = "gpt-4o"
+    /// timeout = 30
+    /// retries = 2
+    /// ```
+    pub fn from_toml(toml: &str) -> Result<ClientConfig, ConfigError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Read a configuration from a TOML file, see [`ClientConfig::from_toml`]
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<ClientConfig, ConfigError> {
+        ClientConfig::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(all(test, feature = "toml"))]
+mod tests {
+    use super::*;
+
+    /// Test reading a configuration file and rejecting api keys in it
+    #[test]
+    fn test_from_toml_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "model = \"gpt-4o\"\nbase_url = \"http://localhost:8080/v1\"\ntimeout = 30\nretries = 2\n",
+        )
+        .unwrap();
+
+        let config = ClientConfig::from_toml_file(&path).unwrap();
+        assert_eq!(config.model, Some(Model::Gpt4o));
+        assert_eq!(config.base_url.as_deref(), Some("http://localhost:8080/v1"));
+        assert_eq!(config.timeout, Some(30));
+        assert_eq!(config.retries, Some(2));
+        assert_eq!(config.temperature, None);
+
+        let result = ClientConfig::from_toml("api_key = \"sk-secret\"");
+        assert!(matches!(result, Err(ConfigError::Parse(_))));
+        let result = ClientConfig::from_toml_file(dir.path().join("missing.toml"));
+        assert!(matches!(result, Err(ConfigError::Io(_))));
+    }
+}
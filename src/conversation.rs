@@ -0,0 +1,865 @@
+use crate::backend::ChatBackend;
+use crate::client::OpenAI;
+use crate::error::OpenAIError;
+use crate::model::Model;
+use crate::openai::{
+    default_model, ChatCompletionRequest, ChatCompletionResponse, ChatEntry, ChatLog,
+    ChatRole, FinishReason,
+};
+use crate::tokens::SessionCost;
+use crate::tools::{Tool, ToolCall};
+
+/// The instruction used to summarize old turns of a conversation
+const SUMMARIZE_PROMPT: &str = "Summarize this conversation. Keep every fact, \
+    decision and open question that later turns may rely on. Reply only with the \
+    summary.";
+
+/// What [`Conversation::ask`] does when a new message would exceed the token
+/// budget of the conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextPolicy {
+    /// Fail with [`OpenAIError::ContextTooLong`] without changing the log
+    #[default]
+    Error,
+    /// Drop the oldest turns after the system prompt until the log fits
+    TrimOldest,
+    /// Replace all the turns so far with a summary, see
+    /// [`Conversation::summarize_old`]
+    Summarize,
+}
+
+/// An ongoing conversation with a model, keeping the log between turns. It talks
+/// to the model through the OpenAI client unless another backend is given.
+pub struct Conversation<'a, B: ChatBackend = OpenAI> {
+    /// The backend used to talk to the model
+    backend: &'a B,
+    /// The conversation so far
+    log: ChatLog,
+    /// The model used by this conversation, the default model if `None`
+    model: Option<Model>,
+    /// The maximum estimated tokens of the log, unlimited if `None`
+    max_context_tokens: Option<usize>,
+    /// What to do when a new message would exceed `max_context_tokens`
+    context_policy: ContextPolicy,
+    /// The usage and cost of the completions so far
+    cost: SessionCost,
+}
+
+impl<'a, B: ChatBackend> Conversation<'a, B> {
+    /// Start a new conversation with the given system prompt
+    pub fn new(
+        backend: &'a B,
+        system_prompt: impl Into<String>,
+    ) -> Conversation<'a, B> {
+        let mut log = ChatLog::new();
+        log.push_system(system_prompt);
+        Conversation {
+            backend,
+            log,
+            model: None,
+            max_context_tokens: None,
+            context_policy: ContextPolicy::Error,
+            cost: SessionCost::new(),
+        }
+    }
+
+    /// Start a new conversation pinned to a model, independently of the default
+    /// model, so several conversations can use different models with one client
+    pub fn with_model(
+        backend: &'a B,
+        system_prompt: impl Into<String>,
+        model: impl Into<Model>,
+    ) -> Conversation<'a, B> {
+        let mut conversation = Conversation::new(backend, system_prompt);
+        conversation.model = Some(model.into());
+        conversation
+    }
+
+    /// Keep the log within `max_tokens` estimated tokens, counted with the token
+    /// counter of the backend. The policy decides what [`Conversation::ask`] does
+    /// when a new message would exceed it. Replies are not counted in advance,
+    /// so leave room for them in the budget.
+    pub fn with_context_budget(
+        mut self,
+        max_tokens: usize,
+        policy: ContextPolicy,
+    ) -> Conversation<'a, B> {
+        self.max_context_tokens = Some(max_tokens);
+        self.context_policy = policy;
+        self
+    }
+
+    /// The model used by this conversation
+    pub fn model(&self) -> Model {
+        self.model.clone().unwrap_or_else(default_model)
+    }
+
+    /// The conversation so far
+    pub fn log(&self) -> &ChatLog {
+        &self.log
+    }
+
+    /// The usage and cost of every completion of this conversation so far,
+    /// summaries included. Completions whose backend reports no usage are
+    /// missing.
+    pub fn cost(&self) -> &SessionCost {
+        &self.cost
+    }
+
+    /// Replace the conversation so far, for example with a log saved earlier
+    pub fn set_log(&mut self, log: ChatLog) {
+        self.log = log;
+    }
+
+    /// Send a user message and return the reply, which is also added to the log.
+    /// The user message is kept in the log even if the call fails. If the message
+    /// would exceed the token budget the context policy is applied first.
+    pub fn ask(&mut self, input: &str) -> Result<String, OpenAIError> {
+        self.fit_budget(input)?;
+        self.log.push_user(input);
+        self.reply()
+    }
+
+    /// Send a user message and stream the reply into a new assistant entry at
+    /// the end of the log, calling `on_token` with every piece after it is
+    /// added, so the log is always the latest state of the reply. If the stream
+    /// fails the partial reply is kept, and the entry is only removed if nothing
+    /// arrived. The budget is applied like in [`Conversation::ask`].
+    pub fn ask_streaming(
+        &mut self,
+        input: &str,
+        on_token: impl FnMut(&str),
+    ) -> Result<(), OpenAIError> {
+        self.fit_budget(input)?;
+        self.log.push_user(input);
+        let request = ChatCompletionRequest::new(self.model(), self.log.clone());
+        self.stream_reply(&request, on_token)
+    }
+
+    /// Finish a reply whose stream was interrupted, such as one saved before a
+    /// crash, by sending `partial` as a prefill and streaming only the rest of
+    /// it into a new assistant entry that starts with `partial`. A trailing
+    /// assistant entry that `partial` starts with is taken to be the
+    /// interrupted reply and replaced, any other one is kept and the reply
+    /// added after it. The budget is applied like in [`Conversation::ask`],
+    /// counting `partial` as the input. Like in [`Conversation::ask_streaming`]
+    /// a failed stream keeps what arrived, so the partial reply is never lost.
+    ///
+    /// This is an extra request sending the whole log again plus the partial
+    /// reply as prompt tokens, and only its usage is recorded in the
+    /// [`Conversation::cost`]; the tokens of the interrupted stream were billed
+    /// but are missing. Providers that restart the reply instead of continuing
+    /// it repeat the partial reply after it.
+    pub fn resume_partial(
+        &mut self,
+        partial: String,
+        on_token: impl FnMut(&str),
+    ) -> Result<(), OpenAIError> {
+        let interrupted = self.log.entries().last().is_some_and(|entry| {
+            entry.role == ChatRole::Assistant && partial.starts_with(&entry.content)
+        });
+        let replaced = if interrupted {
+            self.log.entries_mut().pop()
+        } else {
+            None
+        };
+        if let Err(error) = self.fit_budget(&partial) {
+            self.log.entries_mut().extend(replaced);
+            return Err(error);
+        }
+        let request = ChatCompletionRequest::new(self.model(), self.log.clone())
+            .with_prefill(partial);
+        self.stream_reply(&request, on_token)
+    }
+
+    /// Stream the reply to a request into a new assistant entry starting with
+    /// the prefill of the request, see [`Conversation::ask_streaming`]
+    fn stream_reply(
+        &mut self,
+        request: &ChatCompletionRequest,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<(), OpenAIError> {
+        let prefill = request.prefill().unwrap_or_default().to_string();
+        self.log.push_reply(prefill, request.model().name());
+        let entries = self.log.entries_mut();
+        let result = self.backend.stream_request(request, &mut |token| {
+            let reply = entries.last_mut().expect("the reply entry was just added");
+            reply.content.push_str(token);
+            on_token(token);
+        });
+        if result.is_err()
+            && entries.last().is_some_and(|reply| reply.content.is_empty())
+        {
+            entries.pop();
+        }
+        if let Some(usage) = result? {
+            self.cost.record(request.model().clone(), usage);
+        }
+        Ok(())
+    }
+
+    /// The estimated tokens of the log once the input is added
+    fn tokens_with(&self, input: &str) -> usize {
+        let counter = self.backend.token_counter();
+        let model = self.model();
+        self.log.count_tokens(counter, &model) + counter.count(input, &model)
+    }
+
+    /// Apply the context policy until the input fits in the token budget
+    fn fit_budget(&mut self, input: &str) -> Result<(), OpenAIError> {
+        let Some(max) = self.max_context_tokens else {
+            return Ok(());
+        };
+        if self.tokens_with(input) <= max {
+            return Ok(());
+        }
+        match self.context_policy {
+            ContextPolicy::Error => {}
+            ContextPolicy::TrimOldest => {
+                let start = self.first_turn();
+                while self.log.len() > start && self.tokens_with(input) > max {
+                    self.log.entries_mut().remove(start);
+                }
+                // Keep the log starting with a user message
+                let starts_with_reply = self
+                    .log
+                    .entries()
+                    .get(start)
+                    .is_some_and(|entry| entry.role == ChatRole::Assistant);
+                if starts_with_reply {
+                    self.log.entries_mut().remove(start);
+                }
+            }
+            ContextPolicy::Summarize => self.summarize_old(0)?,
+        }
+        let tokens = self.tokens_with(input);
+        if tokens > max {
+            return Err(OpenAIError::ContextTooLong { tokens, max });
+        }
+        Ok(())
+    }
+
+    /// Ask every input in order, returning all the replies. Stops at the first
+    /// failure and returns its error, the turns before it stay in the log.
+    pub fn replay(&mut self, inputs: &[&str]) -> Result<Vec<String>, OpenAIError> {
+        inputs.iter().map(|input| self.ask(input)).collect()
+    }
+
+    /// Drop the last reply, if the log ends with one, and ask the model for a new
+    /// one to the same messages
+    pub fn regenerate(&mut self) -> Result<String, OpenAIError> {
+        if let Some(ChatEntry {
+            role: ChatRole::Assistant,
+            ..
+        }) = self.log.entries().last()
+        {
+            self.log.entries_mut().pop();
+        }
+        self.reply()
+    }
+
+    /// Ask the model to reply to the log and add the reply to it. A refusal fails
+    /// with [`OpenAIError::Refusal`] and is not added.
+    fn reply(&mut self) -> Result<String, OpenAIError> {
+        let response = self.complete(self.log.clone())?;
+        let reply = response.reply()?.to_string();
+        self.log.push_reply(reply.clone(), response.model.as_str());
+        Ok(reply)
+    }
+
+    /// Let the model reply to the log with the given tools, running the tool
+    /// calls it asks for through `dispatch` and sending their results back,
+    /// until it answers without calling tools. The calls, their results and
+    /// the final reply are all added to the log, and the reply is returned.
+    ///
+    /// Fails with [`OpenAIError::TooManyToolRounds`] if the model still calls
+    /// tools after `max_iterations` completions; the rounds so far stay in the
+    /// log. A refusal fails like in [`Conversation::ask`].
+    pub fn run_tools(
+        &mut self,
+        tools: &[Tool],
+        mut dispatch: impl FnMut(&ToolCall) -> String,
+        max_iterations: usize,
+    ) -> Result<String, OpenAIError> {
+        for _ in 0..max_iterations {
+            let request = ChatCompletionRequest::new(self.model(), self.log.clone())
+                .with_tools(tools.to_vec());
+            let response = self.complete_with(&request)?;
+            let choice = response.choices.first().ok_or(OpenAIError::NoChoices)?;
+            let calls = match &choice.message.tool_calls {
+                Some(calls) if choice.finish_reason == FinishReason::ToolCalls => {
+                    calls.clone()
+                }
+                _ => {
+                    let reply = response.reply()?.to_string();
+                    self.log.push_reply(reply.clone(), response.model.as_str());
+                    return Ok(reply);
+                }
+            };
+            self.log.push(ChatEntry {
+                model: Some(response.model.clone()),
+                ..choice.message.clone()
+            });
+            for call in &calls {
+                let result = dispatch(call);
+                self.log.push_tool_result(call.id.clone(), result);
+            }
+        }
+        Err(OpenAIError::TooManyToolRounds(max_iterations))
+    }
+
+    /// Complete a log with the model of this conversation, recording its cost
+    fn complete(
+        &mut self,
+        log: ChatLog,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        self.complete_with(&ChatCompletionRequest::new(self.model(), log))
+    }
+
+    /// Complete a request, recording its cost
+    fn complete_with(
+        &mut self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = self.backend.complete_request(request)?;
+        if let Some(usage) = &response.usage {
+            self.cost.record(response.model.as_str(), usage.clone());
+        }
+        Ok(response)
+    }
+
+    /// Replace the oldest turns, all but the most recent `keep_recent` entries, with
+    /// a single system message holding a summary of them written by the model.
+    /// The leading system prompt is always kept. This costs one extra completion
+    /// but keeps the context of long conversations within budget.
+    pub fn summarize_old(&mut self, keep_recent: usize) -> Result<(), OpenAIError> {
+        let old = self.old_entries(keep_recent);
+        if old.is_empty() {
+            return Ok(());
+        }
+
+        let mut request = ChatLog::new();
+        request.push_system(SUMMARIZE_PROMPT);
+        request.push_user(transcript(old));
+        let response = self.complete(request)?;
+        let summary = response.reply()?.to_string();
+
+        self.replace_old(keep_recent, summary);
+        Ok(())
+    }
+
+    /// The index of the first entry after the leading system prompt
+    fn first_turn(&self) -> usize {
+        match self.log.entries().first() {
+            Some(entry) if entry.role.is_steering() => 1,
+            _ => 0,
+        }
+    }
+
+    /// The entries that would be summarized when keeping `keep_recent` entries
+    fn old_entries(&self, keep_recent: usize) -> &[ChatEntry] {
+        let start = self.first_turn();
+        let end = self.log.len().saturating_sub(keep_recent).max(start);
+        &self.log.entries()[start..end]
+    }
+
+    /// Replace the old entries with a system message holding their summary
+    fn replace_old(&mut self, keep_recent: usize, summary: String) {
+        let start = self.first_turn();
+        let end = start + self.old_entries(keep_recent).len();
+        let summary = ChatEntry::new(
+            ChatRole::System,
+            format!("Summary of the earlier conversation: {}", summary),
+        );
+        self.log.entries_mut().splice(start..end, [summary]);
+    }
+}
+
+/// Render entries as a plain `role: content` transcript
+fn transcript(entries: &[ChatEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| format!("{}: {}", entry.role.name(), entry.content))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::{ChatCompletionChoice, CompletionUsage};
+    use crate::tokens::{HeuristicCounter, TokenCounter};
+    use crate::tools::{FunctionCall, ToolType};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// The usage reported by the test backends for every completion
+    fn usage() -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 100,
+            total_tokens: 1100,
+        }
+    }
+
+    /// A backend answering with scripted replies, failing once they run out
+    struct ScriptedBackend {
+        /// The replies left, in order
+        replies: RefCell<VecDeque<&'static str>>,
+        /// The requests received so far, in order
+        requests: RefCell<Vec<ChatCompletionRequest>>,
+    }
+
+    impl ChatBackend for ScriptedBackend {
+        fn token_counter(&self) -> &dyn TokenCounter {
+            &HeuristicCounter
+        }
+
+        fn complete_request(
+            &self,
+            request: &ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, OpenAIError> {
+            self.requests.borrow_mut().push(request.clone());
+            let reply = self.replies.borrow_mut().pop_front();
+            let reply = reply.ok_or(OpenAIError::NoChoices)?;
+            let message = ChatEntry::new(ChatRole::Assistant, reply);
+            Ok(response(message, FinishReason::Stop))
+        }
+    }
+
+    /// A response of `gpt-4o` with a single choice
+    fn response(
+        message: ChatEntry,
+        finish_reason: FinishReason,
+    ) -> ChatCompletionResponse {
+        ChatCompletionResponse {
+            id: "chatcmpl-123".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "gpt-4o".to_string(),
+            system_fingerprint: None,
+            service_tier: None,
+            prompt_filter_results: None,
+            choices: vec![ChatCompletionChoice {
+                index: 0,
+                message,
+                finish_reason,
+                logprobs: None,
+                content_filter_results: None,
+            }],
+            usage: Some(usage()),
+        }
+    }
+
+    /// A backend calling a clock tool until it gets its result, then telling
+    /// the time
+    struct ClockBackend;
+
+    impl ChatBackend for ClockBackend {
+        fn complete_request(
+            &self,
+            request: &ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, OpenAIError> {
+            assert_eq!(request.tools().len(), 1);
+            let last = request.messages().entries().last().unwrap();
+            if last.role == ChatRole::Tool {
+                let message = ChatEntry::new(
+                    ChatRole::Assistant,
+                    format!("It is {}.", last.content),
+                );
+                return Ok(response(message, FinishReason::Stop));
+            }
+            let call = ToolCall {
+                id: format!("call_{}", request.messages().len()),
+                kind: ToolType::Function,
+                function: FunctionCall {
+                    name: "clock".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            };
+            let message = ChatEntry {
+                tool_calls: Some(vec![call]),
+                ..ChatEntry::new(ChatRole::Assistant, "")
+            };
+            Ok(response(message, FinishReason::ToolCalls))
+        }
+    }
+
+    /// Test running tool calls until the model answers
+    #[test]
+    fn test_run_tools() {
+        let tools = [Tool::function(
+            "clock",
+            "Tell the time",
+            serde_json::json!({}),
+        )];
+        let mut conversation = Conversation::new(&ClockBackend, "Be brief.");
+        let mut log = conversation.log().clone();
+        log.push_user("What time is it?");
+        conversation.set_log(log);
+
+        let mut calls = Vec::new();
+        let reply = conversation
+            .run_tools(
+                &tools,
+                |call| {
+                    calls.push(call.id.clone());
+                    "noon".to_string()
+                },
+                3,
+            )
+            .unwrap();
+        assert_eq!(reply, "It is noon.");
+        assert_eq!(calls, ["call_2"]);
+        let roles: Vec<_> = conversation
+            .log()
+            .entries()
+            .iter()
+            .map(|e| e.role.clone())
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                ChatRole::System,
+                ChatRole::User,
+                ChatRole::Assistant,
+                ChatRole::Tool,
+                ChatRole::Assistant
+            ]
+        );
+        let result = &conversation.log().entries()[3];
+        assert_eq!(result.tool_call_id.as_deref(), Some("call_2"));
+        assert!(conversation.log().is_well_formed().is_ok());
+        assert_eq!(conversation.cost().turns().len(), 2);
+
+        let mut conversation = Conversation::new(&ClockBackend, "Be brief.");
+        let result = conversation.run_tools(&tools, |_| "noon".to_string(), 1);
+        assert!(matches!(result, Err(OpenAIError::TooManyToolRounds(1))));
+        assert_eq!(conversation.log().len(), 3);
+    }
+
+    /// A backend streaming two pieces of a reply, then failing if asked to
+    struct StreamingBackend {
+        /// Whether the stream fails after its pieces
+        fail: bool,
+    }
+
+    impl ChatBackend for StreamingBackend {
+        fn complete_request(
+            &self,
+            _request: &ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, OpenAIError> {
+            unreachable!("conversations stream with this backend")
+        }
+
+        fn stream_request(
+            &self,
+            request: &ChatCompletionRequest,
+            on_token: &mut dyn FnMut(&str),
+        ) -> Result<Option<CompletionUsage>, OpenAIError> {
+            let last = request.messages().entries().last().unwrap();
+            let expected = match request.prefill() {
+                Some(_) => ChatRole::Assistant,
+                None => ChatRole::User,
+            };
+            assert_eq!(last.role, expected);
+            on_token("Hel");
+            on_token("lo");
+            if self.fail {
+                return Err(OpenAIError::Stream("connection reset".to_string()));
+            }
+            Ok(Some(usage()))
+        }
+    }
+
+    /// Test streaming replies into the log, keeping partial ones on failure
+    #[test]
+    fn test_ask_streaming() {
+        let backend = StreamingBackend { fail: false };
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        let mut seen = Vec::new();
+        conversation
+            .ask_streaming("Hi", |token| seen.push(token.to_string()))
+            .unwrap();
+        assert_eq!(seen, vec!["Hel", "lo"]);
+        let reply = conversation.log().entries().last().unwrap();
+        assert_eq!(reply.role, ChatRole::Assistant);
+        assert_eq!(reply.content, "Hello");
+
+        let backend = StreamingBackend { fail: true };
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        let result = conversation.ask_streaming("Hi", |_| {});
+        assert!(matches!(result, Err(OpenAIError::Stream(_))));
+        assert_eq!(conversation.log().len(), 3);
+        assert_eq!(conversation.log().entries()[2].content, "Hello");
+
+        let backend = scripted(&[]);
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        assert!(conversation.ask_streaming("Hi", |_| {}).is_err());
+        assert_eq!(conversation.log().len(), 2);
+    }
+
+    /// Test resuming an interrupted reply with the rest of it
+    #[test]
+    fn test_resume_partial() {
+        let backend = StreamingBackend { fail: false };
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        let mut log = conversation.log().clone();
+        log.push_user("Hi");
+        log.push_assistant("Well, h");
+        conversation.set_log(log);
+
+        let mut seen = Vec::new();
+        conversation
+            .resume_partial("Well, hi! ".to_string(), |token| {
+                seen.push(token.to_string())
+            })
+            .unwrap();
+        assert_eq!(seen, vec!["Hel", "lo"]);
+        assert_eq!(conversation.log().len(), 3);
+        let reply = conversation.log().entries().last().unwrap();
+        assert_eq!(reply.role, ChatRole::Assistant);
+        assert_eq!(reply.content, "Well, hi! Hello");
+        assert_eq!(conversation.cost().turns().len(), 1);
+
+        // A finished reply the partial does not continue is kept
+        conversation
+            .resume_partial("Hi! ".to_string(), |_| {})
+            .unwrap();
+        assert_eq!(conversation.log().len(), 4);
+        let entries = conversation.log().entries();
+        assert_eq!(entries[2].content, "Well, hi! Hello");
+        assert_eq!(entries[3].content, "Hi! Hello");
+
+        // The interrupted reply is kept when the budget is exceeded
+        let mut conversation = Conversation::new(&backend, "Be brief.")
+            .with_context_budget(10, ContextPolicy::Error);
+        conversation.log.push_user("Hi");
+        conversation.log.push_assistant("Well, h");
+        let partial = "Well, h".to_string() + &"o".repeat(100);
+        assert!(matches!(
+            conversation.resume_partial(partial, |_| {}),
+            Err(OpenAIError::ContextTooLong { .. })
+        ));
+        assert_eq!(
+            conversation.log().entries().last().unwrap().content,
+            "Well, h"
+        );
+
+        let backend = scripted(&[]);
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        assert!(conversation
+            .resume_partial("Hi".to_string(), |_| {})
+            .is_err());
+        assert_eq!(conversation.log().entries().last().unwrap().content, "Hi");
+    }
+
+    /// Create a conversation with a few turns already in it
+    fn conversation(openai: &OpenAI) -> Conversation<'_> {
+        let mut conversation = Conversation::new(openai, "Be brief.");
+        for i in 0..3 {
+            conversation.log.push_user(format!("question {}", i));
+            conversation.log.push_assistant(format!("answer {}", i));
+        }
+        conversation
+    }
+
+    /// Test that conversations can be pinned to a model
+    #[test]
+    fn test_with_model() {
+        let openai = OpenAI::new("key".to_string());
+        let gpt4 = Conversation::with_model(&openai, "Be brief.", Model::Gpt4);
+        let mini = Conversation::with_model(&openai, "Be brief.", "gpt-4o-mini");
+        assert_eq!(gpt4.model(), Model::Gpt4);
+        assert_eq!(mini.model(), Model::Gpt4oMini);
+    }
+
+    /// Test replaying scripted inputs, stopping at the first failure
+    #[test]
+    fn test_replay() {
+        let backend = scripted(&["one", "two"]);
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        let replies = conversation.replay(&["first", "second"]).unwrap();
+        assert_eq!(replies, vec!["one", "two"]);
+
+        let result = conversation.replay(&["third", "fourth"]);
+        assert!(matches!(result, Err(OpenAIError::NoChoices)));
+        let entries = conversation.log().entries();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[4].content, "two");
+        assert_eq!(entries[5].content, "third");
+        assert_eq!(conversation.log().models_used(), ["gpt-4o"]);
+    }
+
+    /// Test that the cost of every completion is recorded, summaries included
+    #[test]
+    fn test_session_cost() {
+        let backend = scripted(&["one", "two", "summary"]);
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        conversation.replay(&["first", "second"]).unwrap();
+        conversation.summarize_old(0).unwrap();
+
+        let cost = conversation.cost();
+        assert_eq!(cost.turns().len(), 3);
+        assert_eq!(cost.turns()[0].model, Model::Gpt4o);
+        assert_eq!(cost.total_tokens(), 3300);
+        let turn = usage().cost_usd(&Model::Gpt4o).unwrap();
+        assert!((cost.total_cost_usd() - 3.0 * turn).abs() < 1e-12);
+        assert!(!cost.is_partial());
+
+        conversation.ask("third").unwrap_err();
+        assert_eq!(conversation.cost().turns().len(), 3);
+
+        let backend = StreamingBackend { fail: false };
+        let mut conversation = Conversation::with_model(&backend, "Hi", "llama3");
+        conversation.ask_streaming("Hi", |_| {}).unwrap();
+        assert_eq!(conversation.cost().total_tokens(), 1100);
+        assert_eq!(conversation.cost().total_cost_usd(), 0.0);
+        assert!(conversation.cost().is_partial());
+    }
+
+    /// Create a scripted backend with the given replies
+    fn scripted(replies: &[&'static str]) -> ScriptedBackend {
+        ScriptedBackend {
+            replies: RefCell::new(replies.iter().copied().collect()),
+            requests: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Test that the error policy refuses messages over the budget
+    #[test]
+    fn test_budget_error() {
+        let backend = scripted(&["ok"]);
+        let mut conversation = Conversation::new(&backend, "Be brief.")
+            .with_context_budget(10, ContextPolicy::Error);
+        assert!(matches!(
+            conversation.ask(&"a".repeat(80)),
+            Err(OpenAIError::ContextTooLong {
+                tokens: 23,
+                max: 10
+            })
+        ));
+        assert_eq!(conversation.log().len(), 1);
+        assert_eq!(conversation.ask("hi").unwrap(), "ok");
+    }
+
+    /// Test that the trim policy drops the oldest turns
+    #[test]
+    fn test_budget_trim_oldest() {
+        let backend = scripted(&["one", "two", "three"]);
+        let mut conversation = Conversation::new(&backend, "Be brief.")
+            .with_context_budget(10, ContextPolicy::TrimOldest);
+        conversation.replay(&["first q", "second q"]).unwrap();
+        conversation.ask("third q").unwrap();
+
+        let entries = conversation.log().entries();
+        assert_eq!(entries[0].content, "Be brief.");
+        assert_eq!(entries[1].content, "second q");
+        assert_eq!(entries.last().unwrap().content, "three");
+    }
+
+    /// Test that the summarize policy replaces the turns with a summary
+    #[test]
+    fn test_budget_summarize() {
+        let backend = scripted(&["one", "sum", "two"]);
+        let mut conversation = Conversation::new(&backend, "Be brief.")
+            .with_context_budget(15, ContextPolicy::Summarize);
+        conversation.ask(&"a".repeat(48)).unwrap();
+        conversation.ask("next").unwrap();
+
+        let entries = conversation.log().entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            entries[1].content,
+            "Summary of the earlier conversation: sum"
+        );
+        assert_eq!(entries[3].content, "two");
+    }
+
+    /// Test summarizing the old turns with the model, keeping the recent ones
+    #[test]
+    fn test_summarize_old() {
+        let backend = scripted(&["answer 0", "answer 1", "answer 2", "Two answers."]);
+        let mut conversation = Conversation::new(&backend, "Be brief.");
+        conversation
+            .replay(&["question 0", "question 1", "question 2"])
+            .unwrap();
+        conversation.summarize_old(2).unwrap();
+
+        let requests = backend.requests.borrow();
+        assert_eq!(requests.len(), 4);
+        let sent: Vec<_> = requests[3]
+            .messages()
+            .entries()
+            .iter()
+            .map(|entry| (entry.role.clone(), entry.content.as_str()))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (ChatRole::System, SUMMARIZE_PROMPT),
+                (
+                    ChatRole::User,
+                    "user: question 0\nassistant: answer 0\nuser: question 1\n\
+                     assistant: answer 1"
+                ),
+            ]
+        );
+
+        let entries = conversation.log().entries();
+        let contents: Vec<_> =
+            entries.iter().map(|entry| entry.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            [
+                "Be brief.",
+                "Summary of the earlier conversation: Two answers.",
+                "question 2",
+                "answer 2"
+            ]
+        );
+        assert_eq!(entries[1].role, ChatRole::System);
+        assert_eq!(conversation.cost().turns().len(), 4);
+        assert_eq!(conversation.cost().turns()[3].usage, usage());
+
+        // Nothing is left to summarize, so no completion is made
+        conversation.summarize_old(4).unwrap();
+        assert_eq!(backend.requests.borrow().len(), 4);
+    }
+
+    /// Test selecting the old entries to summarize
+    #[test]
+    fn test_old_entries() {
+        let openai = OpenAI::new("key".to_string());
+        let conversation = conversation(&openai);
+
+        let old = conversation.old_entries(2);
+        assert_eq!(old.len(), 4);
+        assert_eq!(old[0].content, "question 0");
+        assert_eq!(
+            transcript(old),
+            "user: question 0\nassistant: answer 0\nuser: question 1\nassistant: answer 1"
+        );
+        assert!(conversation.old_entries(10).is_empty());
+    }
+
+    /// Test replacing old entries with their summary
+    #[test]
+    fn test_replace_old() {
+        let openai = OpenAI::new("key".to_string());
+        let mut conversation = conversation(&openai);
+
+        conversation.replace_old(2, "Two questions were answered.".to_string());
+
+        let entries = conversation.log().entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].content, "Be brief.");
+        assert_eq!(entries[1].role, ChatRole::System);
+        assert_eq!(
+            entries[1].content,
+            "Summary of the earlier conversation: Two questions were answered."
+        );
+        assert_eq!(entries[2].content, "question 2");
+        assert_eq!(entries[3].content, "answer 2");
+    }
+}
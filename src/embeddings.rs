@@ -0,0 +1,242 @@
+use crate::error::OpenAIError;
+use crate::model::Model;
+use crate::tokens::{TokenCounter, DEFAULT_COUNTER};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// The maximum amount of tokens of a single embedding input
+pub const MAX_INPUT_TOKENS: usize = 8191;
+
+/// The maximum amount of tokens of all the inputs of one embeddings request
+pub const MAX_REQUEST_TOKENS: usize = 300_000;
+
+/// The maximum amount of inputs of one embeddings request
+pub const MAX_REQUEST_INPUTS: usize = 2048;
+
+/// An embeddings request
+#[derive(Serialize, Debug, Clone)]
+pub struct EmbeddingRequest<'a> {
+    /// The embedding model, such as `text-embedding-3-small`
+    pub model: &'a str,
+    /// The texts to embed
+    pub input: &'a [String],
+}
+
+/// The embedding of one input
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Embedding {
+    /// The position of the input in the request
+    pub index: usize,
+    /// The embedding vector
+    pub embedding: Vec<f32>,
+}
+
+/// An embeddings response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingResponse {
+    /// The embeddings, one per input
+    pub data: Vec<Embedding>,
+    /// The model that computed the embeddings
+    pub model: String,
+    /// The tokens of the inputs, without completion tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<EmbeddingUsage>,
+}
+
+/// The usage of an embeddings request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmbeddingUsage {
+    /// The tokens of the inputs
+    pub prompt_tokens: u32,
+    /// The tokens in the total, the same as the input tokens
+    pub total_tokens: u32,
+}
+
+impl EmbeddingResponse {
+    /// The embedding vectors in the order of the inputs
+    pub fn into_vectors(mut self) -> Vec<Vec<f32>> {
+        self.data.sort_by_key(|embedding| embedding.index);
+        self.data.into_iter().map(|e| e.embedding).collect()
+    }
+}
+
+/// Split inputs with the given token counts into consecutive batches that each
+/// stay within [`MAX_REQUEST_TOKENS`] and [`MAX_REQUEST_INPUTS`]. Fails with
+/// [`OpenAIError::InputTooLong`] if a single input is over [`MAX_INPUT_TOKENS`].
+pub fn batch_inputs(tokens: &[usize]) -> Result<Vec<Range<usize>>, OpenAIError> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut batch_tokens = 0;
+    for (index, &count) in tokens.iter().enumerate() {
+        if count > MAX_INPUT_TOKENS {
+            return Err(OpenAIError::InputTooLong {
+                index,
+                tokens: count,
+                max: MAX_INPUT_TOKENS,
+            });
+        }
+        let full = index - start == MAX_REQUEST_INPUTS;
+        if full || batch_tokens + count > MAX_REQUEST_TOKENS {
+            batches.push(start..index);
+            start = index;
+            batch_tokens = 0;
+        }
+        batch_tokens += count;
+    }
+    if start < tokens.len() {
+        batches.push(start..tokens.len());
+    }
+    Ok(batches)
+}
+
+/// Split a long text into overlapping windows of about `window_tokens` tokens
+/// to embed, counted with the [`DEFAULT_COUNTER`]. See [`window_text_with`].
+pub fn window_text(
+    text: &str,
+    window_tokens: usize,
+    overlap_tokens: usize,
+    model: &Model,
+) -> Vec<String> {
+    window_text_with(DEFAULT_COUNTER, text, window_tokens, overlap_tokens, model)
+}
+
+/// Split a long text into windows of at most `window_tokens` tokens, each
+/// starting with up to `overlap_tokens` tokens of the end of the previous one,
+/// so a passage cut by one window boundary is whole in a neighbour.
+///
+/// Windows only break between words and keep the whitespace inside them. The
+/// tokens of the words are counted one at a time, which slightly overestimates
+/// the tokens of a window. A word longer than the window gets a window of its
+/// own, and the last window holds the tail of the text even if it is shorter.
+pub fn window_text_with(
+    counter: &dyn TokenCounter,
+    text: &str,
+    window_tokens: usize,
+    overlap_tokens: usize,
+    model: &Model,
+) -> Vec<String> {
+    // The byte span of every word, then its tokens with the whitespace before it
+    let mut spans = Vec::new();
+    let mut word_start = None;
+    for (index, c) in text.char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (false, None) => word_start = Some(index),
+            (true, Some(start)) => {
+                spans.push((start, index));
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    spans.extend(word_start.map(|start| (start, text.len())));
+    let mut previous_end = 0;
+    let mut words: Vec<(usize, usize, usize)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        words.push((start, end, counter.count(&text[previous_end..end], model)));
+        previous_end = end;
+    }
+
+    let mut windows = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let mut end = start;
+        let mut tokens = 0;
+        while end < words.len()
+            && (end == start || tokens + words[end].2 <= window_tokens)
+        {
+            tokens += words[end].2;
+            end += 1;
+        }
+        windows.push(text[words[start].0..words[end - 1].1].to_string());
+        if end == words.len() {
+            break;
+        }
+        // Step back over the overlap, always moving forward by one word at least
+        let mut next = end;
+        let mut overlap = 0;
+        while next > start + 1 && overlap + words[next - 1].2 <= overlap_tokens {
+            overlap += words[next - 1].2;
+            next -= 1;
+        }
+        start = next;
+    }
+    windows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::HeuristicCounter;
+
+    /// Test sliding overlapping windows over a text, one token per word
+    #[test]
+    fn test_window_text() {
+        let counter = HeuristicCounter;
+        let windows = |text, window, overlap| {
+            window_text_with(&counter, text, window, overlap, &Model::Gpt4o)
+        };
+        let text = "aaa bbb ccc ddd eee";
+        assert_eq!(
+            windows(text, 2, 1),
+            ["aaa bbb", "bbb ccc", "ccc ddd", "ddd eee"]
+        );
+        assert_eq!(windows(text, 3, 0), ["aaa bbb ccc", "ddd eee"]);
+        assert_eq!(
+            windows(text, 3, 5),
+            ["aaa bbb ccc", "bbb ccc ddd", "ccc ddd eee"]
+        );
+        assert_eq!(windows(text, 100, 10), [text]);
+
+        assert_eq!(
+            windows("aaaaaaaaaaaaa bbb\n\nccc", 3, 0),
+            ["aaaaaaaaaaaaa", "bbb\n\nccc"]
+        );
+        assert!(windows("  ", 10, 2).is_empty());
+    }
+
+    /// Test splitting inputs on the token and input limits
+    #[test]
+    fn test_batch_inputs() {
+        assert!(batch_inputs(&[]).unwrap().is_empty());
+        assert_eq!(batch_inputs(&[10, 20, 30]).unwrap(), vec![0..3]);
+
+        let tokens = vec![8000; 80];
+        let batches = batch_inputs(&tokens).unwrap();
+        assert_eq!(batches, vec![0..37, 37..74, 74..80]);
+
+        let tokens = vec![1; 5000];
+        let batches = batch_inputs(&tokens).unwrap();
+        assert_eq!(batches, vec![0..2048, 2048..4096, 4096..5000]);
+
+        let result = batch_inputs(&[10, 9000]);
+        assert!(matches!(
+            result,
+            Err(OpenAIError::InputTooLong {
+                index: 1,
+                tokens: 9000,
+                ..
+            })
+        ));
+    }
+
+    /// Test that vectors are returned in the order of the inputs
+    #[test]
+    fn test_into_vectors() {
+        let response: EmbeddingResponse = serde_json::from_str(
+            r#"{
+                "object": "list",
+                "data": [
+                    {"object": "embedding", "index": 1, "embedding": [0.5, 0.5]},
+                    {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]}
+                ],
+                "model": "text-embedding-3-small",
+                "usage": {"prompt_tokens": 4, "total_tokens": 4}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.into_vectors(),
+            vec![vec![1.0, 0.0], vec![0.5, 0.5]]
+        );
+    }
+}
@@ -0,0 +1,691 @@
+use crate::openai::ChatRole;
+#[cfg(feature = "http")]
+use serde::Deserialize;
+use std::fmt;
+
+/// The maximum amount of bytes of a response body kept in a decode error
+const MAX_ERROR_BODY_BYTES: usize = 4 * 1024;
+
+/// Errors that can happen while talking to the OpenAI api
+#[derive(Debug)]
+pub enum OpenAIError {
+    /// The HTTP request failed
+    #[cfg(feature = "http")]
+    Http(reqwest::Error),
+    /// The response body could not be decoded
+    Decode {
+        /// The underlying decoding error
+        source: serde_json::Error,
+        /// The raw response body, truncated to a few KB
+        body: String,
+    },
+    /// The response has no choices, which happens when content is filtered
+    NoChoices,
+    /// The model declined to answer, with the given explanation
+    Refusal(String),
+    /// The reply was cut at the token limit, with the partial content
+    Truncated(String),
+    /// The response has no usage, which some compatible providers omit
+    NoUsage,
+    /// Reading the response failed
+    Io(std::io::Error),
+    /// A streamed response failed or ended unexpectedly
+    Stream(String),
+    /// The response was served by another model than the required snapshot
+    ModelMismatch {
+        /// The required snapshot
+        expected: String,
+        /// The model reported by the response
+        got: String,
+    },
+    /// An input is longer than the api accepts
+    InputTooLong {
+        /// The position of the input
+        index: usize,
+        /// The estimated tokens of the input
+        tokens: usize,
+        /// The maximum tokens of an input
+        max: usize,
+    },
+    /// The conversation would exceed its token budget
+    ContextTooLong {
+        /// The estimated tokens of the conversation with the new message
+        tokens: usize,
+        /// The token budget of the conversation
+        max: usize,
+    },
+    /// The request would exceed the rate limit of the client
+    RateLimited {
+        /// How long until the request fits in the limit
+        retry_after: std::time::Duration,
+    },
+    /// A replayed request has no recorded response, with the key of the request
+    NotRecorded(String),
+    /// The api rejected the api key, with the message of the api
+    InvalidKey(String),
+    /// A request of a batch failed, and so did the identical requests sharing
+    /// its result
+    BatchDuplicate {
+        /// The position of the request that was sent
+        index: usize,
+        /// The error of that request
+        message: String,
+    },
+    /// The model still asked for tools after the maximum amount of rounds of
+    /// tool calls, with that maximum
+    TooManyToolRounds(usize),
+    /// The parameters schema of a tool of the request is invalid
+    InvalidTool(ToolError),
+    /// The response body was longer than the limit of the client, in bytes
+    ResponseTooLarge(usize),
+    /// The request took longer than the timeout of the client. It is not
+    /// retried, since the abandoned request may still be running.
+    Timeout(std::time::Duration),
+    /// The api answered with an error status
+    Api {
+        /// The HTTP status code
+        status: u16,
+        /// The error message sent by the api, or the raw body if there was none
+        message: String,
+        /// The machine readable code of the error, if the api sent one
+        code: Option<ApiErrorCode>,
+    },
+}
+
+/// The machine readable code of an api error, for the codes callers commonly
+/// act on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiErrorCode {
+    /// The account ran out of credits, which retrying does not fix
+    InsufficientQuota,
+    /// Too many requests or tokens were sent in a short time
+    RateLimitExceeded,
+    /// The request has more tokens than the context window of the model
+    ContextLengthExceeded,
+    /// The api key is wrong or was revoked
+    InvalidApiKey,
+    /// Any other code, as sent by the api
+    Unknown(String),
+}
+
+impl ApiErrorCode {
+    /// The code as sent by the api
+    pub fn as_str(&self) -> &str {
+        match self {
+            ApiErrorCode::InsufficientQuota => "insufficient_quota",
+            ApiErrorCode::RateLimitExceeded => "rate_limit_exceeded",
+            ApiErrorCode::ContextLengthExceeded => "context_length_exceeded",
+            ApiErrorCode::InvalidApiKey => "invalid_api_key",
+            ApiErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<&str> for ApiErrorCode {
+    fn from(code: &str) -> ApiErrorCode {
+        match code {
+            "insufficient_quota" => ApiErrorCode::InsufficientQuota,
+            "rate_limit_exceeded" => ApiErrorCode::RateLimitExceeded,
+            "context_length_exceeded" => ApiErrorCode::ContextLengthExceeded,
+            "invalid_api_key" => ApiErrorCode::InvalidApiKey,
+            other => ApiErrorCode::Unknown(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ApiErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The body of an api error response
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    /// The error itself
+    error: ApiErrorDetails,
+}
+
+/// The details of an api error
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct ApiErrorDetails {
+    /// The human readable message
+    message: String,
+    /// The machine readable code, which some errors leave out, set to null or
+    /// send as a number
+    #[serde(default)]
+    code: Option<serde_json::Value>,
+}
+
+impl OpenAIError {
+    /// Create a decode error keeping a truncated copy of the raw body
+    pub(crate) fn decode(source: serde_json::Error, body: &str) -> OpenAIError {
+        OpenAIError::Decode {
+            source,
+            body: truncate_body(body),
+        }
+    }
+
+    /// Create an api error from an error status and the response body
+    #[cfg(feature = "http")]
+    pub(crate) fn api(status: u16, body: &str) -> OpenAIError {
+        let (message, code) = match serde_json::from_str::<ApiErrorBody>(body) {
+            Ok(parsed) => (
+                parsed.error.message,
+                parsed
+                    .error
+                    .code
+                    .as_ref()
+                    .and_then(serde_json::Value::as_str)
+                    .map(ApiErrorCode::from),
+            ),
+            Err(_) => (truncate_body(body), None),
+        };
+        OpenAIError::Api {
+            status,
+            message,
+            code,
+        }
+    }
+
+    /// Whether the request that failed with this error may succeed if retried
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            #[cfg(feature = "http")]
+            OpenAIError::Http(e) => e.is_timeout() || e.is_connect(),
+            OpenAIError::Api {
+                code: Some(ApiErrorCode::InsufficientQuota),
+                ..
+            } => false,
+            OpenAIError::Api { status, .. } => *status == 429 || *status >= 500,
+            // Only failures of the connection, not of local files
+            OpenAIError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+            ),
+            // Every attempt would leave another worker blocked on a hung
+            // connection, so watchdog timeouts are given up on at once
+            OpenAIError::Timeout(_) => false,
+            OpenAIError::Decode { .. } | OpenAIError::Stream(_) => false,
+            OpenAIError::NoChoices | OpenAIError::NoUsage => false,
+            OpenAIError::Truncated(_) | OpenAIError::NotRecorded(_) => false,
+            OpenAIError::BatchDuplicate { .. } | OpenAIError::InvalidKey(_) => false,
+            OpenAIError::Refusal(_) | OpenAIError::ModelMismatch { .. } => false,
+            OpenAIError::InputTooLong { .. } | OpenAIError::TooManyToolRounds(_) => {
+                false
+            }
+            OpenAIError::ResponseTooLarge(_) | OpenAIError::InvalidTool(_) => false,
+            OpenAIError::RateLimited { .. } | OpenAIError::ContextTooLong { .. } => {
+                false
+            }
+        }
+    }
+}
+
+/// Truncate a body to at most `MAX_ERROR_BODY_BYTES`, on a char boundary
+fn truncate_body(body: &str) -> String {
+    if body.len() <= MAX_ERROR_BODY_BYTES {
+        return body.to_string();
+    }
+    let mut end = MAX_ERROR_BODY_BYTES;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes truncated)", &body[..end], body.len() - end)
+}
+
+impl fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "http")]
+            OpenAIError::Http(e) => write!(f, "HTTP request failed: {}", e),
+            OpenAIError::Decode { source, body } => {
+                write!(f, "failed to decode response: {} (body: {})", source, body)
+            }
+            OpenAIError::NoChoices => write!(f, "the response has no choices"),
+            OpenAIError::NoUsage => write!(f, "the response has no usage"),
+            OpenAIError::Truncated(partial) => write!(
+                f,
+                "the reply was cut at the token limit after {} characters",
+                partial.chars().count()
+            ),
+            OpenAIError::Refusal(refusal) => {
+                write!(f, "the model refused: {}", refusal)
+            }
+            OpenAIError::Io(e) => write!(f, "failed to read response: {}", e),
+            OpenAIError::Stream(message) => write!(f, "stream failed: {}", message),
+            OpenAIError::ModelMismatch { expected, got } => {
+                write!(
+                    f,
+                    "expected model {} but the response came from {}",
+                    expected, got
+                )
+            }
+            OpenAIError::InputTooLong { index, tokens, max } => write!(
+                f,
+                "input {} has {} tokens, more than the maximum of {}",
+                index, tokens, max
+            ),
+            OpenAIError::ContextTooLong { tokens, max } => write!(
+                f,
+                "the conversation needs {} tokens, more than its budget of {}",
+                tokens, max
+            ),
+            OpenAIError::RateLimited { retry_after } => {
+                write!(f, "rate limit reached, retry in {:?}", retry_after)
+            }
+            OpenAIError::NotRecorded(key) => {
+                write!(f, "no response was recorded for request {}", key)
+            }
+            OpenAIError::InvalidKey(message) => {
+                write!(f, "the api key was rejected: {}", message)
+            }
+            OpenAIError::BatchDuplicate { index, message } => {
+                write!(
+                    f,
+                    "identical request {} of the batch failed: {}",
+                    index, message
+                )
+            }
+            OpenAIError::TooManyToolRounds(max) => {
+                write!(f, "the model still called tools after {} rounds", max)
+            }
+            OpenAIError::InvalidTool(e) => write!(f, "invalid tool: {}", e),
+            OpenAIError::ResponseTooLarge(max) => {
+                write!(f, "the response body is longer than {} bytes", max)
+            }
+            OpenAIError::Timeout(timeout) => {
+                write!(f, "the request timed out after {:?}", timeout)
+            }
+            OpenAIError::Api {
+                status,
+                message,
+                code: Some(code),
+            } => write!(f, "api error {} ({}): {}", status, code, message),
+            OpenAIError::Api {
+                status,
+                message,
+                code: None,
+            } => {
+                write!(f, "api error {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpenAIError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "http")]
+            OpenAIError::Http(e) => Some(e),
+            OpenAIError::Decode { source, .. } => Some(source),
+            OpenAIError::Io(e) => Some(e),
+            OpenAIError::InvalidTool(e) => Some(e),
+            OpenAIError::NoChoices
+            | OpenAIError::NoUsage
+            | OpenAIError::Truncated(_)
+            | OpenAIError::Refusal(_)
+            | OpenAIError::RateLimited { .. }
+            | OpenAIError::Timeout(_)
+            | OpenAIError::NotRecorded(_)
+            | OpenAIError::BatchDuplicate { .. }
+            | OpenAIError::InvalidKey(_)
+            | OpenAIError::ContextTooLong { .. }
+            | OpenAIError::ModelMismatch { .. }
+            | OpenAIError::InputTooLong { .. }
+            | OpenAIError::TooManyToolRounds(_)
+            | OpenAIError::ResponseTooLarge(_)
+            | OpenAIError::Stream(_)
+            | OpenAIError::Api { .. } => None,
+        }
+    }
+}
+
+impl From<ToolError> for OpenAIError {
+    fn from(e: ToolError) -> OpenAIError {
+        OpenAIError::InvalidTool(e)
+    }
+}
+
+impl From<std::io::Error> for OpenAIError {
+    fn from(e: std::io::Error) -> OpenAIError {
+        OpenAIError::Io(e)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<reqwest::Error> for OpenAIError {
+    fn from(e: reqwest::Error) -> OpenAIError {
+        OpenAIError::Http(e)
+    }
+}
+
+/// Problems found when validating the structure of a chat log
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogError {
+    /// A system message appears somewhere other than the start of the log
+    MisplacedSystem {
+        /// The index of the offending entry
+        index: usize,
+    },
+    /// An entry breaks the user/assistant alternation
+    UnexpectedRole {
+        /// The index of the offending entry
+        index: usize,
+        /// The role that was expected at this index
+        expected: ChatRole,
+        /// The role that was found
+        found: ChatRole,
+    },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::MisplacedSystem { index } => {
+                write!(f, "system message at index {} is not at the start", index)
+            }
+            LogError::UnexpectedRole {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected a {} message at index {}, found a {} message",
+                expected.name(),
+                index,
+                found.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+/// Reasons the metadata of a request would be rejected by the api
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataError {
+    /// There are more pairs than allowed
+    TooManyPairs {
+        /// The amount of pairs given
+        count: usize,
+    },
+    /// A key is longer than allowed
+    KeyTooLong {
+        /// The offending key
+        key: String,
+    },
+    /// A value is longer than allowed
+    ValueTooLong {
+        /// The key of the offending value
+        key: String,
+    },
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::TooManyPairs { count } => {
+                write!(f, "metadata has {} pairs, at most 16 are allowed", count)
+            }
+            MetadataError::KeyTooLong { key } => {
+                write!(f, "metadata key {:?} is longer than 64 characters", key)
+            }
+            MetadataError::ValueTooLong { key } => {
+                write!(
+                    f,
+                    "metadata value of {:?} is longer than 512 characters",
+                    key
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// Reasons the parameters schema of a tool would be rejected by the api, each
+/// with the name of the tool
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolError {
+    /// The parameters are not a JSON object
+    NotAnObject {
+        /// The name of the tool
+        tool: String,
+    },
+    /// The parameters have no `type`
+    MissingType {
+        /// The name of the tool
+        tool: String,
+    },
+    /// The `properties` of the parameters are not an object
+    InvalidProperties {
+        /// The name of the tool
+        tool: String,
+    },
+    /// The schema of a property is not an object
+    InvalidProperty {
+        /// The name of the tool
+        tool: String,
+        /// The name of the property
+        property: String,
+    },
+    /// The `required` names are not an array of strings
+    InvalidRequired {
+        /// The name of the tool
+        tool: String,
+    },
+    /// A required name is not one of the properties
+    UnknownRequired {
+        /// The name of the tool
+        tool: String,
+        /// The required name
+        name: String,
+    },
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolError::NotAnObject { tool } => {
+                write!(f, "the parameters of tool {} are not an object", tool)
+            }
+            ToolError::MissingType { tool } => {
+                write!(f, "the parameters of tool {} have no type", tool)
+            }
+            ToolError::InvalidProperties { tool } => {
+                write!(f, "the properties of tool {} are not an object", tool)
+            }
+            ToolError::InvalidProperty { tool, property } => write!(
+                f,
+                "the schema of property {} of tool {} is not an object",
+                property, tool
+            ),
+            ToolError::InvalidRequired { tool } => write!(
+                f,
+                "the required names of tool {} are not an array of strings",
+                tool
+            ),
+            ToolError::UnknownRequired { tool, name } => write!(
+                f,
+                "tool {} requires {}, which is not one of its properties",
+                tool, name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+/// Errors that can happen while extracting JSON from a reply
+#[derive(Debug)]
+pub enum ExtractError {
+    /// The reply contains no balanced JSON object or array
+    NotFound,
+    /// The JSON found in the reply does not deserialize to the expected type
+    Invalid {
+        /// The extracted JSON
+        json: String,
+        /// The underlying decoding error
+        source: serde_json::Error,
+    },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::NotFound => write!(f, "the reply contains no JSON"),
+            ExtractError::Invalid { json, source } => {
+                write!(
+                    f,
+                    "failed to decode extracted JSON: {} (json: {})",
+                    source, json
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExtractError::NotFound => None,
+            ExtractError::Invalid { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Errors that can happen while loading a configuration file
+#[cfg(feature = "toml")]
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    Io(std::io::Error),
+    /// The file is not a valid configuration
+    Parse(toml::de::Error),
+}
+
+#[cfg(feature = "toml")]
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read configuration: {}", e),
+            ConfigError::Parse(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "toml")]
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "toml")]
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+#[cfg(feature = "toml")]
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> ConfigError {
+        ConfigError::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Test that long bodies are truncated on a char boundary
+    #[test]
+    fn test_truncate_body() {
+        assert_eq!(truncate_body("short"), "short");
+
+        let body = "é".repeat(MAX_ERROR_BODY_BYTES);
+        let truncated = truncate_body(&body);
+        assert!(truncated.len() < MAX_ERROR_BODY_BYTES + 64);
+        assert!(truncated.ends_with("bytes truncated)"));
+    }
+
+    /// Test that only io errors of the connection are retried
+    #[test]
+    fn test_io_retryable() {
+        let io_error = |kind| OpenAIError::Io(io::Error::from(kind));
+        assert!(io_error(io::ErrorKind::ConnectionReset).is_retryable());
+        assert!(io_error(io::ErrorKind::TimedOut).is_retryable());
+        assert!(io_error(io::ErrorKind::Interrupted).is_retryable());
+        assert!(!io_error(io::ErrorKind::NotFound).is_retryable());
+        assert!(!io_error(io::ErrorKind::PermissionDenied).is_retryable());
+        assert!(!io_error(io::ErrorKind::InvalidData).is_retryable());
+    }
+
+    /// Test parsing api errors and deciding whether to retry them
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_api_error() {
+        let error = OpenAIError::api(
+            429,
+            r#"{"error": {"message": "Rate limit reached", "type": "requests"}}"#,
+        );
+        match &error {
+            OpenAIError::Api {
+                status,
+                message,
+                code,
+            } => {
+                assert_eq!(*status, 429);
+                assert_eq!(message, "Rate limit reached");
+                assert_eq!(*code, None);
+            }
+            other => panic!("expected an api error, got {:?}", other),
+        }
+        assert!(error.is_retryable());
+
+        let quota = OpenAIError::api(
+            429,
+            r#"{"error": {"message": "You exceeded your quota", "code": "insufficient_quota"}}"#,
+        );
+        assert!(matches!(
+            quota,
+            OpenAIError::Api {
+                code: Some(ApiErrorCode::InsufficientQuota),
+                ..
+            }
+        ));
+        assert!(!quota.is_retryable());
+        assert_eq!(
+            quota.to_string(),
+            "api error 429 (insufficient_quota): You exceeded your quota"
+        );
+        let other = OpenAIError::api(
+            400,
+            r#"{"error": {"message": "Bad", "code": "model_not_found"}}"#,
+        );
+        assert!(matches!(
+            other,
+            OpenAIError::Api { code: Some(ApiErrorCode::Unknown(code)), .. } if code == "model_not_found"
+        ));
+        let numeric = OpenAIError::api(
+            500,
+            r#"{"error": {"message": "Server error", "code": 500}}"#,
+        );
+        assert!(matches!(
+            numeric,
+            OpenAIError::Api { code: None, ref message, .. } if message == "Server error"
+        ));
+        assert!(OpenAIError::api(503, "Service Unavailable").is_retryable());
+        assert!(!OpenAIError::api(400, "Bad Request").is_retryable());
+    }
+}
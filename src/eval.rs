@@ -0,0 +1,126 @@
+use crate::error::OpenAIError;
+use crate::model::Model;
+use crate::openai::{ChatCompletionResponse, CompletionUsage};
+
+/// The outcome of one model in a comparison, such as the ones returned by
+/// `OpenAI::compare_models`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparisonRow {
+    /// The model that was asked
+    pub model: Model,
+    /// The length of the reply in characters, if there was one
+    pub reply_chars: Option<usize>,
+    /// The usage of the call, if it succeeded and reported one
+    pub usage: Option<CompletionUsage>,
+    /// The cost in USD of the call, if the model has a known pricing
+    pub cost_usd: Option<f64>,
+    /// The error of the call, if it failed
+    pub error: Option<String>,
+}
+
+impl ComparisonRow {
+    /// Summarize the result of a model
+    pub fn new(
+        model: &Model,
+        result: &Result<ChatCompletionResponse, OpenAIError>,
+    ) -> ComparisonRow {
+        let (reply_chars, usage, cost_usd, error) = match result {
+            Ok(response) => (
+                response.reply().ok().map(|reply| reply.chars().count()),
+                response.usage.clone(),
+                response.cost_usd(),
+                None,
+            ),
+            Err(e) => (None, None, None, Some(e.to_string())),
+        };
+        ComparisonRow {
+            model: model.clone(),
+            reply_chars,
+            usage,
+            cost_usd,
+            error,
+        }
+    }
+}
+
+/// Render the results of a comparison as a plain text table with the reply
+/// length, token usage and cost of every model side by side. Unknown values
+/// are shown as `-`, and failed calls show their error instead.
+pub fn comparison_table(
+    results: &[(Model, Result<ChatCompletionResponse, OpenAIError>)],
+) -> String {
+    let rows: Vec<_> = results
+        .iter()
+        .map(|(model, result)| ComparisonRow::new(model, result))
+        .collect();
+    let width = rows
+        .iter()
+        .map(|row| row.model.name().len())
+        .chain(["model".len()])
+        .max()
+        .unwrap_or_default();
+    let unknown = || "-".to_string();
+
+    let mut table = format!(
+        "{:<width$}  {:>6}  {:>7}  {:>10}  {:>10}\n",
+        "model", "chars", "prompt", "completion", "cost"
+    );
+    for row in rows {
+        let name = row.model.name();
+        if let Some(error) = row.error {
+            table.push_str(&format!("{:<width$}  error: {}\n", name, error));
+            continue;
+        }
+        let chars = row
+            .reply_chars
+            .map_or_else(unknown, |chars| chars.to_string());
+        let (prompt, completion) = match &row.usage {
+            Some(usage) => (
+                usage.prompt_tokens.to_string(),
+                usage.completion_tokens.to_string(),
+            ),
+            None => (unknown(), unknown()),
+        };
+        let cost = row
+            .cost_usd
+            .map_or_else(unknown, |cost| format!("${:.6}", cost));
+        table.push_str(&format!(
+            "{:<width$}  {:>6}  {:>7}  {:>10}  {:>10}\n",
+            name, chars, prompt, completion, cost
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::completion_body_with_usage;
+
+    /// A response of the given model answering `Hello!`
+    fn response(model: &str) -> ChatCompletionResponse {
+        let body = completion_body_with_usage(model, "Hello!", "stop", 1000, 500);
+        ChatCompletionResponse::parse(&body).unwrap()
+    }
+
+    /// Test tabulating the results of several models
+    #[test]
+    fn test_comparison_table() {
+        let results = vec![
+            (Model::Gpt4oMini, Ok(response("gpt-4o-mini"))),
+            (Model::from("llama3"), Ok(response("llama3"))),
+            (Model::Gpt4o, Err(OpenAIError::NoChoices)),
+        ];
+
+        let row = ComparisonRow::new(&results[0].0, &results[0].1);
+        assert_eq!(row.reply_chars, Some(6));
+        assert!((row.cost_usd.unwrap() - 0.00045).abs() < 1e-12);
+        assert_eq!(
+            comparison_table(&results),
+            "model         chars   prompt  completion        cost\n\
+             gpt-4o-mini       6     1000         500   $0.000450\n\
+             llama3            6     1000         500           -\n\
+             gpt-4o       error: the response has no choices\n"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+#[cfg(feature = "regex")]
+pub mod anonymize;
+pub mod anthropic;
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod azure;
+pub mod backend;
+pub mod builder;
+pub mod cassette;
+#[cfg(feature = "blocking")]
+pub mod client;
+pub mod config;
+#[cfg(feature = "blocking")]
+pub mod conversation;
+pub mod embeddings;
+pub mod error;
+pub mod eval;
+pub mod metrics;
+pub mod model;
+pub mod openai;
+pub mod playground;
+pub mod prompts;
+pub mod provider;
+pub mod rate_limit;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod session;
+pub mod stream;
+pub mod terminal;
+#[cfg(test)]
+mod test_support;
+pub mod text;
+pub mod tokens;
+pub mod tools;
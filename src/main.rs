@@ -1,5 +1,3 @@
-mod openai;
-
 fn main() {
     println!("Hello, world!");
 }
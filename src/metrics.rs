@@ -0,0 +1,202 @@
+use crate::model::Model;
+use crate::openai::{CompletionUsage, FinishReason};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The columns of the CSV written by [`UsageLog::to_csv`]
+const CSV_HEADER: &str = "timestamp,model,prompt_tokens,completion_tokens,cost_usd,\
+    latency_ms,finish_reason";
+
+/// A counter increment, in a shape any metrics backend such as Prometheus or
+/// OpenTelemetry can take without this crate depending on it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    /// The name of the counter
+    pub name: String,
+    /// The labels of the increment, as (name, value) pairs
+    pub labels: Vec<(String, String)>,
+    /// How much the counter grows
+    pub value: f64,
+}
+
+impl CompletionUsage {
+    /// The counter increments of one call with this usage: `prompt_tokens`,
+    /// `completion_tokens` and `total_tokens`, and `cost_usd` if the model has
+    /// a known pricing, all labeled with the `model`
+    pub fn metrics(&self, model: &Model) -> Vec<Metric> {
+        let labels = vec![("model".to_string(), model.name().to_string())];
+        let metric = |name: &str, value: f64| Metric {
+            name: name.to_string(),
+            labels: labels.clone(),
+            value,
+        };
+        let mut metrics = vec![
+            metric("prompt_tokens", f64::from(self.prompt_tokens)),
+            metric("completion_tokens", f64::from(self.completion_tokens)),
+            metric("total_tokens", f64::from(self.total_tokens)),
+        ];
+        metrics.extend(self.cost_usd(model).map(|cost| metric("cost_usd", cost)));
+        metrics
+    }
+}
+
+/// The usage and latency of one completion
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    /// When the completion ended, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The model of the completion
+    pub model: String,
+    /// The tokens of the prompt
+    pub prompt_tokens: u32,
+    /// The tokens of the reply
+    pub completion_tokens: u32,
+    /// The cost of the completion, if the model has a known pricing
+    pub cost_usd: Option<f64>,
+    /// How long the completion took, in milliseconds
+    pub latency_ms: u64,
+    /// Why the first choice ended, unknown for streams
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A record of the usage of many completions, for analyzing cost and latency
+/// trends. It can be shared between clients with `OpenAI::with_usage_log`
+/// and exported with [`UsageLog::to_csv`].
+#[derive(Debug, Default)]
+pub struct UsageLog {
+    /// The recorded completions, in order
+    rows: Mutex<Vec<UsageRow>>,
+}
+
+impl UsageLog {
+    /// Create an empty log
+    pub fn new() -> UsageLog {
+        UsageLog::default()
+    }
+
+    /// Add a row to the log
+    pub fn record(&self, row: UsageRow) {
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        rows.push(row);
+    }
+
+    /// Add a row for a completion that just ended
+    pub fn record_completion(
+        &self,
+        model: &str,
+        usage: &CompletionUsage,
+        latency: Duration,
+        finish_reason: Option<FinishReason>,
+    ) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        self.record(UsageRow {
+            timestamp: now.map_or(0, |elapsed| elapsed.as_secs()),
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost_usd: usage.cost_usd(&Model::from(model)),
+            latency_ms: u64::try_from(latency.as_millis()).unwrap_or(u64::MAX),
+            finish_reason,
+        });
+    }
+
+    /// The recorded rows, in order
+    pub fn rows(&self) -> Vec<UsageRow> {
+        self.rows.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Write the rows as CSV with a header line. Unknown values are empty, and
+    /// models with commas or quotes are quoted.
+    pub fn to_csv(&self, mut w: impl Write) -> io::Result<()> {
+        writeln!(w, "{}", CSV_HEADER)?;
+        for row in self.rows() {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                row.timestamp,
+                csv_field(&row.model),
+                row.prompt_tokens,
+                row.completion_tokens,
+                row.cost_usd
+                    .map(|cost| cost.to_string())
+                    .unwrap_or_default(),
+                row.latency_ms,
+                row.finish_reason.as_ref().map_or("", FinishReason::name),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if it has a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test the metrics of a call, with and without known pricing
+    #[test]
+    fn test_metrics() {
+        let usage = CompletionUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            total_tokens: 1500,
+        };
+
+        let metrics = usage.metrics(&Model::Gpt4oMini);
+        let names: Vec<_> = metrics.iter().map(|metric| metric.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "prompt_tokens",
+                "completion_tokens",
+                "total_tokens",
+                "cost_usd"
+            ]
+        );
+        assert_eq!(metrics[0].value, 1000.0);
+        // 1000 * 0.15 + 500 * 0.6 per million tokens
+        assert!((metrics[3].value - 0.00045).abs() < 1e-12);
+        assert_eq!(
+            metrics[0].labels,
+            [("model".to_string(), "gpt-4o-mini".to_string())]
+        );
+
+        let metrics = usage.metrics(&Model::from("llama3"));
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics[2].labels[0].1, "llama3");
+    }
+
+    /// Test exporting recorded completions as CSV
+    #[test]
+    fn test_usage_log_csv() {
+        let log = UsageLog::new();
+        let usage = CompletionUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            total_tokens: 1500,
+        };
+        let latency = Duration::from_millis(1250);
+        log.record_completion("gpt-4o-mini", &usage, latency, Some(FinishReason::Stop));
+        log.record_completion("local,\"v2\"", &usage, latency, None);
+        assert_eq!(log.rows()[0].cost_usd, usage.cost_usd(&Model::Gpt4oMini));
+
+        let mut csv = Vec::new();
+        log.to_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        let first = lines[1].split_once(',').unwrap().1;
+        assert_eq!(first, "gpt-4o-mini,1000,500,0.00045,1250,stop");
+        let second = lines[2].split_once(',').unwrap().1;
+        assert_eq!(second, "\"local,\"\"v2\"\"\",1000,500,,1250,");
+    }
+}
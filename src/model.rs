@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Models that can be used for chat completions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum Model {
+    /// gpt-3.5-turbo
+    Gpt35Turbo,
+    /// gpt-4
+    Gpt4,
+    /// gpt-4-turbo
+    Gpt4Turbo,
+    /// gpt-4o
+    Gpt4o,
+    /// gpt-4o-mini
+    Gpt4oMini,
+    /// o1
+    O1,
+    /// o1-mini
+    O1Mini,
+    /// o3-mini
+    O3Mini,
+    /// Any other model, including dated snapshots, by name
+    Other(String),
+}
+
+impl Model {
+    /// The name of the model as understood by the api
+    pub fn name(&self) -> &str {
+        match self {
+            Model::Gpt35Turbo => "gpt-3.5-turbo",
+            Model::Gpt4 => "gpt-4",
+            Model::Gpt4Turbo => "gpt-4-turbo",
+            Model::Gpt4o => "gpt-4o",
+            Model::Gpt4oMini => "gpt-4o-mini",
+            Model::O1 => "o1",
+            Model::O1Mini => "o1-mini",
+            Model::O3Mini => "o3-mini",
+            Model::Other(name) => name,
+        }
+    }
+
+    /// The price of the model. Snapshots such as `gpt-4o-2024-08-06` are priced
+    /// like the model they belong to. Returns `None` for unknown models.
+    pub fn pricing(&self) -> Option<ModelPricing> {
+        let name = self.name();
+        PRICING
+            .iter()
+            .find(|(prefix, _, _)| name.starts_with(prefix))
+            .map(|&(_, prompt, completion)| ModelPricing { prompt, completion })
+    }
+
+    /// The maximum amount of prompt and completion tokens of one call, looked up
+    /// like the pricing. Returns `None` for unknown models.
+    pub fn context_window(&self) -> Option<u32> {
+        let name = self.name();
+        CONTEXT_WINDOWS
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map(|&(_, window)| window)
+    }
+
+    /// Whether the model expects `max_completion_tokens` and rejects the legacy
+    /// `max_tokens`. This is the case for reasoning models and later families,
+    /// including their snapshots.
+    pub fn uses_max_completion_tokens(&self) -> bool {
+        let name = self.name();
+        MAX_COMPLETION_TOKENS_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+}
+
+impl From<&str> for Model {
+    fn from(name: &str) -> Model {
+        match name {
+            "gpt-3.5-turbo" => Model::Gpt35Turbo,
+            "gpt-4" => Model::Gpt4,
+            "gpt-4-turbo" => Model::Gpt4Turbo,
+            "gpt-4o" => Model::Gpt4o,
+            "gpt-4o-mini" => Model::Gpt4oMini,
+            "o1" => Model::O1,
+            "o1-mini" => Model::O1Mini,
+            "o3-mini" => Model::O3Mini,
+            other => Model::Other(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for Model {
+    fn from(name: String) -> Model {
+        Model::from(name.as_str())
+    }
+}
+
+impl From<Model> for String {
+    fn from(model: Model) -> String {
+        model.name().to_string()
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The price of a model, in USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPricing {
+    /// The price of prompt tokens
+    pub prompt: f64,
+    /// The price of completion tokens
+    pub completion: f64,
+}
+
+impl ModelPricing {
+    /// The cost in USD of the given amount of prompt tokens
+    pub fn prompt_cost(&self, tokens: u32) -> f64 {
+        f64::from(tokens) * self.prompt / 1_000_000.0
+    }
+
+    /// The cost in USD of the given amount of completion tokens
+    pub fn completion_cost(&self, tokens: u32) -> f64 {
+        f64::from(tokens) * self.completion / 1_000_000.0
+    }
+
+    /// The cost in USD of a call with the given amount of tokens
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        self.prompt_cost(prompt_tokens) + self.completion_cost(completion_tokens)
+    }
+}
+
+/// Pricing by model name prefix, as (prefix, prompt, completion) in USD per million
+/// tokens. More specific prefixes must come first.
+const PRICING: [(&str, f64, f64); 8] = [
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("o1-mini", 1.1, 4.4),
+    ("o1", 15.0, 60.0),
+    ("o3-mini", 1.1, 4.4),
+];
+
+/// Context windows by model name prefix, in tokens. More specific prefixes must
+/// come first.
+const CONTEXT_WINDOWS: [(&str, u32); 8] = [
+    ("gpt-4o-mini", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1-mini", 128_000),
+    ("o1", 200_000),
+    ("o3-mini", 200_000),
+];
+
+/// Prefixes of the names of models that expect `max_completion_tokens`
+const MAX_COMPLETION_TOKENS_PREFIXES: [&str; 4] = ["o1", "o3", "o4", "gpt-5"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that names round trip through the model
+    #[test]
+    fn test_model_names() {
+        assert_eq!(Model::from("gpt-4o"), Model::Gpt4o);
+        assert_eq!(Model::Gpt4oMini.name(), "gpt-4o-mini");
+        assert_eq!(
+            Model::from("gpt-4o-2024-08-06"),
+            Model::Other("gpt-4o-2024-08-06".to_string())
+        );
+        assert_eq!(serde_json::to_string(&Model::O1).unwrap(), "\"o1\"");
+    }
+
+    /// Test that snapshots are priced like their base model
+    #[test]
+    fn test_snapshot_pricing() {
+        assert_eq!(
+            Model::from("gpt-4o-2024-08-06").pricing(),
+            Model::Gpt4o.pricing()
+        );
+        assert_eq!(
+            Model::from("gpt-4o-mini-2024-07-18").pricing(),
+            Model::Gpt4oMini.pricing()
+        );
+        assert_eq!(Model::from("llama-3").pricing(), None);
+    }
+
+    /// Test looking up context windows
+    #[test]
+    fn test_context_window() {
+        assert_eq!(Model::Gpt4.context_window(), Some(8_192));
+        assert_eq!(Model::from("gpt-4-0613").context_window(), Some(8_192));
+        assert_eq!(Model::Gpt4Turbo.context_window(), Some(128_000));
+        assert_eq!(Model::from("llama-3").context_window(), None);
+    }
+
+    /// Test which models expect `max_completion_tokens`
+    #[test]
+    fn test_uses_max_completion_tokens() {
+        assert!(Model::O1.uses_max_completion_tokens());
+        assert!(Model::O3Mini.uses_max_completion_tokens());
+        assert!(Model::from("o1-2024-12-17").uses_max_completion_tokens());
+        assert!(!Model::Gpt4o.uses_max_completion_tokens());
+        assert!(!Model::Gpt35Turbo.uses_max_completion_tokens());
+    }
+}
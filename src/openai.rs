@@ -1,8 +1,70 @@
-use serde::{Deserialize, Serialize};
+use crate::azure::{ContentFilterResults, PromptFilterResult};
+use crate::error::{LogError, MetadataError, OpenAIError};
+use crate::model::Model;
+use crate::text::{join_prefill, repair_json};
+use crate::tools::{FunctionCall, FunctionDefinition, Tool, ToolCall, ToolType};
+use serde::de::DeserializeOwned;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The endpoint of the chat completions api
+#[cfg(feature = "async")]
+pub(crate) const CHAT_COMPLETIONS_URL: &str =
+    "https://api.openai.com/v1/chat/completions";
+
+/// The base url of the OpenAI api, which the endpoints are relative to
+#[cfg(feature = "http")]
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// The `User-Agent` sent when none is configured
+#[cfg(feature = "http")]
+pub const DEFAULT_USER_AGENT: &str = concat!("hch-cgpt/", env!("CARGO_PKG_VERSION"));
+
+/// The maximum amount of metadata pairs of a request
+const MAX_METADATA_PAIRS: usize = 16;
+
+/// The maximum length of a metadata key, in characters
+const MAX_METADATA_KEY_CHARS: usize = 64;
+
+/// The maximum length of a metadata value, in characters
+const MAX_METADATA_VALUE_CHARS: usize = 512;
+
+/// The model used when none is given explicitly, see [`set_default_model`]
+static DEFAULT_MODEL: RwLock<Option<Model>> = RwLock::new(None);
+
+/// Set the process-wide model used by conversions that don't take a model, such
+/// as `ChatCompletionRequest::from(log)`
+pub fn set_default_model(model: impl Into<Model>) {
+    write_default(&DEFAULT_MODEL, model.into());
+}
+
+/// The process-wide default model, `gpt-3.5-turbo` unless changed with
+/// [`set_default_model`]
+pub fn default_model() -> Model {
+    read_default(&DEFAULT_MODEL)
+}
+
+/// Keep a model as the default of a slot
+fn write_default(slot: &RwLock<Option<Model>>, model: Model) {
+    let mut default = slot.write().unwrap_or_else(|e| e.into_inner());
+    *default = Some(model);
+}
+
+/// The default model of a slot, `gpt-3.5-turbo` if none was kept
+fn read_default(slot: &RwLock<Option<Model>>) -> Model {
+    let default = slot.read().unwrap_or_else(|e| e.into_inner());
+    default.clone().unwrap_or(Model::Gpt35Turbo)
+}
 
 /// Roles that can be used in a chat log
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-enum ChatRole {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChatRole {
     /// The system, used for the initial prompt and maybe other things
     #[serde(rename = "system")]
     System,
@@ -12,187 +74,2150 @@ enum ChatRole {
     /// The assistant, used for the assistant's response
     #[serde(rename = "assistant")]
     Assistant,
+    /// The developer, which replaces the system role on newer models and on some
+    /// compatible providers
+    #[serde(rename = "developer")]
+    Developer,
+    /// The result of a tool call requested by the assistant
+    #[serde(rename = "tool")]
+    Tool,
+}
+
+impl ChatRole {
+    /// The name of the role as used by the api
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatRole::System => "system",
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+            ChatRole::Developer => "developer",
+            ChatRole::Tool => "tool",
+        }
+    }
+
+    /// Whether the role steers the model, that is system or developer
+    pub fn is_steering(&self) -> bool {
+        matches!(self, ChatRole::System | ChatRole::Developer)
+    }
+}
+
+/// Deserialize a string that may be `null`, which is common for the content of
+/// messages that only carry tool calls
+fn null_as_empty<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Deserialize a timestamp sent either as a JSON number or, by some compatible
+/// providers, as a numeric string
+pub(crate) fn timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    /// The forms a timestamp is sent in
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        /// A JSON number
+        Number(u64),
+        /// A numeric string
+        String(String),
+    }
+    match Timestamp::deserialize(deserializer)? {
+        Timestamp::Number(seconds) => Ok(seconds),
+        Timestamp::String(seconds) => seconds.trim().parse().map_err(|_| {
+            serde::de::Error::custom(format!("invalid timestamp {:?}", seconds))
+        }),
+    }
+}
+
+/// Check that no system or developer message follows the first entry, other
+/// than copies of it, see [`ChatLog::check_system_placement`]
+pub(crate) fn check_system_placement(entries: &[ChatEntry]) -> Result<(), LogError> {
+    let misplaced =
+        entries.iter().enumerate().skip(1).find(|(_, entry)| {
+            entry.role.is_steering() && !is_system_copy(entries, entry)
+        });
+    match misplaced {
+        Some((index, _)) => Err(LogError::MisplacedSystem { index }),
+        None => Ok(()),
+    }
+}
+
+/// Whether an entry is a system or developer message repeating the leading
+/// one, as inserted by [`ChatLog::reinforce_system`]
+fn is_system_copy(entries: &[ChatEntry], entry: &ChatEntry) -> bool {
+    entries.first().is_some_and(|first| {
+        first.role.is_steering()
+            && entry.role.is_steering()
+            && entry.content == first.content
+    })
+}
+
+/// How system prompts usually start, which a first user message should not
+#[cfg(feature = "blocking")]
+const SYSTEM_PROMPT_OPENING: &str = "you are";
+
+/// Whether the first entry is a user message that reads like a system prompt,
+/// which usually means it was pushed with the wrong role
+#[cfg(feature = "blocking")]
+pub(crate) fn looks_like_misplaced_system(entries: &[ChatEntry]) -> bool {
+    let Some(first) = entries.first() else {
+        return false;
+    };
+    let opening = first
+        .content
+        .trim_start()
+        .get(..SYSTEM_PROMPT_OPENING.len());
+    first.role == ChatRole::User
+        && opening
+            .is_some_and(|opening| opening.eq_ignore_ascii_case(SYSTEM_PROMPT_OPENING))
+}
+
+/// Appended to the content of entries cut by [`ChatLog::truncate_message_bytes`]
+const TRUNCATION_MARKER: &str = "…[truncated]";
+
+/// The initial state of a 64-bit FNV-1a hash
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Feed bytes to a 64-bit FNV-1a hash, which unlike the standard hasher is the
+/// same across runs and platforms
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// A source cited by a reply, such as a web page found by a search tool
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UrlCitation {
+    /// The url of the source
+    pub url: String,
+    /// The title of the source
+    #[serde(default)]
+    pub title: String,
+    /// The index of the first character of the content backed by the source
+    pub start_index: usize,
+    /// The index just past the last character backed by the source
+    pub end_index: usize,
+}
+
+impl UrlCitation {
+    /// The part of the content backed by the source, if the range fits in it
+    pub fn cited_text<'c>(&self, content: &'c str) -> Option<&'c str> {
+        let byte = |index| {
+            let boundaries = content.char_indices().map(|(byte, _)| byte);
+            boundaries.chain([content.len()]).nth(index)
+        };
+        let (start, end) = (byte(self.start_index)?, byte(self.end_index)?);
+        content.get(start..end)
+    }
+}
+
+/// A note attached to a reply by the api, such as a citation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// The kind of the annotation, such as `url_citation`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The cited source, for `url_citation` annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_citation: Option<UrlCitation>,
 }
 
 /// A single entry in a chat log
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatEntry {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatEntry {
+    /// The role of the entry
+    pub role: ChatRole,
+    /// The text of the entry
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    /// The tools the assistant asked to call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The function the assistant asked to call, when using legacy functions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    /// The id of the tool call this entry is the result of, for tool entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Why the assistant declined to answer, sent instead of the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    /// The notes attached to a reply, such as the sources of a web search.
+    /// Kept when a log is saved, but never sent back to the api.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Vec<Annotation>>,
+    /// When the entry was added, in seconds since the Unix epoch. Kept when a
+    /// log is saved and loaded, but never sent to the api, which rejects unknown
+    /// message fields.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// The model that wrote the entry, for replies. Kept locally like
+    /// `created_at` and never sent to the api.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl ChatEntry {
+    /// Create a new entry with the given role and text
+    pub fn new(role: ChatRole, content: impl Into<String>) -> ChatEntry {
+        ChatEntry {
+            role,
+            content: content.into(),
+            tool_calls: None,
+            function_call: None,
+            tool_call_id: None,
+            refusal: None,
+            annotations: None,
+            created_at: None,
+            model: None,
+        }
+    }
+
+    /// Create a new entry stamped with the current time
+    fn now(role: ChatRole, content: impl Into<String>) -> ChatEntry {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        ChatEntry {
+            created_at: Some(now.map_or(0, |elapsed| elapsed.as_secs())),
+            ..ChatEntry::new(role, content)
+        }
+    }
+}
+
+/// An entry as sent to the api, without the fields only kept locally
+#[derive(Serialize)]
+struct ApiEntry<'a> {
     /// The role of the entry
-    role: ChatRole,
+    role: &'a ChatRole,
     /// The text of the entry
-    content: String,
+    content: &'a str,
+    /// The tools the assistant asked to call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<&'a Vec<ToolCall>>,
+    /// The function the assistant asked to call, when using legacy functions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<&'a FunctionCall>,
+    /// The id of the tool call this entry is the result of, for tool entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+    /// Why the assistant declined to answer, sent instead of the content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refusal: Option<&'a str>,
+}
+
+/// Serialize messages the way the api expects them, leaving out
+/// [`ChatEntry::created_at`] and [`ChatEntry::model`]
+pub(crate) fn api_messages<M, S>(messages: &M, serializer: S) -> Result<S::Ok, S::Error>
+where
+    M: AsRef<[ChatEntry]>,
+    S: Serializer,
+{
+    serializer.collect_seq(messages.as_ref().iter().map(|entry| ApiEntry {
+        role: &entry.role,
+        content: &entry.content,
+        tool_calls: entry.tool_calls.as_ref(),
+        function_call: entry.function_call.as_ref(),
+        tool_call_id: entry.tool_call_id.as_deref(),
+        refusal: entry.refusal.as_deref(),
+    }))
+}
+
+/// The tools of a request, with the options that only apply to them
+#[derive(Deserialize, Debug, Clone, Default)]
+struct RequestTools {
+    /// The tools the model may call
+    #[serde(default)]
+    tools: Option<Vec<Tool>>,
+    /// Whether the model may call several tools in one reply
+    #[serde(default)]
+    parallel_tool_calls: Option<bool>,
+}
+
+impl Serialize for RequestTools {
+    /// Serialize the tools, and the options only along tools since the api
+    /// rejects them otherwise, such as with legacy functions
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(tools) = &self.tools {
+            map.serialize_entry("tools", tools)?;
+            if let (false, Some(parallel)) =
+                (tools.is_empty(), self.parallel_tool_calls)
+            {
+                map.serialize_entry("parallel_tool_calls", &parallel)?;
+            }
+        }
+        map.end()
+    }
 }
 
 /// A chat completion request
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatCompletionRequest {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionRequest {
     /// The model used for the completion
-    model: String,
+    model: Model,
     /// The chat log
+    #[serde(serialize_with = "api_messages")]
     messages: ChatLog,
+    /// The tools the model may call and their options
+    #[serde(flatten)]
+    tools: RequestTools,
+    /// The functions the model may call, for the legacy functions api
+    #[serde(skip_serializing_if = "Option::is_none")]
+    functions: Option<Vec<FunctionDefinition>>,
+    /// Whether tools are sent using the legacy functions api
+    #[serde(skip)]
+    use_legacy_functions: bool,
+    /// Whether the response is streamed as server-sent events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    /// Options of the streamed response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    /// The service tier that should serve the request, such as `default` or
+    /// `scale`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_tier: Option<String>,
+    /// The sampling temperature, between 0 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    /// The maximum amount of tokens to generate, for older models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    /// The maximum amount of tokens to generate, including reasoning tokens, for
+    /// newer models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<usize>,
+    /// Whether the completion is stored for later retrieval and evals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store: Option<bool>,
+    /// Tags of a stored completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<HashMap<String, String>>,
+    /// The start of the reply, sent as the last message
+    #[serde(skip)]
+    prefill: Option<String>,
+    /// Whether the log probabilities of the reply tokens are returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    /// How many of the likeliest tokens are returned at each position
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
+    /// The format the reply must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+}
+
+/// The format of the replies of a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free text, the default
+    Text,
+    /// A JSON object, which the messages must ask for explicitly
+    JsonObject,
+}
+
+/// Options of a streamed response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamOptions {
+    /// Whether a last chunk with the usage of the whole completion is sent
+    pub include_usage: bool,
 }
 
 impl ChatCompletionRequest {
     /// Create a new chat completion request
-    fn new(model: String, messages: ChatLog) -> ChatCompletionRequest {
-        ChatCompletionRequest { model, messages }
+    pub fn new(model: impl Into<Model>, messages: ChatLog) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: model.into(),
+            messages,
+            tools: RequestTools::default(),
+            functions: None,
+            use_legacy_functions: false,
+            stream: None,
+            stream_options: None,
+            service_tier: None,
+            temperature: None,
+            max_tokens: None,
+            max_completion_tokens: None,
+            store: None,
+            metadata: None,
+            prefill: None,
+            logprobs: None,
+            top_logprobs: None,
+            response_format: None,
+        }
+    }
+
+    /// Limit the amount of generated tokens. The limit is sent as
+    /// `max_completion_tokens` to the models that expect it and as the legacy
+    /// `max_tokens` to the others, see [`Model::uses_max_completion_tokens`].
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> ChatCompletionRequest {
+        if self.model.uses_max_completion_tokens() {
+            self.max_completion_tokens = Some(max_tokens);
+        } else {
+            self.max_tokens = Some(max_tokens);
+        }
+        self
+    }
+
+    /// Sample with the given temperature, between 0 and 2, higher being more
+    /// random
+    pub fn with_temperature(mut self, temperature: f64) -> ChatCompletionRequest {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// The sampling temperature, if set
+    pub fn temperature(&self) -> Option<f64> {
+        self.temperature
+    }
+
+    /// Ask for the request to be served by the given service tier. The tier that
+    /// actually served it is reported in [`ChatCompletionResponse::service_tier`].
+    pub fn with_service_tier(
+        mut self,
+        tier: impl Into<String>,
+    ) -> ChatCompletionRequest {
+        self.service_tier = Some(tier.into());
+        self
+    }
+
+    /// Seed the start of the reply, for example with `{` to get JSON, by sending
+    /// it as a last partial assistant message.
+    ///
+    /// Providers that support continuation, such as most local OpenAI compatible
+    /// servers, continue the partial message. The OpenAI api instead takes it as
+    /// an earlier turn, which still steers the reply but does not force it. The
+    /// clients join the prefill with the reply either way with
+    /// [`crate::text::join_prefill`], so replies always start with it. Setting
+    /// a prefill again replaces the previous one.
+    pub fn with_prefill(mut self, prefill: impl Into<String>) -> ChatCompletionRequest {
+        let prefill = prefill.into();
+        if self.prefill.is_some() {
+            self.messages.entries_mut().pop();
+        }
+        self.messages.push_assistant(prefill.clone());
+        self.prefill = Some(prefill);
+        self
+    }
+
+    /// The prefill of the reply, if any
+    pub fn prefill(&self) -> Option<&str> {
+        self.prefill.as_deref()
+    }
+
+    /// Return the log probability of every reply token, with the `top` likeliest
+    /// alternatives at each position, at most 20
+    pub fn with_logprobs(mut self, top: u8) -> ChatCompletionRequest {
+        self.logprobs = Some(true);
+        self.top_logprobs = (top > 0).then_some(top.min(20));
+        self
+    }
+
+    /// Ask for replies that are a single JSON object. The api rejects the
+    /// request unless the messages mention JSON, and a reply cut at the token
+    /// limit is still invalid JSON, see [`ChatCompletionResponse::json_reply`].
+    pub fn with_json_mode(mut self) -> ChatCompletionRequest {
+        self.response_format = Some(ResponseFormat::JsonObject);
+        self
+    }
+
+    /// Store the completion so it can be retrieved later and used for evals in
+    /// the dashboard
+    pub fn with_store(mut self, store: bool) -> ChatCompletionRequest {
+        self.store = Some(store);
+        self
+    }
+
+    /// Tag a stored completion with metadata. The api accepts at most 16 pairs,
+    /// with keys of at most 64 characters and values of at most 512, so larger
+    /// metadata is rejected here instead of by the server.
+    pub fn with_metadata(
+        mut self,
+        metadata: HashMap<String, String>,
+    ) -> Result<ChatCompletionRequest, MetadataError> {
+        if metadata.len() > MAX_METADATA_PAIRS {
+            return Err(MetadataError::TooManyPairs {
+                count: metadata.len(),
+            });
+        }
+        for (key, value) in &metadata {
+            if key.chars().count() > MAX_METADATA_KEY_CHARS {
+                return Err(MetadataError::KeyTooLong { key: key.clone() });
+            }
+            if value.chars().count() > MAX_METADATA_VALUE_CHARS {
+                return Err(MetadataError::ValueTooLong { key: key.clone() });
+            }
+        }
+        self.metadata = Some(metadata);
+        Ok(self)
+    }
+
+    /// Replace the content of every message with the result of `transform`,
+    /// given its role and content. The prefill is left as is, since the reply
+    /// is joined with it.
+    pub fn map_contents(
+        mut self,
+        transform: impl Fn(&ChatRole, &str) -> String,
+    ) -> ChatCompletionRequest {
+        let prefilled = self.prefill.is_some() as usize;
+        let entries = self.messages.entries_mut();
+        let end = entries.len() - prefilled;
+        for entry in &mut entries[..end] {
+            entry.content = transform(&entry.role, &entry.content);
+        }
+        self
+    }
+
+    /// Send system messages with the `developer` role, as expected by newer
+    /// models and some compatible providers
+    pub fn with_developer_role(mut self) -> ChatCompletionRequest {
+        for entry in self.messages.entries_mut() {
+            if entry.role == ChatRole::System {
+                entry.role = ChatRole::Developer;
+            }
+        }
+        self
+    }
+
+    /// The model of the request
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    /// The chat log of the request
+    pub fn messages(&self) -> &ChatLog {
+        &self.messages
+    }
+
+    /// A hash of the body sent to the api, the same across runs and platforms,
+    /// to recognize identical requests. The timestamps of the entries are not
+    /// part of the body, so they do not change it.
+    pub fn content_hash(&self) -> u64 {
+        // Going through a value sorts the keys, including those of the metadata
+        let body = serde_json::to_value(self).expect("requests always serialize");
+        fnv1a(FNV_OFFSET_BASIS, body.to_string().as_bytes())
+    }
+
+    /// The tools the model may call, empty when there are none or they are sent
+    /// as legacy functions
+    pub fn tools(&self) -> &[Tool] {
+        self.tools.tools.as_deref().unwrap_or_default()
+    }
+
+    /// The functions the model may call with the legacy functions api, empty
+    /// when there are none or they are sent as tools
+    pub fn functions(&self) -> &[FunctionDefinition] {
+        self.functions.as_deref().unwrap_or_default()
+    }
+
+    /// Ask for the response to be streamed, including a final usage chunk
+    pub fn streaming(mut self) -> ChatCompletionRequest {
+        self.stream = Some(true);
+        self.stream_options = Some(StreamOptions {
+            include_usage: true,
+        });
+        self
+    }
+
+    /// Set the tools the model may call
+    pub fn with_tools(mut self, tools: Vec<Tool>) -> ChatCompletionRequest {
+        if self.use_legacy_functions {
+            self.functions =
+                Some(tools.into_iter().map(|tool| tool.function).collect());
+        } else {
+            self.tools.tools = Some(tools);
+        }
+        self
+    }
+
+    /// Allow or forbid several tool calls in one reply. When disabled the reply
+    /// contains at most one tool call. This is only sent along tools set with
+    /// [`ChatCompletionRequest::with_tools`], since the api rejects it otherwise,
+    /// and the legacy functions api always calls a single function.
+    pub fn with_parallel_tool_calls(mut self, enabled: bool) -> ChatCompletionRequest {
+        self.tools.parallel_tool_calls = Some(enabled);
+        self
+    }
+
+    /// Send the tools using the deprecated `functions` field instead of `tools`.
+    /// The model then answers with a `function_call` on the message instead of
+    /// `tool_calls`. This is only needed for old snapshots that predate tools,
+    /// such as `gpt-3.5-turbo-0613` and `gpt-4-0613`, or for compatible servers
+    /// that only implement the old schema.
+    pub fn use_legacy_functions(mut self, enabled: bool) -> ChatCompletionRequest {
+        self.use_legacy_functions = enabled;
+        if enabled {
+            if let Some(tools) = self.tools.tools.take() {
+                return self.with_tools(tools);
+            }
+        } else if let Some(functions) = self.functions.take() {
+            let tools = functions
+                .into_iter()
+                .map(|function| Tool {
+                    kind: ToolType::Function,
+                    function,
+                })
+                .collect();
+            return self.with_tools(tools);
+        }
+        self
     }
 }
 
 impl From<ChatLog> for ChatCompletionRequest {
-    /// Create a new chat completion request from a chat log
+    /// Create a new chat completion request from a chat log using the
+    /// process-wide default model. Prefer [`ChatCompletionRequest::new`] to make
+    /// the model explicit; the default can be changed with [`set_default_model`].
     fn from(log: ChatLog) -> ChatCompletionRequest {
-        ChatCompletionRequest::new("gpt-3.5-turbo".to_string(), log)
+        ChatCompletionRequest::new(default_model(), log)
     }
 }
 
 /// A chat log, which is a list of chat entries
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatLog(Vec<ChatEntry>);
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatLog(Vec<ChatEntry>);
+
+impl ChatLog {
+    /// Create an empty chat log
+    pub fn new() -> ChatLog {
+        ChatLog(Vec::new())
+    }
+
+    /// The entries of the log, in order
+    pub fn entries(&self) -> &[ChatEntry] {
+        &self.0
+    }
+
+    /// The entries of the log, mutably
+    pub fn entries_mut(&mut self) -> &mut Vec<ChatEntry> {
+        &mut self.0
+    }
+
+    /// The number of entries in the log
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the log has no entries
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Append an entry to the log
+    pub fn push(&mut self, entry: ChatEntry) {
+        self.0.push(entry);
+    }
+
+    /// Append a system message to the log, stamped with the current time
+    pub fn push_system(&mut self, content: impl Into<String>) {
+        self.push(ChatEntry::now(ChatRole::System, content));
+    }
+
+    /// Append a user message to the log, stamped with the current time
+    pub fn push_user(&mut self, content: impl Into<String>) {
+        self.push(ChatEntry::now(ChatRole::User, content));
+    }
+
+    /// Append an assistant message to the log
+    pub fn push_assistant(&mut self, content: impl Into<String>) {
+        self.push(ChatEntry::now(ChatRole::Assistant, content));
+    }
+
+    /// Append an assistant message written by the given model, stamped with
+    /// the current time
+    pub fn push_reply(&mut self, content: impl Into<String>, model: impl Into<String>) {
+        self.push(ChatEntry {
+            model: Some(model.into()),
+            ..ChatEntry::now(ChatRole::Assistant, content)
+        });
+    }
+
+    /// Append the result of a tool call, answering the call with the given id,
+    /// stamped with the current time
+    pub fn push_tool_result(
+        &mut self,
+        tool_call_id: impl Into<String>,
+        content: impl Into<String>,
+    ) {
+        self.push(ChatEntry {
+            tool_call_id: Some(tool_call_id.into()),
+            ..ChatEntry::now(ChatRole::Tool, content)
+        });
+    }
+
+    /// The distinct models that wrote the entries of the log, in the order
+    /// they first appear. Entries without a recorded model are skipped.
+    pub fn models_used(&self) -> Vec<String> {
+        let mut models: Vec<String> = Vec::new();
+        for model in self.0.iter().filter_map(|entry| entry.model.as_ref()) {
+            if !models.contains(model) {
+                models.push(model.clone());
+            }
+        }
+        models
+    }
+
+    /// The content of the leading system or developer message, if there is one
+    pub fn system(&self) -> Option<&str> {
+        match self.0.first() {
+            Some(entry) if entry.role.is_steering() => Some(&entry.content),
+            _ => None,
+        }
+    }
+
+    /// Replace the content of the leading system or developer message, inserting
+    /// a system message at the start of the log if there is none
+    pub fn set_system(&mut self, content: impl Into<String>) {
+        match self.0.first_mut() {
+            Some(entry) if entry.role.is_steering() => entry.content = content.into(),
+            _ => self.0.insert(0, ChatEntry::new(ChatRole::System, content)),
+        }
+    }
+
+    /// Repeat the leading system message after every `every_n_turns` replies,
+    /// so models keep following it in long conversations, and return how many
+    /// copies were inserted. Copies already in the log count as reminders, so
+    /// calling this again only adds the ones the new turns need. Does nothing
+    /// without a leading system message or if `every_n_turns` is 0.
+    ///
+    /// Every copy is sent with every later request, so it costs the tokens of
+    /// the system prompt each time. [`ChatLog::check_system_placement`] and
+    /// [`ChatLog::is_well_formed`] accept the copies.
+    pub fn reinforce_system(&mut self, every_n_turns: usize) -> usize {
+        let Some(system) = self.system().map(str::to_string) else {
+            return 0;
+        };
+        if every_n_turns == 0 {
+            return 0;
+        }
+        let is_reminder =
+            |entry: &ChatEntry| entry.role.is_steering() && entry.content == system;
+        let mut inserted = 0;
+        let mut replies = 0;
+        let mut index = 1;
+        while index < self.0.len() {
+            if is_reminder(&self.0[index]) {
+                replies = 0;
+            } else if self.0[index].role == ChatRole::Assistant {
+                replies += 1;
+                let next = self.0.get(index + 1);
+                if replies == every_n_turns && !next.is_some_and(is_reminder) {
+                    let reminder = ChatEntry::new(ChatRole::System, system.clone());
+                    self.0.insert(index + 1, reminder);
+                    inserted += 1;
+                }
+            }
+            index += 1;
+        }
+        inserted
+    }
+
+    /// Cut every entry whose content is longer than `max_bytes` bytes, for
+    /// providers that cap the size of single messages, and return the indices
+    /// of the cut entries. The content is cut on a char boundary and ends with
+    /// `…[truncated]`, the whole staying within `max_bytes`; if the limit is
+    /// too small for the marker, the content is only cut.
+    pub fn truncate_message_bytes(&mut self, max_bytes: usize) -> Vec<usize> {
+        let mut truncated = Vec::new();
+        for (index, entry) in self.0.iter_mut().enumerate() {
+            if entry.content.len() <= max_bytes {
+                continue;
+            }
+            let marker = if TRUNCATION_MARKER.len() <= max_bytes {
+                TRUNCATION_MARKER
+            } else {
+                ""
+            };
+            let mut end = max_bytes - marker.len();
+            while !entry.content.is_char_boundary(end) {
+                end -= 1;
+            }
+            entry.content.truncate(end);
+            entry.content.push_str(marker);
+            truncated.push(index);
+        }
+        truncated
+    }
+
+    /// A hash of the roles and contents of the entries, in order, that is the
+    /// same across runs and platforms. Computed with 64-bit FNV-1a over every
+    /// role name and content prefixed with its length in bytes, so entries
+    /// cannot be confused by moving text between them.
+    pub fn content_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for entry in &self.0 {
+            for field in [entry.role.name(), &entry.content] {
+                hash = fnv1a(hash, &(field.len() as u64).to_le_bytes());
+                hash = fnv1a(hash, field.as_bytes());
+            }
+        }
+        hash
+    }
+
+    /// Check only that the log has at most one system or developer message, at
+    /// the start, which most models otherwise ignore or follow oddly. Copies of
+    /// it, such as those of [`ChatLog::reinforce_system`], are allowed. Unlike
+    /// [`ChatLog::is_well_formed`] the order of the other roles is not checked.
+    /// The error holds the index of the first misplaced message. The blocking
+    /// client also warns through the `log` crate when it sends such a log.
+    pub fn check_system_placement(&self) -> Result<(), LogError> {
+        check_system_placement(&self.0)
+    }
+
+    /// Panic unless the first entry is a system or developer message, to catch
+    /// system prompts pushed with [`ChatLog::push_user`] in tests
+    pub fn assert_first_is_system(&self) {
+        match self.0.first() {
+            Some(entry) if entry.role.is_steering() => {}
+            Some(entry) => panic!(
+                "expected the log to start with a system message, found a {} message",
+                entry.role.name()
+            ),
+            None => {
+                panic!("expected the log to start with a system message, it is empty")
+            }
+        }
+    }
+
+    /// Check that the log has at most one system (or developer) message, at the start, apart
+    /// from copies of it, and that the remaining entries strictly alternate user, assistant, user, ...
+    /// starting with a user message, so every assistant message answers a user
+    /// message. Tool results may follow any entry and are answered by the
+    /// assistant. The log may end with any role. On failure the error holds the
+    /// index of the first offending entry.
+    pub fn is_well_formed(&self) -> Result<(), LogError> {
+        let mut expected = ChatRole::User;
+        for (index, entry) in self.0.iter().enumerate() {
+            match entry.role {
+                ChatRole::System | ChatRole::Developer
+                    if index == 0 || is_system_copy(&self.0, entry) =>
+                {
+                    continue
+                }
+                ChatRole::System | ChatRole::Developer => {
+                    return Err(LogError::MisplacedSystem { index })
+                }
+                ChatRole::Tool => {
+                    expected = ChatRole::Assistant;
+                    continue;
+                }
+                ref found if *found != expected => {
+                    return Err(LogError::UnexpectedRole {
+                        index,
+                        expected,
+                        found: found.clone(),
+                    })
+                }
+                _ => {}
+            }
+            expected = match expected {
+                ChatRole::User => ChatRole::Assistant,
+                _ => ChatRole::User,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl AsRef<[ChatEntry]> for ChatLog {
+    fn as_ref(&self) -> &[ChatEntry] {
+        &self.0
+    }
+}
+
+impl From<Vec<ChatEntry>> for ChatLog {
+    fn from(entries: Vec<ChatEntry>) -> ChatLog {
+        ChatLog(entries)
+    }
+}
+
+impl FromIterator<ChatEntry> for ChatLog {
+    fn from_iter<I: IntoIterator<Item = ChatEntry>>(entries: I) -> ChatLog {
+        ChatLog(entries.into_iter().collect())
+    }
+}
+
+impl<S: Into<String>> FromIterator<(ChatRole, S)> for ChatLog {
+    /// Build a log from `(role, content)` pairs
+    fn from_iter<I: IntoIterator<Item = (ChatRole, S)>>(turns: I) -> ChatLog {
+        turns
+            .into_iter()
+            .map(|(role, content)| ChatEntry::new(role, content))
+            .collect()
+    }
+}
+
+impl IntoIterator for ChatLog {
+    type Item = ChatEntry;
+    type IntoIter = std::vec::IntoIter<ChatEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChatLog {
+    type Item = &'a ChatEntry;
+    type IntoIter = std::slice::Iter<'a, ChatEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
 
 /// A reason for which the completion stopped
-#[derive(Serialize, Deserialize, Debug)]
-enum FinishReason {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FinishReason {
     /// A stop token was reached
     #[serde(rename = "stop")]
     Stop,
     /// The maximum number of tokens was reached
     #[serde(rename = "length")]
     Length,
+    /// The model asked to call tools
+    #[serde(rename = "tool_calls")]
+    ToolCalls,
+    /// The model asked to call a function, when using legacy functions
+    #[serde(rename = "function_call")]
+    FunctionCall,
+    /// The content was omitted by the content filter
+    #[serde(rename = "content_filter")]
+    ContentFilter,
+}
+
+impl FinishReason {
+    /// The name of the reason as used by the api
+    pub fn name(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::ToolCalls => "tool_calls",
+            FinishReason::FunctionCall => "function_call",
+            FinishReason::ContentFilter => "content_filter",
+        }
+    }
 }
 
 /// Chat completion choice
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatCompletionChoice {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionChoice {
     /// The text of the choice
-    index: usize,
+    pub index: usize,
     /// The message of the choice
-    message: ChatEntry,
+    pub message: ChatEntry,
     /// The finish reason of the choice
-    finish_reason: FinishReason,
+    pub finish_reason: FinishReason,
+    /// The log probabilities of the tokens of the choice, when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
+    /// The verdicts of the content filters on the choice, only sent by Azure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_filter_results: Option<ContentFilterResults>,
+}
+
+impl ChatCompletionChoice {
+    /// The probability of every token of the content, between 0 and 1, or `None`
+    /// if log probabilities were not requested
+    pub fn per_token_confidence(&self) -> Option<Vec<f64>> {
+        let tokens = self.logprobs.as_ref()?.content.as_ref()?;
+        Some(tokens.iter().map(|token| token.logprob.exp()).collect())
+    }
+
+    /// The average probability of the tokens of the content, between 0 and 1, to
+    /// flag uncertain replies. Returns `None` if log probabilities were not
+    /// requested or the content is empty.
+    pub fn confidence(&self) -> Option<f64> {
+        let probabilities = self.per_token_confidence()?;
+        if probabilities.is_empty() {
+            return None;
+        }
+        Some(probabilities.iter().sum::<f64>() / probabilities.len() as f64)
+    }
+
+    /// The perplexity of the content, `exp(-mean(logprob))` over its tokens: 1
+    /// when the model was certain of every token, higher the less natural it
+    /// found its reply. Returns `None` if log probabilities were not requested or
+    /// the content is empty.
+    pub fn perplexity(&self) -> Option<f64> {
+        let tokens = self.logprobs.as_ref()?.content.as_ref()?;
+        if tokens.is_empty() {
+            return None;
+        }
+        let sum: f64 = tokens.iter().map(|token| token.logprob).sum();
+        Some((-sum / tokens.len() as f64).exp())
+    }
+}
+
+/// The log probabilities of a choice
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChoiceLogprobs {
+    /// The log probabilities of the content tokens
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+/// The log probability of a token, with the likeliest alternatives
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    /// The token
+    pub token: String,
+    /// The natural log of the probability of the token
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token, if it has a text representation
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    /// The likeliest tokens at this position, as many as requested
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// One of the likeliest tokens at a position
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    /// The token
+    pub token: String,
+    /// The natural log of the probability of the token
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token, if it has a text representation
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
 }
 
 /// A completion usage information
-#[derive(Serialize, Deserialize, Debug)]
-struct CompletionUsage {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionUsage {
     /// The tokens in the prompt
-    prompt_tokens: usize,
+    pub prompt_tokens: u32,
     /// The tokens in the completion
-    completion_tokens: usize,
+    pub completion_tokens: u32,
     /// The tokens in the total
-    total_tokens: usize,
+    pub total_tokens: u32,
+}
+
+impl CompletionUsage {
+    /// The usage of two calls together
+    pub fn combine(&self, other: &CompletionUsage) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            total_tokens: self.total_tokens + other.total_tokens,
+        }
+    }
+
+    /// The cost in USD of this usage for the given model, or `None` if the model
+    /// has no known pricing. When several choices were requested the usage already
+    /// covers all of them, so this is the cost of the whole call.
+    pub fn cost_usd(&self, model: &Model) -> Option<f64> {
+        let pricing = model.pricing()?;
+        Some(pricing.cost(self.prompt_tokens, self.completion_tokens))
+    }
+}
+
+/// A reply decoded from JSON, see [`ChatCompletionResponse::json_reply`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonReply<T> {
+    /// The decoded value
+    pub value: T,
+    /// Whether the reply had to be repaired first, in which case the value may
+    /// be missing data the model did not get to write
+    pub repaired: bool,
 }
 
 /// A chat completion response
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatCompletionResponse {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionResponse {
     /// The completion id
-    id: String,
+    pub id: String,
     /// The completion object
-    object: String,
-    /// The completion creation time
-    created: usize,
+    pub object: String,
+    /// The completion creation time, in seconds since the Unix epoch
+    #[serde(deserialize_with = "timestamp")]
+    pub created: u64,
+    /// The model that served the completion
+    pub model: String,
+    /// The fingerprint of the backend configuration that served the completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    /// The service tier that served the completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// The verdicts of the content filters on the prompt, only sent by Azure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_filter_results: Option<Vec<PromptFilterResult>>,
     /// The completion choices
-    choices: Vec<ChatCompletionChoice>,
-    /// The completion usage
-    usage: CompletionUsage,
+    pub choices: Vec<ChatCompletionChoice>,
+    /// The completion usage, missing from some streamed responses and from some
+    /// compatible providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
 }
 
-/// OpenAI api clients
-pub struct OpenAI {
-    /// HTTP client
-    client: reqwest::blocking::Client,
-    /// OpenAI api key
-    api_key: String,
-}
+impl ChatCompletionResponse {
+    /// The cost in USD of the completion, or `None` if the model has no known
+    /// pricing or the response has no usage.
+    ///
+    /// The usage reported by the api is aggregated over all choices, so this is
+    /// the real cost of the call and is never multiplied by the number of choices.
+    pub fn cost_usd(&self) -> Option<f64> {
+        let usage = self.usage.as_ref()?;
+        usage.cost_usd(&Model::from(self.model.as_str()))
+    }
+
+    /// A rough attribution of the completion cost to each choice, obtained by
+    /// dividing the completion cost evenly between the choices. The prompt cost is
+    /// shared by all choices and is not included. Returns `None` if the model has
+    /// no known pricing, the response has no usage or there are no choices.
+    pub fn cost_per_choice(&self) -> Option<f64> {
+        if self.choices.is_empty() {
+            return None;
+        }
+        let usage = self.usage.as_ref()?;
+        let pricing = Model::from(self.model.as_str()).pricing()?;
+        let completion_cost = pricing.completion_cost(usage.completion_tokens);
+        Some(completion_cost / self.choices.len() as f64)
+    }
+
+    /// Join the prefill of the request with the content of every choice, see
+    /// [`crate::text::join_prefill`]
+    pub fn join_prefill(&mut self, prefill: &str) {
+        for choice in &mut self.choices {
+            choice.message.content = join_prefill(prefill, &choice.message.content);
+        }
+    }
 
-impl OpenAI {
-    /// Create a new OpenAI client
-    pub fn new(api_key: String) -> OpenAI {
-        OpenAI {
-            client: reqwest::blocking::Client::new(),
-            api_key,
+    /// Combine the choices of two responses to the same prompt, such as when
+    /// `n` candidates are split across several requests. The choices of `other`
+    /// follow those of `self` and are re-indexed, the usages are summed, and the
+    /// id and model of `self` are kept. Fails with [`OpenAIError::ModelMismatch`]
+    /// if the responses come from different models. The usage is `None` unless
+    /// both responses report one.
+    pub fn merge(
+        mut self,
+        other: ChatCompletionResponse,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        if self.model != other.model {
+            return Err(OpenAIError::ModelMismatch {
+                expected: self.model,
+                got: other.model,
+            });
         }
+        let offset = self.choices.len();
+        self.choices
+            .extend(other.choices.into_iter().map(|mut choice| {
+                choice.index += offset;
+                choice
+            }));
+        self.usage = match (&self.usage, &other.usage) {
+            (Some(usage), Some(other)) => Some(usage.combine(other)),
+            _ => None,
+        };
+        Ok(self)
+    }
+
+    /// The message of the first choice, if there is one. A refused message is
+    /// returned as is, with an empty content and its [`ChatEntry::refusal`] set;
+    /// use [`ChatCompletionResponse::reply`] to tell refusals from empty replies.
+    pub fn first_message(&self) -> Option<&ChatEntry> {
+        self.choices.first().map(|choice| &choice.message)
     }
 
-    /// Complete a chat
-    fn complete_chat(
+    /// The refusal of the first choice, if the model declined to answer
+    pub fn refusal(&self) -> Option<&str> {
+        self.first_message()?.refusal.as_deref()
+    }
+
+    /// The sources cited by the first choice, with the range of the reply
+    /// each one backs, in the order of the annotations
+    pub fn citations(&self) -> Vec<&UrlCitation> {
+        let annotations = self.first_message().and_then(|m| m.annotations.as_ref());
+        let annotations = annotations.into_iter().flatten();
+        annotations
+            .filter_map(|a| a.url_citation.as_ref())
+            .collect()
+    }
+
+    /// The content of the first choice, failing with [`OpenAIError::NoChoices`]
+    /// if there is none and with [`OpenAIError::Refusal`] if the model declined
+    /// to answer
+    pub fn reply(&self) -> Result<&str, OpenAIError> {
+        let message = self.first_message().ok_or(OpenAIError::NoChoices)?;
+        match &message.refusal {
+            Some(refusal) => Err(OpenAIError::Refusal(refusal.clone())),
+            None => Ok(&message.content),
+        }
+    }
+
+    /// Decode the reply as JSON. With `repair`, a reply that does not decode is
+    /// closed with [`repair_json`] and decoded again, which rescues replies cut
+    /// at the token limit but can yield a value missing its end.
+    pub fn json_reply<T: DeserializeOwned>(
         &self,
-        chat: ChatLog,
-    ) -> Result<ChatCompletionResponse, reqwest::Error> {
-        let request = ChatCompletionRequest::from(chat);
+        repair: bool,
+    ) -> Result<JsonReply<T>, OpenAIError> {
+        let reply = self.reply()?;
+        match serde_json::from_str(reply) {
+            Ok(value) => Ok(JsonReply {
+                value,
+                repaired: false,
+            }),
+            Err(e) => {
+                let repaired = repair.then(|| repair_json(reply)).flatten();
+                let value = repaired
+                    .and_then(|repaired| serde_json::from_str(&repaired).ok())
+                    .ok_or_else(|| OpenAIError::decode(e, reply))?;
+                Ok(JsonReply {
+                    value,
+                    repaired: true,
+                })
+            }
+        }
+    }
+
+    /// Parse a response body, keeping the raw body in the error on failure
+    pub fn parse(body: &str) -> Result<ChatCompletionResponse, OpenAIError> {
+        serde_json::from_str(body).map_err(|e| OpenAIError::decode(e, body))
+    }
+
+    /// Parse a response read from a reader, such as a captured response on disk
+    pub fn from_reader(
+        mut reader: impl Read,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        ChatCompletionResponse::parse(&body)
+    }
+
+    /// Write the response as JSON, in the same shape it is parsed from
+    pub fn to_writer(&self, writer: impl Write) -> Result<(), OpenAIError> {
+        serde_json::to_writer(writer, self).map_err(|e| OpenAIError::Io(e.into()))
+    }
+
+    /// Parse the body of a completion, rejecting responses without choices so
+    /// callers can rely on the first choice being there
+    pub fn parse_completion(body: &str) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = ChatCompletionResponse::parse(body)?;
+        if response.choices.is_empty() {
+            return Err(OpenAIError::NoChoices);
+        }
+        Ok(response)
+    }
+
+    /// The completion creation time as a UTC timestamp
+    #[cfg(feature = "chrono")]
+    pub fn created_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp(i64::try_from(self.created).ok()?, 0)
+    }
+
+    /// Reduce the response to a compact owned summary of its first choice
+    pub fn summarize(self) -> CompletionSummary {
+        let first = self.choices.into_iter().next();
+        let (content, refusal, finish_reason) = match first {
+            Some(choice) => (
+                choice.message.content,
+                choice.message.refusal,
+                Some(choice.finish_reason),
+            ),
+            None => (String::new(), None, None),
+        };
+        CompletionSummary {
+            id: self.id,
+            content,
+            refusal,
+            finish_reason,
+            usage: self.usage,
+            model: self.model,
+        }
+    }
+}
+
+impl FromStr for ChatCompletionResponse {
+    type Err = OpenAIError;
+
+    fn from_str(body: &str) -> Result<ChatCompletionResponse, OpenAIError> {
+        ChatCompletionResponse::parse(body)
+    }
+}
 
-        // Make post request to OpenAI
-        self.client
-            .post("https://api.openai.com/v1/chat/completions")
-            .bearer_auth(self.api_key.clone())
-            .json(&request)
-            .send()?
-            .json::<ChatCompletionResponse>()
+impl fmt::Display for ChatCompletionResponse {
+    /// Format the response as JSON, so `to_string` round trips with `from_str`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
     }
 }
 
+/// A compact summary of a chat completion response, keeping only the first choice
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionSummary {
+    /// The completion id
+    pub id: String,
+    /// The content of the first choice, empty if there were no choices
+    pub content: String,
+    /// The refusal of the first choice, if the model declined to answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    /// The finish reason of the first choice, if there was one
+    pub finish_reason: Option<FinishReason>,
+    /// The completion usage, if the response reported it
+    pub usage: Option<CompletionUsage>,
+    /// The model that served the completion
+    pub model: String,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::*;
 
     /// Test serialization of a chat log
     #[test]
     fn test_chat_log() {
         let log = ChatLog(vec![
-            ChatEntry {
-                role: ChatRole::System,
-                content: "Hello, world!".to_string(),
-            },
-            ChatEntry {
-                role: ChatRole::User,
-                content: "Hello, world!".to_string(),
-            },
-            ChatEntry {
-                role: ChatRole::Assistant,
-                content: "Hello, world!".to_string(),
-            },
+            ChatEntry::new(ChatRole::System, "Hello, world!"),
+            ChatEntry::new(ChatRole::User, "Hello, world!"),
+            ChatEntry::new(ChatRole::Assistant, "Hello, world!"),
         ]);
         let serialized = serde_json::to_string(&log).unwrap();
         insta::assert_yaml_snapshot!(serialized);
     }
 
-    use std::env;
+    /// Test that entry timestamps are saved locally but not sent to the api
+    #[test]
+    fn test_created_at() {
+        let mut log = ChatLog::new();
+        log.push_user("Hello");
+        let created_at = log.entries()[0].created_at.unwrap();
+        assert!(created_at > 1_700_000_000);
+
+        let saved = serde_json::to_string(&log).unwrap();
+        let loaded: ChatLog = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.entries()[0].created_at, Some(created_at));
 
-    /// Test the chat completion request
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            value["messages"],
+            serde_json::json!([{"role": "user", "content": "Hello"}])
+        );
+    }
+
+    /// Test listing the models of a log, which are never sent to the api
     #[test]
-    fn test_chat_completion_request() {
-        // Read the key from environment variable
-        let key = env::var("OPENAI_KEY").expect("OPENAI_KEY must be set");
+    fn test_models_used() {
+        let mut log = ChatLog::new();
+        log.push_user("Hi");
+        log.push_reply("Hello", "gpt-4o-2024-08-06");
+        log.push_user("And?");
+        log.push_reply("Well", "gpt-4o-mini");
+        log.push_assistant("Also");
+        log.push_reply("Anyway", "gpt-4o-2024-08-06");
+        assert_eq!(log.models_used(), ["gpt-4o-2024-08-06", "gpt-4o-mini"]);
 
-        // Create a new OpenAI client
-        let openai = OpenAI::new(key);
+        let loaded: ChatLog =
+            serde_json::from_str(&serde_json::to_string(&log).unwrap()).unwrap();
+        assert_eq!(loaded.models_used(), log.models_used());
 
-        // Create a new chat log
-        let log = ChatLog(vec![
-            ChatEntry {
-                role: ChatRole::System,
-                content: "You are an assistant that always says \"A\"".to_string(),
-            },
-            ChatEntry {
-                role: ChatRole::User,
-                content: "Please say \"A\". Do not say anything else, only \"A\"."
-                    .to_string(),
-            },
-        ]);
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log);
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value["messages"][1].get("model").is_none());
+    }
+
+    /// Test reading and replacing the system prompt
+    #[test]
+    fn test_set_system() {
+        let mut log = ChatLog::new();
+        log.push_user("Hi");
+        assert_eq!(log.system(), None);
+
+        log.set_system("You are a pirate.");
+        assert_eq!(log.system(), Some("You are a pirate."));
+        assert_eq!(log.len(), 2);
+
+        log.set_system("You are a robot.");
+        assert_eq!(log.system(), Some("You are a robot."));
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.entries()[1].content, "Hi");
+    }
+
+    /// Test that the content hash is stable and depends on every entry
+    #[test]
+    fn test_content_hash() {
+        let log =
+            |turns: &[(ChatRole, &str)]| turns.iter().cloned().collect::<ChatLog>();
+        let hello = log(&[(ChatRole::User, "Hello"), (ChatRole::Assistant, "Hi")]);
+        assert_eq!(ChatLog::new().content_hash(), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hello.content_hash(), hello.clone().content_hash());
+        assert_eq!(hello.content_hash(), 0xa816_5151_687d_fe05);
+
+        let swapped = log(&[(ChatRole::Assistant, "Hi"), (ChatRole::User, "Hello")]);
+        assert_ne!(hello.content_hash(), swapped.content_hash());
+        let moved = log(&[(ChatRole::User, "Hell"), (ChatRole::Assistant, "oHi")]);
+        assert_ne!(hello.content_hash(), moved.content_hash());
+        let role = log(&[(ChatRole::System, "Hello"), (ChatRole::Assistant, "Hi")]);
+        assert_ne!(hello.content_hash(), role.content_hash());
+    }
+
+    /// Test finding system messages after the start of the log
+    #[test]
+    fn test_check_system_placement() {
+        let log = |roles: &[ChatRole]| -> ChatLog {
+            roles
+                .iter()
+                .enumerate()
+                .map(|(index, role)| (role.clone(), index.to_string()))
+                .collect()
+        };
+        use ChatRole::*;
+        assert!(log(&[]).check_system_placement().is_ok());
+        assert!(log(&[System, User, User]).check_system_placement().is_ok());
+        assert!(matches!(
+            log(&[User, System]).check_system_placement(),
+            Err(LogError::MisplacedSystem { index: 1 })
+        ));
+        assert!(matches!(
+            log(&[System, User, Developer]).check_system_placement(),
+            Err(LogError::MisplacedSystem { index: 2 })
+        ));
+    }
 
-        // Complete the chat
-        let response = openai.complete_chat(log).expect("Failed to complete chat");
+    /// Test validating the structure of chat logs
+    #[test]
+    fn test_is_well_formed() {
+        let log = |roles: &[ChatRole]| {
+            ChatLog(
+                roles
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| ChatEntry::new(r.clone(), i.to_string()))
+                    .collect(),
+            )
+        };
+        use ChatRole::*;
+
+        assert_eq!(log(&[]).is_well_formed(), Ok(()));
+        assert_eq!(
+            log(&[System, User, Assistant, User]).is_well_formed(),
+            Ok(())
+        );
+        assert_eq!(log(&[User, Assistant]).is_well_formed(), Ok(()));
+        assert_eq!(
+            log(&[System, User, System]).is_well_formed(),
+            Err(LogError::MisplacedSystem { index: 2 })
+        );
+        assert_eq!(
+            log(&[System, Assistant]).is_well_formed(),
+            Err(LogError::UnexpectedRole {
+                index: 1,
+                expected: User,
+                found: Assistant
+            })
+        );
+        assert_eq!(
+            log(&[User, Assistant, Assistant]).is_well_formed(),
+            Err(LogError::UnexpectedRole {
+                index: 2,
+                expected: User,
+                found: Assistant
+            })
+        );
+    }
 
-        // Get the first choice
-        let choice = response.choices.first().expect("No choices");
+    /// Test that the cost of a multi choice completion uses the aggregate usage
+    #[test]
+    fn test_multi_choice_cost() {
+        let response: ChatCompletionResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o-mini",
+                "choices": [
+                    {"index": 0, "message": {"role": "assistant", "content": "A"}, "finish_reason": "stop"},
+                    {"index": 1, "message": {"role": "assistant", "content": "B"}, "finish_reason": "stop"},
+                    {"index": 2, "message": {"role": "assistant", "content": "C"}, "finish_reason": "stop"}
+                ],
+                "usage": {"prompt_tokens": 1000, "completion_tokens": 3000, "total_tokens": 4000}
+            }"#,
+        )
+        .unwrap();
+
+        let pricing = Model::Gpt4oMini.pricing().unwrap();
+        let expected = pricing.cost(1000, 3000);
+        let cost = response.cost_usd().unwrap();
+        assert!((cost - expected).abs() < 1e-12);
+
+        let per_choice = response.cost_per_choice().unwrap();
+        let summed = per_choice * response.choices.len() as f64;
+        assert!((summed - pricing.completion_cost(3000)).abs() < 1e-12);
+    }
+
+    /// Test that conversions from a chat log follow the default model. The
+    /// default is changed on a slot of its own, since other tests read the
+    /// process-wide one in parallel.
+    #[test]
+    fn test_default_model() {
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog(vec![]));
+        assert_eq!(request.model, Model::Gpt4o);
+        let request = ChatCompletionRequest::from(ChatLog(vec![]));
+        assert_eq!(request.model, default_model());
 
-        // Get the message
-        let message = &choice.message;
+        let slot = RwLock::new(None);
+        assert_eq!(read_default(&slot), Model::Gpt35Turbo);
+        write_default(&slot, Model::Gpt4oMini);
+        assert_eq!(read_default(&slot), Model::Gpt4oMini);
+    }
+
+    /// Test summarizing a response into its first choice
+    #[test]
+    fn test_summarize() {
+        let response: ChatCompletionResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o",
+                "choices": [
+                    {"index": 0, "message": {"role": "assistant", "content": "A"}, "finish_reason": "length"}
+                ],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 1, "total_tokens": 11}
+            }"#,
+        )
+        .unwrap();
+
+        let summary = response.summarize();
+
+        fn assert_thread_safe<T: Clone + Send + Sync>(_: &T) {}
+        assert_thread_safe(&summary);
+        assert_eq!(summary.id, "chatcmpl-123");
+        assert_eq!(summary.content, "A");
+        assert_eq!(summary.finish_reason, Some(FinishReason::Length));
+        assert_eq!(summary.usage.unwrap().total_tokens, 11);
+        assert_eq!(summary.model, "gpt-4o");
+    }
+
+    /// Test that decode errors keep the raw body
+    #[test]
+    fn test_decode_error_body() {
+        let body = r#"{"error": {"message": "Something odd happened"}}"#;
+        match ChatCompletionResponse::parse(body) {
+            Err(OpenAIError::Decode { body: raw, .. }) => assert_eq!(raw, body),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+
+    /// Test that both steering roles are accepted and can be converted
+    #[test]
+    fn test_developer_role() {
+        let log: ChatLog = serde_json::from_str(
+            r#"[{"role": "developer", "content": "Be brief."}, {"role": "user", "content": "Hi"}]"#,
+        )
+        .unwrap();
+        assert_eq!(log.entries()[0].role, ChatRole::Developer);
+        assert_eq!(log.system(), Some("Be brief."));
+        assert_eq!(log.is_well_formed(), Ok(()));
 
-        // Check that the message is correct
-        assert_eq!(message.role, ChatRole::Assistant);
+        let mut log = ChatLog::new();
+        log.push_system("Be brief.");
+        let request = ChatCompletionRequest::new(Model::O1, log).with_developer_role();
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["messages"][0]["role"], "developer");
+    }
+
+    /// Test sending and receiving the service tier
+    #[test]
+    fn test_service_tier() {
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new());
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("service_tier").is_none());
+
+        let request = request.with_service_tier("scale");
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["service_tier"], "scale");
+
+        let response = ChatCompletionResponse::parse(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o",
+                "service_tier": "scale",
+                "choices": [],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 0, "total_tokens": 10}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.service_tier.as_deref(), Some("scale"));
+    }
+
+    /// Test that the token limit is sent in the field the model expects
+    #[test]
+    fn test_max_tokens_field() {
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new())
+            .with_max_tokens(64);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["max_tokens"], 64);
+        assert!(value.get("max_completion_tokens").is_none());
+
+        let request =
+            ChatCompletionRequest::new(Model::O1, ChatLog::new()).with_max_tokens(64);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["max_completion_tokens"], 64);
+        assert!(value.get("max_tokens").is_none());
+    }
+
+    /// Test that a completion without choices is a typed error
+    #[test]
+    fn test_no_choices() {
+        let body = r#"{
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1677652288,
+            "model": "gpt-4o",
+            "choices": [],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 0, "total_tokens": 10}
+        }"#;
+        assert!(ChatCompletionResponse::parse(body).is_ok());
+        assert!(matches!(
+            ChatCompletionResponse::parse_completion(body),
+            Err(OpenAIError::NoChoices)
+        ));
+    }
+
+    /// Test that creation times beyond the range of a 32-bit usize parse
+    #[test]
+    fn test_large_created() {
+        let response = ChatCompletionResponse::parse(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 8589934592,
+                "model": "gpt-4o",
+                "choices": []
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.created, 8_589_934_592);
+    }
+
+    /// Test merging the choices and usage of two responses
+    #[test]
+    fn test_merge() {
+        let response = |model: &str, contents: &[&str], tokens: u32| {
+            let body =
+                completion_body_with_usage(model, contents[0], "stop", 10, tokens);
+            let mut body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            body["id"] = format!("chatcmpl-{}", tokens).into();
+            for (index, content) in contents.iter().enumerate().skip(1) {
+                let mut choice = body["choices"][0].clone();
+                choice["index"] = index.into();
+                choice["message"]["content"] = (*content).into();
+                body["choices"].as_array_mut().unwrap().push(choice);
+            }
+            ChatCompletionResponse::parse(&body.to_string()).unwrap()
+        };
+
+        let merged = response("gpt-4o", &["a", "b"], 4)
+            .merge(response("gpt-4o", &["c"], 2))
+            .unwrap();
+        assert_eq!(merged.id, "chatcmpl-4");
+        let indices: Vec<_> = merged.choices.iter().map(|c| c.index).collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(merged.choices[2].message.content, "c");
+        let usage = merged.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 20);
+        assert_eq!(usage.completion_tokens, 6);
+        assert_eq!(usage.total_tokens, 26);
+
+        let mismatch =
+            response("gpt-4o", &["a"], 1).merge(response("gpt-4", &["b"], 1));
+        assert!(matches!(mismatch, Err(OpenAIError::ModelMismatch { .. })));
+    }
+
+    /// Test that creation times sent as numbers or strings parse the same
+    #[test]
+    fn test_string_created() {
+        let parse = |created: &str| {
+            let body = completion_body("gpt-4o", "Hi", "stop");
+            let mut body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            body["created"] = serde_json::from_str(created).unwrap();
+            ChatCompletionResponse::parse(&body.to_string())
+                .map(|response| response.created)
+        };
+        assert_eq!(parse("1700000000").unwrap(), 1_700_000_000);
+        assert_eq!(parse(r#""1700000000""#).unwrap(), 1_700_000_000);
+        assert!(parse(r#""yesterday""#).is_err());
+    }
+
+    /// Test that responses round trip through strings, readers and writers
+    #[test]
+    fn test_response_round_trip() {
+        let body = r#"{
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1677652288,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 1, "total_tokens": 11}
+        }"#;
+        let response: ChatCompletionResponse = body.parse().unwrap();
+        let reparsed: ChatCompletionResponse = response.to_string().parse().unwrap();
+        assert_eq!(reparsed.to_string(), response.to_string());
+
+        let mut written = Vec::new();
+        response.to_writer(&mut written).unwrap();
+        let read = ChatCompletionResponse::from_reader(written.as_slice()).unwrap();
+        assert_eq!(read.first_message().unwrap().content, "Hi");
+        assert_eq!(read.usage, response.usage);
+    }
+
+    /// Test turning log probabilities into a confidence score
+    #[test]
+    fn test_confidence() {
+        let choice: ChatCompletionChoice = serde_json::from_value(serde_json::json!({
+            "index": 0,
+            "message": {"role": "assistant", "content": "Yes."},
+            "finish_reason": "stop",
+            "logprobs": {"content": [
+                {"token": "Yes", "logprob": 0.0, "bytes": [89, 101, 115], "top_logprobs": []},
+                {"token": ".", "logprob": -std::f64::consts::LN_2, "bytes": [46],
+                 "top_logprobs": [{"token": ".", "logprob": -0.69, "bytes": [46]}]}
+            ]}
+        }))
+        .unwrap();
+        let per_token = choice.per_token_confidence().unwrap();
+        assert!((per_token[0] - 1.0).abs() < 1e-9);
+        assert!((per_token[1] - 0.5).abs() < 1e-9);
+        assert!((choice.confidence().unwrap() - 0.75).abs() < 1e-9);
+
+        let plain = ChatCompletionChoice {
+            logprobs: None,
+            ..choice
+        };
+        assert_eq!(plain.confidence(), None);
+
+        let request =
+            ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new()).with_logprobs(3);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["logprobs"], true);
+        assert_eq!(value["top_logprobs"], 3);
+    }
+
+    /// Test the perplexity of a reply against a hand computed value
+    #[test]
+    fn test_perplexity() {
+        let choice: ChatCompletionChoice = serde_json::from_value(serde_json::json!({
+            "index": 0,
+            "message": {"role": "assistant", "content": "a b c"},
+            "finish_reason": "stop",
+            "logprobs": {"content": [
+                {"token": "a", "logprob": -1.0, "bytes": [97], "top_logprobs": []},
+                {"token": " b", "logprob": -2.0, "bytes": [32, 98], "top_logprobs": []},
+                {"token": " c", "logprob": -3.0, "bytes": [32, 99], "top_logprobs": []}
+            ]}
+        }))
+        .unwrap();
+        // exp(-(-1 - 2 - 3) / 3) = e^2
+        assert!((choice.perplexity().unwrap() - 7.389_056_098_930_65).abs() < 1e-9);
+
+        let mut choice = choice;
+        choice.logprobs = Some(ChoiceLogprobs {
+            content: Some(vec![]),
+        });
+        assert_eq!(choice.perplexity(), None);
+        choice.logprobs = None;
+        assert_eq!(choice.perplexity(), None);
+    }
+
+    /// Test decoding JSON replies, repairing truncated ones only when asked
+    #[test]
+    fn test_json_reply() {
+        let response = |content: &str| {
+            ChatCompletionResponse::parse(&completion_body("gpt-4o", content, "length"))
+                .unwrap()
+        };
+
+        let reply: JsonReply<Vec<u32>> = response("[1, 2]").json_reply(true).unwrap();
+        assert_eq!(reply.value, [1, 2]);
+        assert!(!reply.repaired);
+
+        let truncated = response("[1, 2, ");
+        assert!(truncated.json_reply::<Vec<u32>>(false).is_err());
+        let reply: JsonReply<Vec<u32>> = truncated.json_reply(true).unwrap();
+        assert_eq!(reply.value, [1, 2]);
+        assert!(reply.repaired);
+
+        let result = response("[1, tr").json_reply::<Vec<u32>>(true);
+        assert!(matches!(result, Err(OpenAIError::Decode { .. })));
+
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new());
+        let value = serde_json::to_value(request.with_json_mode()).unwrap();
+        assert_eq!(
+            value["response_format"],
+            serde_json::json!({"type": "json_object"})
+        );
+    }
+
+    /// Test that refusals are told apart from empty replies
+    #[test]
+    fn test_refusal() {
+        let response = ChatCompletionResponse::parse_completion(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "refusal": "I can't help with that."
+                    },
+                    "finish_reason": "stop"
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.first_message().unwrap().content, "");
+        assert_eq!(response.refusal(), Some("I can't help with that."));
+        assert!(matches!(
+            response.reply(),
+            Err(OpenAIError::Refusal(refusal)) if refusal == "I can't help with that."
+        ));
+        assert_eq!(
+            response.summarize().refusal.as_deref(),
+            Some("I can't help with that.")
+        );
+    }
+
+    /// Test that a response without usage parses and has no cost
+    #[test]
+    fn test_missing_usage() {
+        let response = ChatCompletionResponse::parse_completion(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hi"},
+                    "finish_reason": "stop"
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert!(response.usage.is_none());
+        assert_eq!(response.cost_usd(), None);
+        assert_eq!(response.cost_per_choice(), None);
+        assert_eq!(response.summarize().usage, None);
+    }
+
+    /// Test building and iterating logs with standard iterators
+    #[test]
+    fn test_log_iterators() {
+        let turns = vec![(ChatRole::User, "Hi"), (ChatRole::Assistant, "Hello")];
+        let log: ChatLog = turns.into_iter().collect();
+        assert_eq!(log.len(), 2);
+
+        let roles: Vec<&ChatRole> =
+            (&log).into_iter().map(|entry| &entry.role).collect();
+        assert_eq!(roles, vec![&ChatRole::User, &ChatRole::Assistant]);
+
+        let users: ChatLog = log
+            .into_iter()
+            .filter(|entry| entry.role == ChatRole::User)
+            .collect();
+        assert_eq!(users.entries()[0].content, "Hi");
+    }
+
+    /// Test reading the url citations of a reply
+    #[test]
+    fn test_citations() {
+        let response = ChatCompletionResponse::parse(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-4o-search-preview",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": "Rust 1.0 shipped in 2015, née Rust.",
+                        "annotations": [
+                            {"type": "file_citation"},
+                            {
+                                "type": "url_citation",
+                                "url_citation": {
+                                    "url": "https://blog.rust-lang.org/2015/05/15/Rust-1.0.html",
+                                    "title": "Announcing Rust 1.0",
+                                    "start_index": 0,
+                                    "end_index": 24
+                                }
+                            },
+                            {
+                                "type": "url_citation",
+                                "url_citation": {
+                                    "url": "https://example.com",
+                                    "start_index": 26,
+                                    "end_index": 35
+                                }
+                            }
+                        ]
+                    },
+                    "finish_reason": "stop"
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let citations = response.citations();
+        assert_eq!(citations.len(), 2);
+        assert_eq!(citations[0].title, "Announcing Rust 1.0");
+        let content = response.reply().unwrap();
+        assert_eq!(
+            citations[0].cited_text(content),
+            Some("Rust 1.0 shipped in 2015")
+        );
+        assert_eq!(citations[1].cited_text(content), Some("née Rust."));
+        let past_end = UrlCitation {
+            start_index: 30,
+            end_index: 40,
+            ..citations[1].clone()
+        };
+        assert_eq!(past_end.cited_text(content), None);
+
+        let plain = ChatLog::from(vec![response.choices[0].message.clone()]);
+        let sent =
+            serde_json::to_value(ChatCompletionRequest::new(Model::Gpt4o, plain))
+                .unwrap();
+        assert!(sent["messages"][0].get("annotations").is_none());
+    }
+
+    /// Test repeating the system prompt every few turns, only once
+    #[test]
+    fn test_reinforce_system() {
+        let mut log = ChatLog::new();
+        log.push_system("Be brief.");
+        for i in 0..5 {
+            log.push_user(format!("question {}", i));
+            log.push_assistant(format!("answer {}", i));
+        }
+
+        assert_eq!(log.clone().reinforce_system(0), 0);
+        assert_eq!(log.reinforce_system(2), 2);
+        let reminders: Vec<usize> = (1..log.len())
+            .filter(|&index| log.entries()[index].role == ChatRole::System)
+            .collect();
+        assert_eq!(reminders, [5, 10]);
+        assert_eq!(log.entries()[5].content, "Be brief.");
+        assert_eq!(log.reinforce_system(2), 0);
+
+        log.push_user("question 5");
+        log.push_assistant("answer 5");
+        assert_eq!(log.reinforce_system(2), 1);
+        assert_eq!(log.entries().last().unwrap().role, ChatRole::System);
+        assert_eq!(log.check_system_placement(), Ok(()));
+        assert_eq!(log.is_well_formed(), Ok(()));
+        log.push_system("Be verbose.");
+        assert_eq!(
+            log.check_system_placement(),
+            Err(LogError::MisplacedSystem { index: 16 })
+        );
+
+        let mut plain: ChatLog =
+            [(ChatRole::User, "Hi"), (ChatRole::Assistant, "Hello")]
+                .into_iter()
+                .collect();
+        assert_eq!(plain.reinforce_system(1), 0);
+    }
+
+    /// Test cutting oversized messages on char boundaries
+    #[test]
+    fn test_truncate_message_bytes() {
+        let mut log: ChatLog = [
+            (ChatRole::System, "short"),
+            (ChatRole::User, "héhéhéhéhéhéhéhé"),
+            (ChatRole::Assistant, "exactly twenty bytes"),
+        ]
+        .into_iter()
+        .collect();
+        let before = log.content_hash();
+        assert!(log.truncate_message_bytes(24).is_empty());
+        assert_eq!(log.content_hash(), before);
+
+        assert_eq!(log.truncate_message_bytes(20), [1]);
+        // The marker takes 14 bytes, which leaves 6 for the content
+        assert_eq!(log.entries()[1].content, "héhé…[truncated]");
+        assert!(log.entries()[1].content.len() <= 20);
+
+        assert_eq!(log.truncate_message_bytes(4), [0, 1, 2]);
+        assert_eq!(log.entries()[0].content, "shor");
+        assert_eq!(log.entries()[1].content, "héh");
+        assert_eq!(log.truncate_message_bytes(2), [0, 1, 2]);
+        assert_eq!(log.entries()[1].content, "h");
+    }
+
+    /// Test spotting system prompts sent as user messages
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_misplaced_system_prompt() {
+        let system: ChatLog =
+            [(ChatRole::System, "You are terse.")].into_iter().collect();
+        system.assert_first_is_system();
+        assert!(!looks_like_misplaced_system(system.entries()));
+
+        let user: ChatLog =
+            [(ChatRole::User, "  you are terse.")].into_iter().collect();
+        assert!(looks_like_misplaced_system(user.entries()));
+        assert!(std::panic::catch_unwind(|| user.assert_first_is_system()).is_err());
+        assert!(
+            std::panic::catch_unwind(|| ChatLog::new().assert_first_is_system())
+                .is_err()
+        );
+
+        let question: ChatLog = [(ChatRole::User, "You")].into_iter().collect();
+        assert!(!looks_like_misplaced_system(question.entries()));
+        assert!(!looks_like_misplaced_system(&[]));
+    }
+
+    /// Test that a prefill is sent as a last assistant message
+    #[test]
+    fn test_prefill() {
+        let mut log = ChatLog::new();
+        log.push_user("List three colors as JSON");
+        let request = ChatCompletionRequest::new(Model::Gpt4o, log).with_prefill("[");
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["messages"][1]["role"], "assistant");
+        assert_eq!(value["messages"][1]["content"], "[");
+        assert!(value.get("prefill").is_none());
+        assert_eq!(request.prefill(), Some("["));
+
+        let request = request.with_prefill("[\"red\",");
+        assert_eq!(request.messages().len(), 2);
+        assert_eq!(request.messages().entries()[1].content, "[\"red\",");
+    }
+
+    /// Test storing completions with validated metadata
+    #[test]
+    fn test_store_metadata() {
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new());
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("store").is_none());
+        assert!(value.get("metadata").is_none());
+
+        let metadata = HashMap::from([("team".to_string(), "evals".to_string())]);
+        let request = request.with_store(true).with_metadata(metadata).unwrap();
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["store"], true);
+        assert_eq!(value["metadata"]["team"], "evals");
+
+        let too_long = HashMap::from([("k".repeat(65), String::new())]);
+        let result = request.clone().with_metadata(too_long);
+        assert!(matches!(result, Err(MetadataError::KeyTooLong { .. })));
+        let too_many = (0..17).map(|i| (i.to_string(), String::new())).collect();
+        let result = request.with_metadata(too_many);
+        assert!(matches!(
+            result,
+            Err(MetadataError::TooManyPairs { count: 17 })
+        ));
+    }
+
+    /// Test that parallel tool calls are only sent when set, and only along
+    /// tools
+    #[test]
+    fn test_parallel_tool_calls() {
+        let tool = Tool::function("noop", "Do nothing", serde_json::json!({}));
+        let request = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new())
+            .with_tools(vec![tool]);
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("parallel_tool_calls").is_none());
+
+        let request = request.with_parallel_tool_calls(false);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["parallel_tool_calls"], false);
+        let legacy = request.use_legacy_functions(true);
+        let value = serde_json::to_value(&legacy).unwrap();
+        assert!(value.get("parallel_tool_calls").is_none());
+        assert!(value.get("functions").is_some());
+
+        let without_tools = ChatCompletionRequest::new(Model::Gpt4o, ChatLog::new())
+            .with_parallel_tool_calls(true);
+        let value = serde_json::to_value(&without_tools).unwrap();
+        assert!(value.get("parallel_tool_calls").is_none());
+        let empty_tools = without_tools.with_tools(vec![]);
+        let value = serde_json::to_value(&empty_tools).unwrap();
+        assert!(value.get("parallel_tool_calls").is_none());
+
+        // Switching back sends the option again, and requests load back with it
+        let request = legacy.use_legacy_functions(false);
+        let value = serde_json::to_value(&request).unwrap();
+        let loaded: ChatCompletionRequest = serde_json::from_value(value).unwrap();
+        let value = serde_json::to_value(&loaded).unwrap();
+        assert_eq!(value["parallel_tool_calls"], false);
+    }
+
+    /// Test sending tools with the legacy functions api
+    #[test]
+    fn test_legacy_functions() {
+        let tool = Tool::function(
+            "get_weather",
+            "Get the weather of a city",
+            serde_json::json!({"type": "object", "properties": {}}),
+        );
+        let log = ChatLog(vec![ChatEntry::new(ChatRole::User, "Weather?")]);
+        let request = ChatCompletionRequest::new(Model::Gpt35Turbo, log)
+            .with_tools(vec![tool])
+            .use_legacy_functions(true);
+
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("tools").is_none());
+        assert_eq!(value["functions"][0]["name"], "get_weather");
+
+        let request = request.use_legacy_functions(false);
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("functions").is_none());
+        assert_eq!(value["tools"][0]["function"]["name"], "get_weather");
+    }
+
+    /// Test parsing a legacy function call from a response
+    #[test]
+    fn test_legacy_function_call_response() {
+        let response = ChatCompletionResponse::parse(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-3.5-turbo-0613",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "function_call": {"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}
+                    },
+                    "finish_reason": "function_call"
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+            }"#,
+        )
+        .unwrap();
 
-        println!("Assistant: {}", message.content);
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason, FinishReason::FunctionCall);
+        assert_eq!(choice.message.content, "");
+        let call = choice.message.function_call.as_ref().unwrap();
+        assert_eq!(call.name, "get_weather");
+        assert_eq!(call.arguments, "{\"city\": \"Paris\"}");
     }
 }
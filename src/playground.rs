@@ -0,0 +1,126 @@
+use crate::model::Model;
+use crate::openai::{api_messages, default_model, ChatLog};
+use serde::{Deserialize, Serialize};
+
+/// The model and sampling settings of a session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    /// The model used for the session
+    pub model: Model,
+    /// The sampling temperature, between 0 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// The nucleus sampling probability mass, between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// The maximum amount of tokens to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// The penalty for tokens based on how often they already appeared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// The penalty for tokens that already appeared at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+}
+
+impl SamplingParams {
+    /// Create settings for the given model, leaving sampling to the api defaults
+    pub fn new(model: impl Into<Model>) -> SamplingParams {
+        SamplingParams {
+            model: model.into(),
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        }
+    }
+}
+
+impl Default for SamplingParams {
+    /// Settings for the process-wide default model
+    fn default() -> SamplingParams {
+        SamplingParams::new(default_model())
+    }
+}
+
+/// A session as exported to and imported from the playground
+#[derive(Serialize)]
+struct PlaygroundExport<'a> {
+    /// The messages of the session
+    #[serde(serialize_with = "api_messages")]
+    messages: &'a ChatLog,
+    /// The settings of the session, inlined next to the messages
+    #[serde(flatten)]
+    params: &'a SamplingParams,
+}
+
+/// An owned session read from the playground
+#[derive(Deserialize)]
+struct PlaygroundImport {
+    /// The messages of the session
+    messages: ChatLog,
+    /// The settings of the session, inlined next to the messages
+    #[serde(flatten)]
+    params: SamplingParams,
+}
+
+impl ChatLog {
+    /// Export the log as the JSON the OpenAI playground imports: the messages
+    /// next to the model and the sampling settings, like a request body.
+    pub fn to_playground_json(&self, params: &SamplingParams) -> serde_json::Value {
+        let export = PlaygroundExport {
+            messages: self,
+            params,
+        };
+        serde_json::to_value(export).expect("chat logs always serialize")
+    }
+
+    /// Import a session exported from the playground, the inverse of
+    /// [`ChatLog::to_playground_json`]. Unknown settings are ignored.
+    pub fn from_playground_json(
+        value: serde_json::Value,
+    ) -> Result<(ChatLog, SamplingParams), serde_json::Error> {
+        let import: PlaygroundImport = serde_json::from_value(value)?;
+        Ok((import.messages, import.params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Test that a session round trips through the playground format
+    #[test]
+    fn test_playground_round_trip() {
+        let mut log = ChatLog::new();
+        log.push_system("You are a pirate.");
+        log.push_user("Hello");
+        let params = SamplingParams {
+            temperature: Some(0.7),
+            max_tokens: Some(256),
+            ..SamplingParams::new(Model::Gpt4o)
+        };
+
+        let value = log.to_playground_json(&params);
+        assert_eq!(
+            value,
+            json!({
+                "model": "gpt-4o",
+                "messages": [
+                    {"role": "system", "content": "You are a pirate."},
+                    {"role": "user", "content": "Hello"}
+                ],
+                "temperature": 0.7,
+                "max_tokens": 256
+            })
+        );
+
+        let (imported, imported_params) = ChatLog::from_playground_json(value).unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported.system(), Some("You are a pirate."));
+        assert_eq!(imported_params, params);
+    }
+}
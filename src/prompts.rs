@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files that are picked up as prompts
+const PROMPT_EXTENSIONS: [&str; 2] = ["md", "txt"];
+
+/// Errors that can happen while loading a prompt library
+#[derive(Debug)]
+pub enum PromptLibraryError {
+    /// The given path does not exist or is not a directory
+    NotADirectory(PathBuf),
+    /// A file or directory could not be read
+    Io {
+        /// The path that failed to be read
+        path: PathBuf,
+        /// The underlying io error
+        source: std::io::Error,
+    },
+    /// A prompt file does not contain valid UTF-8
+    InvalidUtf8(PathBuf),
+}
+
+impl fmt::Display for PromptLibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PromptLibraryError::NotADirectory(path) => {
+                write!(f, "prompt directory {} does not exist", path.display())
+            }
+            PromptLibraryError::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            PromptLibraryError::InvalidUtf8(path) => {
+                write!(f, "prompt file {} is not valid UTF-8", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PromptLibraryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PromptLibraryError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A named collection of reusable prompts, usually system prompts
+#[derive(Debug, Default)]
+pub struct PromptLibrary {
+    /// The prompts, keyed by name
+    prompts: HashMap<String, String>,
+}
+
+impl PromptLibrary {
+    /// Load every `.md` and `.txt` file in a directory, using the file stem as the
+    /// prompt name. Subdirectories and other files are ignored.
+    pub fn load_dir(
+        path: impl AsRef<Path>,
+    ) -> Result<PromptLibrary, PromptLibraryError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(PromptLibraryError::NotADirectory(path.to_path_buf()));
+        }
+
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| PromptLibraryError::Io { path, source }
+        };
+
+        let mut prompts = HashMap::new();
+        for entry in fs::read_dir(path).map_err(io_error(path))? {
+            let file = entry.map_err(io_error(path))?.path();
+            let is_prompt = file
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| PROMPT_EXTENSIONS.contains(&ext));
+            if !file.is_file() || !is_prompt {
+                continue;
+            }
+            let name = match file.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => return Err(PromptLibraryError::InvalidUtf8(file)),
+            };
+            let bytes = fs::read(&file).map_err(io_error(&file))?;
+            let content = String::from_utf8(bytes)
+                .map_err(|_| PromptLibraryError::InvalidUtf8(file.clone()))?;
+            prompts.insert(name, content);
+        }
+
+        Ok(PromptLibrary { prompts })
+    }
+
+    /// Get a prompt by name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.prompts.get(name).map(String::as_str)
+    }
+
+    /// The names of all the prompts in the library, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.prompts.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test loading prompts from a directory
+    #[test]
+    fn test_load_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("pirate.md"), "You are a pirate.").unwrap();
+        fs::write(dir.path().join("robot.txt"), "You are a robot.").unwrap();
+        fs::write(dir.path().join("notes.json"), "{}").unwrap();
+
+        let library = PromptLibrary::load_dir(dir.path()).unwrap();
+
+        assert_eq!(library.get("pirate"), Some("You are a pirate."));
+        assert_eq!(library.get("robot"), Some("You are a robot."));
+        assert_eq!(library.get("notes"), None);
+        assert_eq!(library.names(), vec!["pirate", "robot"]);
+    }
+
+    /// Test that a missing directory is reported
+    #[test]
+    fn test_load_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = PromptLibrary::load_dir(dir.path().join("missing"));
+        assert!(matches!(result, Err(PromptLibraryError::NotADirectory(_))));
+    }
+
+    /// Test that a non UTF-8 prompt is reported
+    #[test]
+    fn test_load_invalid_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("broken.md"), [0xff, 0xfe, 0xfd]).unwrap();
+        let result = PromptLibrary::load_dir(dir.path());
+        assert!(matches!(result, Err(PromptLibraryError::InvalidUtf8(_))));
+    }
+}
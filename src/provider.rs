@@ -0,0 +1,134 @@
+use crate::error::OpenAIError;
+use crate::openai::ChatCompletionResponse;
+use serde_json::{Map, Value};
+
+/// How the responses of a provider are mapped onto the types of this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProviderProfile {
+    /// Responses follow the OpenAI api exactly, anything else is a decode error
+    #[default]
+    OpenAI,
+    /// Responses of OpenAI compatible servers such as LocalAI or the Ollama
+    /// shim, normalized before decoding:
+    ///
+    /// - finish reasons such as `eos_token`, `eos`, `end_turn` or missing ones
+    ///   become `stop`, and `max_tokens` becomes `length`
+    /// - usage named `input_tokens`/`output_tokens` is renamed, and a missing
+    ///   `total_tokens` is computed
+    /// - a missing `id`, `object`, `created` or `model` gets an empty default
+    Compatible,
+}
+
+impl ProviderProfile {
+    /// Parse the body of a completion like
+    /// [`ChatCompletionResponse::parse_completion`], normalizing it first with
+    /// the quirks of the provider
+    pub fn parse_completion(
+        self,
+        body: &str,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        match self {
+            ProviderProfile::OpenAI => ChatCompletionResponse::parse_completion(body),
+            ProviderProfile::Compatible => {
+                let mut value: Value = serde_json::from_str(body)
+                    .map_err(|e| OpenAIError::decode(e, body))?;
+                if let Some(response) = value.as_object_mut() {
+                    normalize(response);
+                }
+                let response: ChatCompletionResponse = serde_json::from_value(value)
+                    .map_err(|e| OpenAIError::decode(e, body))?;
+                if response.choices.is_empty() {
+                    return Err(OpenAIError::NoChoices);
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// The canonical finish reason of a non standard one
+fn finish_reason(reason: &str) -> &str {
+    match reason {
+        "length" | "max_tokens" => "length",
+        "tool_calls" | "function_call" | "content_filter" => reason,
+        _ => "stop",
+    }
+}
+
+/// Rewrite a compatible response into the shape of the OpenAI api
+fn normalize(response: &mut Map<String, Value>) {
+    for (field, default) in [
+        ("id", Value::from("")),
+        ("object", Value::from("chat.completion")),
+        ("created", Value::from(0)),
+        ("model", Value::from("")),
+    ] {
+        if response.get(field).is_none_or(Value::is_null) {
+            response.insert(field.to_string(), default);
+        }
+    }
+
+    let choices = response.get_mut("choices").and_then(Value::as_array_mut);
+    for choice in choices.into_iter().flatten() {
+        let Some(choice) = choice.as_object_mut() else {
+            continue;
+        };
+        let reason = choice.get("finish_reason").and_then(Value::as_str);
+        let reason = finish_reason(reason.unwrap_or("stop")).to_string();
+        choice.insert("finish_reason".to_string(), Value::from(reason));
+    }
+
+    let usage = response.get_mut("usage").and_then(Value::as_object_mut);
+    if let Some(usage) = usage {
+        for (from, to) in [
+            ("input_tokens", "prompt_tokens"),
+            ("output_tokens", "completion_tokens"),
+        ] {
+            if let Some(tokens) = usage.remove(from) {
+                usage.entry(to).or_insert(tokens);
+            }
+        }
+        let count = |usage: &Map<String, Value>, field| {
+            usage.get(field).and_then(Value::as_u64).unwrap_or(0)
+        };
+        if usage.get("total_tokens").is_none() {
+            let total =
+                count(usage, "prompt_tokens") + count(usage, "completion_tokens");
+            usage.insert("total_tokens".to_string(), Value::from(total));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::FinishReason;
+
+    /// A response of a compatible server with non standard fields
+    const QUIRKY: &str = r#"{
+        "model": "llama3",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": "Hi"},
+            "finish_reason": "eos_token"
+        }],
+        "usage": {"input_tokens": 5, "output_tokens": 2}
+    }"#;
+
+    /// Test that the compatible profile normalizes quirks the strict one rejects
+    #[test]
+    fn test_compatible_profile() {
+        assert!(ProviderProfile::OpenAI.parse_completion(QUIRKY).is_err());
+
+        let response = ProviderProfile::Compatible
+            .parse_completion(QUIRKY)
+            .unwrap();
+        assert_eq!(response.choices[0].finish_reason, FinishReason::Stop);
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 5);
+        assert_eq!(usage.completion_tokens, 2);
+        assert_eq!(usage.total_tokens, 7);
+        assert_eq!(response.model, "llama3");
+        assert_eq!(finish_reason("max_tokens"), "length");
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The length of the window the limits apply to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A sliding window limiter of requests and tokens per minute
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// The maximum amount of requests in any minute
+    requests_per_minute: u32,
+    /// The maximum amount of estimated tokens in any minute
+    tokens_per_minute: u32,
+    /// The time and estimated tokens of the requests of the last minute, oldest
+    /// first
+    window: VecDeque<(Instant, u32)>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing the given amounts of requests and tokens in any
+    /// rolling minute
+    pub fn new(requests_per_minute: u32, tokens_per_minute: u32) -> RateLimiter {
+        RateLimiter {
+            requests_per_minute,
+            tokens_per_minute,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Record a request of the given estimated tokens if it fits in the limits
+    /// at `now`, or return how long to wait until it does. A request estimated
+    /// above the whole token limit is let through once the window is empty,
+    /// so it is not blocked forever.
+    pub fn try_acquire(&mut self, tokens: u32, now: Instant) -> Result<(), Duration> {
+        while let Some(&(time, _)) = self.window.front() {
+            if now.duration_since(time) < WINDOW {
+                break;
+            }
+            self.window.pop_front();
+        }
+
+        let mut requests = self.window.len() as u64;
+        let mut used: u64 = self.window.iter().map(|&(_, t)| u64::from(t)).sum();
+        let fits = |requests: u64, used: u64| {
+            requests == 0
+                || (requests < u64::from(self.requests_per_minute)
+                    && used + u64::from(tokens) <= u64::from(self.tokens_per_minute))
+        };
+        if fits(requests, used) {
+            self.window.push_back((now, tokens));
+            return Ok(());
+        }
+
+        // Find the oldest request whose expiry frees enough room
+        for &(time, expired) in &self.window {
+            requests -= 1;
+            used -= u64::from(expired);
+            if fits(requests, used) {
+                return Err((time + WINDOW).saturating_duration_since(now));
+            }
+        }
+        unreachable!("an empty window always fits")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that the request limit is enforced over a rolling minute
+    #[test]
+    fn test_request_limit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2, 1000);
+        assert_eq!(limiter.try_acquire(10, start), Ok(()));
+        let later = start + Duration::from_secs(20);
+        assert_eq!(limiter.try_acquire(10, later), Ok(()));
+        assert_eq!(limiter.try_acquire(10, later), Err(Duration::from_secs(40)));
+        assert_eq!(limiter.try_acquire(10, start + WINDOW), Ok(()));
+    }
+
+    /// Test that the token limit accounts for the estimate of every request
+    #[test]
+    fn test_token_limit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100, 1000);
+        assert_eq!(limiter.try_acquire(600, start), Ok(()));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.try_acquire(300, later), Ok(()));
+        assert_eq!(
+            limiter.try_acquire(300, later),
+            Err(Duration::from_secs(50))
+        );
+        assert_eq!(limiter.try_acquire(100, later), Ok(()));
+
+        let mut limiter = RateLimiter::new(100, 1000);
+        assert_eq!(limiter.try_acquire(5000, start), Ok(()));
+    }
+}
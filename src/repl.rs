@@ -0,0 +1,186 @@
+use crate::backend::ChatBackend;
+use crate::client::OpenAI;
+use crate::conversation::Conversation;
+use crate::openai::ChatLog;
+use crate::session::SaveFormat;
+use std::io::{self, BufRead, Write};
+
+/// The help shown for unknown commands
+const HELP: &str = "commands: /reset, /save <path>, /load <path>, /exit";
+
+/// A line typed in the REPL
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    /// Start over with only the system prompt
+    Reset,
+    /// Write the log as JSON to a file
+    Save(&'a str),
+    /// Replace the log with one read from a JSON file
+    Load(&'a str),
+    /// Leave the REPL
+    Exit,
+    /// A command that does not exist or misses its argument
+    Unknown,
+    /// A message for the model
+    Message(&'a str),
+}
+
+impl<'a> Command<'a> {
+    /// Parse a line, which is a command if it starts with `/`
+    fn parse(line: &'a str) -> Command<'a> {
+        let line = line.trim();
+        let Some(command) = line.strip_prefix('/') else {
+            return Command::Message(line);
+        };
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+        match (name, argument) {
+            ("reset", "") => Command::Reset,
+            ("exit", "") => Command::Exit,
+            ("save", path) if !path.is_empty() => Command::Save(path),
+            ("load", path) if !path.is_empty() => Command::Load(path),
+            _ => Command::Unknown,
+        }
+    }
+}
+
+/// Chat with the model on stdin and stdout, streaming every reply as it
+/// arrives, until `/exit` or the end of the input. See [`run_repl_with`].
+pub fn run_repl(openai: &OpenAI, system: &str) -> io::Result<()> {
+    run_repl_with(openai, system, io::stdin().lock(), io::stdout().lock())
+}
+
+/// Chat with the model of a backend, reading lines from `input` and writing
+/// the replies to `output`. Besides messages, the lines can be the commands
+/// `/reset`, `/save <path>`, `/load <path>` and `/exit`; logs are saved
+/// compressed when the path ends with `.gz`. Failed requests and
+/// commands are reported on the output and the REPL goes on; only failing to
+/// read the input or write the output stops it.
+pub fn run_repl_with<B: ChatBackend>(
+    backend: &B,
+    system: &str,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut conversation = Conversation::new(backend, system);
+    for line in input.lines() {
+        let line = line?;
+        match Command::parse(&line) {
+            Command::Message("") => {}
+            Command::Message(message) => {
+                let mut written = Ok(());
+                let result = conversation.ask_streaming(message, |token| {
+                    if written.is_ok() {
+                        written =
+                            output.write_all(token.as_bytes()).and(output.flush());
+                    }
+                });
+                written?;
+                match result {
+                    Ok(()) => writeln!(output)?,
+                    Err(e) => writeln!(output, "\nerror: {}", e)?,
+                }
+            }
+            Command::Reset => conversation = Conversation::new(backend, system),
+            Command::Save(path) => {
+                let compressed = path.ends_with(".gz");
+                if let Err(e) =
+                    conversation
+                        .log()
+                        .save(path, SaveFormat::Pretty, compressed)
+                {
+                    writeln!(output, "error: {}", e)?;
+                }
+            }
+            Command::Load(path) => match ChatLog::load(path) {
+                Ok(log) => conversation.set_log(log),
+                Err(e) => writeln!(output, "error: {}", e)?,
+            },
+            Command::Exit => break,
+            Command::Unknown => writeln!(output, "{}", HELP)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::OpenAIError;
+    use crate::openai::{
+        ChatCompletionRequest, ChatCompletionResponse, ChatRole, CompletionUsage,
+    };
+
+    /// A backend streaming back the number of messages it was sent
+    struct CountingBackend;
+
+    impl ChatBackend for CountingBackend {
+        fn complete_request(
+            &self,
+            _request: &ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, OpenAIError> {
+            unreachable!("the REPL streams its replies")
+        }
+
+        fn stream_request(
+            &self,
+            request: &ChatCompletionRequest,
+            on_token: &mut dyn FnMut(&str),
+        ) -> Result<Option<CompletionUsage>, OpenAIError> {
+            on_token("messages: ");
+            on_token(&request.messages().len().to_string());
+            Ok(None)
+        }
+    }
+
+    /// Test parsing commands and messages
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse(" hello "), Command::Message("hello"));
+        assert_eq!(Command::parse("/reset"), Command::Reset);
+        assert_eq!(
+            Command::parse("/save  log.json "),
+            Command::Save("log.json")
+        );
+        assert_eq!(Command::parse("/load a b.json"), Command::Load("a b.json"));
+        assert_eq!(Command::parse("/save"), Command::Unknown);
+        assert_eq!(Command::parse("/exit now"), Command::Unknown);
+        assert_eq!(Command::parse("/quit"), Command::Unknown);
+    }
+
+    /// Test a session with every command
+    #[test]
+    fn test_run_repl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.json");
+        let path = path.to_str().unwrap();
+        let input = format!(
+            "Hi\n/save {path}\nAgain\n/reset\n\nHi\n/load {path}\nBack\n/help\n\
+             /exit\nIgnored\n"
+        );
+
+        let mut output = Vec::new();
+        run_repl_with(&CountingBackend, "Be brief.", input.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "messages: 2\nmessages: 4\nmessages: 2\nmessages: 4\n{}\n",
+                HELP
+            )
+        );
+
+        let saved = ChatLog::load(path).unwrap();
+        let roles: Vec<_> = saved
+            .entries()
+            .iter()
+            .map(|entry| entry.role.clone())
+            .collect();
+        assert_eq!(
+            roles,
+            [ChatRole::System, ChatRole::User, ChatRole::Assistant]
+        );
+    }
+}
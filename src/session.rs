@@ -0,0 +1,227 @@
+use crate::error::OpenAIError;
+use crate::openai::ChatLog;
+use crate::playground::SamplingParams;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The first bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How [`ChatLog::save`] lays out the JSON of a log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveFormat {
+    /// Indented with one field per line, which is easy to read, edit by hand
+    /// and diff when sessions are kept in git
+    #[default]
+    Pretty,
+    /// Without any whitespace, which is smaller
+    Compact,
+}
+
+/// What a session was run with, saved along its log in a [`SessionFile`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionMeta {
+    /// When the metadata was created, in seconds since the Unix epoch
+    pub created_at: u64,
+    /// The model and sampling settings of the session
+    pub params: SamplingParams,
+    /// A free text note, such as what the session was about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl SessionMeta {
+    /// Create metadata for a session run with the given settings, stamped
+    /// with the current time
+    pub fn new(params: SamplingParams) -> SessionMeta {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        SessionMeta {
+            created_at: now.map_or(0, |elapsed| elapsed.as_secs()),
+            params,
+            note: None,
+        }
+    }
+
+    /// Attach a note to the metadata
+    pub fn with_note(mut self, note: impl Into<String>) -> SessionMeta {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+/// A log saved with its metadata, which is written at the top of the file
+/// and kept out of the messages. Unlike [`ChatLog::save`] the file holds a
+/// JSON object, so the two kinds of files cannot be loaded as each other.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionFile {
+    /// What the session was run with
+    pub metadata: SessionMeta,
+    /// The conversation
+    pub log: ChatLog,
+}
+
+impl SessionFile {
+    /// Save the session to a file like [`ChatLog::save`]
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        format: SaveFormat,
+        compressed: bool,
+    ) -> Result<(), OpenAIError> {
+        save_json(self, path, format, compressed)
+    }
+
+    /// Load a session saved with [`SessionFile::save`], compressed or not
+    pub fn load(path: impl AsRef<Path>) -> Result<SessionFile, OpenAIError> {
+        load_json(path)
+    }
+}
+
+impl ChatLog {
+    /// Save the log to a file as JSON in the given format, gzip compressed if
+    /// `compressed` is set, which usually makes long sessions several times
+    /// smaller. See [`SessionFile`] to save metadata with it.
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        format: SaveFormat,
+        compressed: bool,
+    ) -> Result<(), OpenAIError> {
+        save_json(self, path, format, compressed)
+    }
+
+    /// Load a log saved with [`ChatLog::save`]. Compressed files are told
+    /// apart by the gzip header, so plain and compressed files both load.
+    pub fn load(path: impl AsRef<Path>) -> Result<ChatLog, OpenAIError> {
+        load_json(path)
+    }
+}
+
+/// Save a value to a file as JSON in the given format, gzip compressed if
+/// `compressed` is set
+fn save_json(
+    value: &impl Serialize,
+    path: impl AsRef<Path>,
+    format: SaveFormat,
+    compressed: bool,
+) -> Result<(), OpenAIError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if compressed {
+        let mut encoder = GzEncoder::new(&mut file, Compression::default());
+        write_json(value, &mut encoder, format)?;
+        encoder.finish()?;
+    } else {
+        write_json(value, &mut file, format)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Write a value as JSON in the given format
+fn write_json(
+    value: &impl Serialize,
+    writer: impl Write,
+    format: SaveFormat,
+) -> io::Result<()> {
+    match format {
+        SaveFormat::Pretty => serde_json::to_writer_pretty(writer, value)?,
+        SaveFormat::Compact => serde_json::to_writer(writer, value)?,
+    }
+    Ok(())
+}
+
+/// Load a value saved with [`save_json`], detecting compression by the gzip
+/// header
+fn load_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, OpenAIError> {
+    let bytes = fs::read(path)?;
+    let json = if bytes.starts_with(&GZIP_MAGIC) {
+        let mut json = String::new();
+        GzDecoder::new(bytes.as_slice()).read_to_string(&mut json)?;
+        json
+    } else {
+        String::from_utf8(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    };
+    serde_json::from_str(&json).map_err(|e| OpenAIError::decode(e, &json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::ChatRole;
+
+    /// Test that logs load back the same in every format, plain or compressed
+    #[test]
+    fn test_save_load() {
+        let log: ChatLog = [
+            (ChatRole::System, "Be brief."),
+            (ChatRole::User, "Hello"),
+            (ChatRole::Assistant, "Hi!"),
+        ]
+        .into_iter()
+        .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("log.json");
+        let compact = dir.path().join("compact.json");
+        let compressed = dir.path().join("log.json.gz");
+
+        log.save(&plain, SaveFormat::Pretty, false).unwrap();
+        log.save(&compact, SaveFormat::Compact, false).unwrap();
+        log.save(&compressed, SaveFormat::Compact, true).unwrap();
+        assert!(fs::read(&compressed).unwrap().starts_with(&GZIP_MAGIC));
+        let pretty_json = fs::read_to_string(&plain).unwrap();
+        let compact_json = fs::read_to_string(&compact).unwrap();
+        assert!(pretty_json.starts_with("[\n"));
+        assert!(!compact_json.contains('\n'));
+        assert!(compact_json.len() < pretty_json.len());
+
+        for path in [plain, compact, compressed] {
+            let loaded = ChatLog::load(&path).unwrap();
+            assert_eq!(
+                serde_json::to_value(&loaded).unwrap(),
+                serde_json::to_value(&log).unwrap()
+            );
+        }
+
+        let garbage = dir.path().join("garbage");
+        fs::write(&garbage, "not json").unwrap();
+        assert!(matches!(
+            ChatLog::load(&garbage),
+            Err(OpenAIError::Decode { .. })
+        ));
+    }
+
+    /// Test that sessions load back with their metadata and a clean log
+    #[test]
+    fn test_session_file() {
+        let log: ChatLog = [(ChatRole::User, "Hello"), (ChatRole::Assistant, "Hi!")]
+            .into_iter()
+            .collect();
+        let mut params = SamplingParams::new("gpt-4o");
+        params.temperature = Some(0.2);
+        let session = SessionFile {
+            metadata: SessionMeta::new(params).with_note("greeting test"),
+            log,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+
+        session.save(&path, SaveFormat::Pretty, false).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.find("\"metadata\"").unwrap() < json.find("\"log\"").unwrap());
+
+        let loaded = SessionFile::load(&path).unwrap();
+        assert_eq!(loaded.metadata, session.metadata);
+        assert!(loaded.metadata.created_at > 0);
+        assert_eq!(loaded.log.len(), 2);
+        assert_eq!(loaded.log.entries()[1].content, "Hi!");
+        assert!(ChatLog::load(&path).is_err());
+    }
+}
@@ -0,0 +1,1044 @@
+use crate::error::OpenAIError;
+use crate::openai::{timestamp, ChatEntry, ChatRole, CompletionUsage, FinishReason};
+use crate::tools::{FunctionCall, ToolCall, ToolType};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::BufRead;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+/// The payload that marks the end of a stream
+const DONE: &str = "[DONE]";
+
+/// How far back the token rate of a stream is measured for time estimates
+const RATE_WINDOW: Duration = Duration::from_secs(3);
+
+/// The partial message carried by a streamed chunk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ChunkDelta {
+    /// The role of the message, only sent in the first chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<ChatRole>,
+    /// The next piece of the content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The next pieces of the tool calls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A piece of a streamed tool call. The id, type and name are only sent in the
+/// first piece of a call, the arguments are split over all its pieces.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCallDelta {
+    /// The position of the call in the message, shared by all its pieces
+    pub index: usize,
+    /// The id of the call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The kind of tool being called
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ToolType>,
+    /// The piece of the function being called
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionCallDelta>,
+}
+
+/// A piece of a streamed function call
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FunctionCallDelta {
+    /// The name of the function
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The next piece of the JSON encoded arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// A choice of a streamed chunk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkChoice {
+    /// The index of the choice
+    pub index: usize,
+    /// The partial message of the choice
+    pub delta: ChunkDelta,
+    /// The finish reason, only sent once the choice is complete
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A single chunk of a streamed chat completion
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatCompletionChunk {
+    /// The completion id, shared by all its chunks
+    pub id: String,
+    /// The chunk object
+    pub object: String,
+    /// The completion creation time, in seconds since the Unix epoch
+    #[serde(deserialize_with = "timestamp")]
+    pub created: u64,
+    /// The model that served the completion
+    pub model: String,
+    /// The choices updated by this chunk
+    pub choices: Vec<ChunkChoice>,
+    /// The usage of the whole completion, only sent in the last chunk when asked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Events produced while streaming a completion
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// A new piece of the content of a choice
+    Token {
+        /// The index of the choice, 0 unless several were requested
+        index: usize,
+        /// The piece of content
+        text: String,
+    },
+    /// A tool call, sent once all its pieces arrived
+    ToolCall {
+        /// The index of the choice making the call
+        index: usize,
+        /// The whole call
+        call: ToolCall,
+    },
+    /// A choice finished for the given reason
+    FinishReason {
+        /// The index of the choice, 0 unless several were requested
+        index: usize,
+        /// Why the choice finished
+        reason: FinishReason,
+    },
+    /// The stream failed, no more events will follow
+    Error(OpenAIError),
+}
+
+impl ChatCompletionChunk {
+    /// The events carried by this chunk alone. Tool calls usually span several
+    /// chunks and are only reported if they are complete in this one, use a
+    /// [`StreamAssembler`] to follow them across the stream.
+    pub fn events(&self) -> Vec<StreamEvent> {
+        let mut assembler = StreamAssembler::new();
+        let mut events = assembler.events(self);
+        events.extend(assembler.finish());
+        events
+    }
+}
+
+/// Turns the chunks of a stream into events, concatenating the pieces of every
+/// tool call by index so each call is reported whole.
+///
+/// A call is complete when a later call of the same choice starts or when the
+/// choice finishes, its event is then sent before the finish reason.
+#[derive(Debug, Default)]
+pub struct StreamAssembler {
+    /// The calls being assembled, by choice index and call index
+    pending: BTreeMap<(usize, usize), ToolCall>,
+}
+
+impl StreamAssembler {
+    /// Create a new assembler
+    pub fn new() -> StreamAssembler {
+        StreamAssembler::default()
+    }
+
+    /// The events completed by the next chunk of the stream
+    pub fn events(&mut self, chunk: &ChatCompletionChunk) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        for choice in &chunk.choices {
+            if let Some(content) = &choice.delta.content {
+                if !content.is_empty() {
+                    events.push(StreamEvent::Token {
+                        index: choice.index,
+                        text: content.clone(),
+                    });
+                }
+            }
+            for delta in choice.delta.tool_calls.iter().flatten() {
+                let key = (choice.index, delta.index);
+                self.complete((choice.index, 0)..key, &mut events);
+                self.push(key, delta);
+            }
+            if let Some(reason) = &choice.finish_reason {
+                self.complete((choice.index, 0)..(choice.index + 1, 0), &mut events);
+                events.push(StreamEvent::FinishReason {
+                    index: choice.index,
+                    reason: reason.clone(),
+                });
+            }
+        }
+        events
+    }
+
+    /// Signal the end of the stream, returning the calls that were never
+    /// completed by a finish reason
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .map(|((index, _), call)| StreamEvent::ToolCall { index, call })
+            .collect()
+    }
+
+    /// Add a piece to the call it belongs to
+    fn push(&mut self, key: (usize, usize), delta: &ToolCallDelta) {
+        let call = self.pending.entry(key).or_insert_with(|| ToolCall {
+            id: String::new(),
+            kind: ToolType::Function,
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+        if let Some(id) = &delta.id {
+            call.id.push_str(id);
+        }
+        if let Some(kind) = &delta.kind {
+            call.kind = kind.clone();
+        }
+        if let Some(function) = &delta.function {
+            if let Some(name) = &function.name {
+                call.function.name.push_str(name);
+            }
+            if let Some(arguments) = &function.arguments {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    /// Send the events of the pending calls in the given range of keys
+    fn complete(
+        &mut self,
+        keys: std::ops::Range<(usize, usize)>,
+        events: &mut Vec<StreamEvent>,
+    ) {
+        let completed: Vec<_> = self.pending.range(keys).map(|(&key, _)| key).collect();
+        for key in completed {
+            if let Some(call) = self.pending.remove(&key) {
+                events.push(StreamEvent::ToolCall { index: key.0, call });
+            }
+        }
+    }
+}
+
+/// Rebuilds the message of a streamed reply from its chunks, to push it into a
+/// log once the stream ends. Chunks go through a [`StreamAssembler`], whose
+/// events are returned as they complete so the accumulator can sit in the
+/// middle of any streaming loop.
+///
+/// When several choices are streamed their contents are kept apart by index,
+/// see [`StreamAccumulator::contents`]. The message with its tool calls, the
+/// finish reason and the estimates are those of the first choice.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    /// Assembles the tool calls
+    assembler: StreamAssembler,
+    /// The role of the message, once sent
+    role: Option<ChatRole>,
+    /// The content so far of every choice, by index
+    contents: BTreeMap<usize, String>,
+    /// The tool calls of the first choice completed so far
+    tool_calls: Vec<ToolCall>,
+    /// The finish reason, once sent
+    finish_reason: Option<FinishReason>,
+    /// The content pieces of the first choice received so far
+    tokens: usize,
+    /// The elapsed time and received tokens at the recent estimates
+    samples: VecDeque<(Duration, usize)>,
+}
+
+impl StreamAccumulator {
+    /// Create a new accumulator
+    pub fn new() -> StreamAccumulator {
+        StreamAccumulator::default()
+    }
+
+    /// Add the next chunk of the stream, returning the events it completed
+    pub fn events(&mut self, chunk: &ChatCompletionChunk) -> Vec<StreamEvent> {
+        for choice in chunk.choices.iter().filter(|choice| choice.index == 0) {
+            if let Some(role) = &choice.delta.role {
+                self.role = Some(role.clone());
+            }
+            if let Some(reason) = &choice.finish_reason {
+                self.finish_reason = Some(reason.clone());
+            }
+        }
+        let events = self.assembler.events(chunk);
+        self.record(&events);
+        events
+    }
+
+    /// Signal the end of the stream, returning the tool calls that were never
+    /// completed by a finish reason
+    pub fn finish(&mut self) -> Vec<StreamEvent> {
+        let events = self.assembler.finish();
+        self.record(&events);
+        events
+    }
+
+    /// Keep what the events add to the message
+    fn record(&mut self, events: &[StreamEvent]) {
+        for event in events {
+            match event {
+                StreamEvent::Token { index, text } => {
+                    self.contents.entry(*index).or_default().push_str(text);
+                    if *index == 0 {
+                        self.tokens += 1;
+                    }
+                }
+                StreamEvent::ToolCall { index: 0, call } => {
+                    self.tool_calls.push(call.clone())
+                }
+                StreamEvent::ToolCall { .. }
+                | StreamEvent::FinishReason { .. }
+                | StreamEvent::Error(_) => {}
+            }
+        }
+    }
+
+    /// The content of the first choice received so far
+    pub fn content(&self) -> &str {
+        self.contents.get(&0).map_or("", String::as_str)
+    }
+
+    /// The content received so far of every choice that sent some, by index
+    pub fn contents(&self) -> &BTreeMap<usize, String> {
+        &self.contents
+    }
+
+    /// The finish reason of the reply, once the stream sent it
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// Estimate the time left until the reply reaches `max_tokens`, at the rate
+    /// tokens arrived over the last few seconds, given the time elapsed since
+    /// the request was sent. Every content piece counts as a token, as the api
+    /// sends one per chunk.
+    ///
+    /// Call it regularly, for example after every chunk: each call is a sample
+    /// of the rate, which is averaged over a short window so the estimate does
+    /// not jump with every chunk. Returns zero once the stream finished and
+    /// `None` while no token arrived yet. The reply usually ends before
+    /// `max_tokens`, so this is an upper bound rather than a prediction.
+    pub fn estimate_remaining(
+        &mut self,
+        elapsed: Duration,
+        max_tokens: usize,
+    ) -> Option<Duration> {
+        if self.finish_reason.is_some() || self.tokens >= max_tokens {
+            return Some(Duration::ZERO);
+        }
+        if self.samples.is_empty() {
+            self.samples.push_back((Duration::ZERO, 0));
+        }
+        self.samples.push_back((elapsed, self.tokens));
+        // Keep the newest sample older than the window as the baseline
+        while self.samples.len() > 2
+            && elapsed.saturating_sub(self.samples[1].0) >= RATE_WINDOW
+        {
+            self.samples.pop_front();
+        }
+        let (start, start_tokens) = self.samples[0];
+        let span = elapsed.saturating_sub(start).as_secs_f64();
+        let received = self.tokens - start_tokens;
+        if span == 0.0 || received == 0 {
+            return None;
+        }
+        let rate = received as f64 / span;
+        Some(Duration::from_secs_f64(
+            (max_tokens - self.tokens) as f64 / rate,
+        ))
+    }
+
+    /// The message of the reply, with the tool calls still pending at this point
+    /// included. The role defaults to assistant if the stream never sent one.
+    pub fn into_entry(mut self) -> ChatEntry {
+        self.finish();
+        let content = self.contents.remove(&0).unwrap_or_default();
+        ChatEntry {
+            tool_calls: (!self.tool_calls.is_empty()).then_some(self.tool_calls),
+            ..ChatEntry::new(self.role.unwrap_or(ChatRole::Assistant), content)
+        }
+    }
+}
+
+/// A decoder of server-sent event streams into chunks, independent of the
+/// transport. Bytes can be fed in pieces split anywhere, including in the middle
+/// of a multibyte character or of a line. Events are dispatched on blank lines,
+/// `data` lines of the same event are joined with newlines as the spec says,
+/// comments and other fields are ignored, and everything after the `[DONE]`
+/// sentinel is discarded.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    /// Bytes of the current, incomplete line
+    line: Vec<u8>,
+    /// The data of the current event
+    data: Option<String>,
+    /// Whether the `[DONE]` sentinel was seen
+    done: bool,
+}
+
+impl SseDecoder {
+    /// Create a new decoder
+    pub fn new() -> SseDecoder {
+        SseDecoder::default()
+    }
+
+    /// Whether the `[DONE]` sentinel was seen
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Feed bytes to the decoder, returning the chunks of the events they
+    /// complete. Malformed chunks are returned as decode errors without stopping
+    /// the decoder.
+    pub fn feed(
+        &mut self,
+        bytes: &[u8],
+    ) -> Vec<Result<ChatCompletionChunk, OpenAIError>> {
+        self.feed_raw(bytes)
+            .iter()
+            .filter_map(|data| decode_chunk(data))
+            .collect()
+    }
+
+    /// Feed bytes to the decoder like [`SseDecoder::feed`], but return the data
+    /// of the events they complete as is, `[DONE]` sentinel included
+    pub fn feed_raw(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if let Some(data) = self.process_line(&line) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    /// Signal the end of the input, returning the chunk of a last event that was
+    /// not followed by a blank line
+    pub fn finish(&mut self) -> Option<Result<ChatCompletionChunk, OpenAIError>> {
+        decode_chunk(&self.finish_raw()?)
+    }
+
+    /// Signal the end of the input like [`SseDecoder::finish`], but return the
+    /// data of the last event as is
+    pub fn finish_raw(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.line);
+        let data = if line.is_empty() {
+            None
+        } else {
+            self.process_line(&line)
+        };
+        data.or_else(|| self.dispatch())
+    }
+
+    /// Handle a complete line, returning the data of the event on blank lines
+    fn process_line(&mut self, line: &[u8]) -> Option<String> {
+        if self.done {
+            return None;
+        }
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        let line = String::from_utf8_lossy(line);
+        let value = line.strip_prefix("data:")?;
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match &mut self.data {
+            Some(data) => {
+                data.push('\n');
+                data.push_str(value);
+            }
+            None => self.data = Some(value.to_string()),
+        }
+        None
+    }
+
+    /// Take the data of the current event, unless it is empty
+    fn dispatch(&mut self) -> Option<String> {
+        let data = self.data.take()?;
+        let data = data.trim();
+        if data == DONE {
+            self.done = true;
+        }
+        (!data.is_empty()).then(|| data.to_string())
+    }
+}
+
+/// Decode the data of an event into a chunk, or `None` for the `[DONE]`
+/// sentinel
+fn decode_chunk(data: &str) -> Option<Result<ChatCompletionChunk, OpenAIError>> {
+    if data == DONE {
+        return None;
+    }
+    Some(serde_json::from_str(data).map_err(|e| OpenAIError::decode(e, data)))
+}
+
+/// An iterator over the raw data of the server-sent events of a reader, before
+/// any decoding, for debugging what a provider really sends. It ends after
+/// the `[DONE]` sentinel, which is yielded too, or at the end of the input,
+/// and after the first read error.
+pub struct RawEvents<R> {
+    /// The stream being read
+    reader: R,
+    /// Splits the stream into events
+    decoder: SseDecoder,
+    /// The events read but not yielded yet
+    pending: VecDeque<String>,
+    /// Whether the input is exhausted
+    finished: bool,
+    /// Counts the bytes read against the limit, if any
+    limit: ByteLimit,
+}
+
+impl<R: BufRead> RawEvents<R> {
+    /// Read the events of a reader
+    pub fn new(reader: R) -> RawEvents<R> {
+        RawEvents {
+            reader,
+            decoder: SseDecoder::new(),
+            pending: VecDeque::new(),
+            finished: false,
+            limit: ByteLimit::new(None),
+        }
+    }
+
+    /// Fail with [`OpenAIError::ResponseTooLarge`] instead of reading more
+    /// than `max_bytes` bytes of the stream
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> RawEvents<R> {
+        self.limit = ByteLimit::new(Some(max_bytes));
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for RawEvents<R> {
+    type Item = Result<String, OpenAIError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(data) = self.pending.pop_front() {
+                return Some(Ok(data));
+            }
+            if self.finished || self.decoder.is_done() {
+                return None;
+            }
+            let bytes = match self.reader.fill_buf() {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            if bytes.is_empty() {
+                self.finished = true;
+                self.pending.extend(self.decoder.finish_raw());
+                continue;
+            }
+            let len = bytes.len();
+            if let Err(e) = self.limit.add(len) {
+                self.finished = true;
+                return Some(Err(e));
+            }
+            self.pending.extend(self.decoder.feed_raw(bytes));
+            self.reader.consume(len);
+        }
+    }
+}
+
+/// Read server-sent events from a reader, calling `on_chunk` with every decoded
+/// chunk until the `[DONE]` sentinel or the end of the input
+pub fn read_chunks(
+    reader: impl BufRead,
+    mut on_chunk: impl FnMut(ChatCompletionChunk),
+) -> Result<(), OpenAIError> {
+    read_chunks_until(reader, |chunk| {
+        on_chunk(chunk);
+        ControlFlow::Continue(())
+    })
+}
+
+/// Read server-sent events like [`read_chunks`], stopping early as soon as
+/// `on_chunk` breaks. The rest of the input is left unread.
+pub fn read_chunks_until(
+    reader: impl BufRead,
+    on_chunk: impl FnMut(ChatCompletionChunk) -> ControlFlow<()>,
+) -> Result<(), OpenAIError> {
+    read_chunks_limited(reader, None, on_chunk)
+}
+
+/// Read server-sent events like [`read_chunks_until`], failing with
+/// [`OpenAIError::ResponseTooLarge`] past `max_bytes` bytes if limited. The
+/// chunks decoded before the limit are still given to `on_chunk`.
+pub fn read_chunks_limited(
+    mut reader: impl BufRead,
+    max_bytes: Option<usize>,
+    mut on_chunk: impl FnMut(ChatCompletionChunk) -> ControlFlow<()>,
+) -> Result<(), OpenAIError> {
+    let mut decoder = SseDecoder::new();
+    let mut limit = ByteLimit::new(max_bytes);
+    while !decoder.is_done() {
+        let bytes = reader.fill_buf()?;
+        if bytes.is_empty() {
+            if let Some(chunk) = decoder.finish() {
+                let _ = on_chunk(chunk?);
+            }
+            break;
+        }
+        let len = bytes.len();
+        limit.add(len)?;
+        for chunk in decoder.feed(bytes) {
+            if on_chunk(chunk?).is_break() {
+                return Ok(());
+            }
+        }
+        reader.consume(len);
+    }
+    Ok(())
+}
+
+/// The bytes read from a stream so far, against an optional limit
+struct ByteLimit {
+    /// The most bytes allowed, if limited
+    max_bytes: Option<usize>,
+    /// The bytes read so far
+    read: usize,
+}
+
+impl ByteLimit {
+    /// Start counting against a limit
+    fn new(max_bytes: Option<usize>) -> ByteLimit {
+        ByteLimit { max_bytes, read: 0 }
+    }
+
+    /// Count bytes about to be read, failing if they go past the limit
+    fn add(&mut self, len: usize) -> Result<(), OpenAIError> {
+        self.read = self.read.saturating_add(len);
+        match self.max_bytes {
+            Some(max_bytes) if self.read > max_bytes => {
+                Err(OpenAIError::ResponseTooLarge(max_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A stream of two content chunks, a finish chunk and a usage chunk
+    const STREAM: &str = concat!(
+        ": keep-alive\n\n",
+        "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"gpt-4o\",",
+        "\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"finish_reason\":null}]}\n\n",
+        "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"gpt-4o\",",
+        "\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"},\"finish_reason\":null}]}\n\n",
+        "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"gpt-4o\",",
+        "\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,\"model\":\"gpt-4o\",",
+        "\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2,\"total_tokens\":7}}\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    /// Test decoding a stream into chunks and events
+    #[test]
+    fn test_read_chunks() {
+        let mut chunks = Vec::new();
+        read_chunks(Cursor::new(STREAM), |chunk| chunks.push(chunk)).unwrap();
+        assert_eq!(chunks.len(), 4);
+
+        let events: Vec<StreamEvent> = chunks.iter().flat_map(|c| c.events()).collect();
+        assert!(
+            matches!(&events[0], StreamEvent::Token { index: 0, text } if text == "Hel")
+        );
+        assert!(
+            matches!(&events[1], StreamEvent::Token { index: 0, text } if text == "lo")
+        );
+        assert!(matches!(
+            &events[2],
+            StreamEvent::FinishReason {
+                index: 0,
+                reason: FinishReason::Stop
+            }
+        ));
+        assert_eq!(events.len(), 3);
+        assert_eq!(chunks[3].usage.as_ref().unwrap().total_tokens, 7);
+    }
+
+    /// Test reading the raw data of events, malformed ones included
+    #[test]
+    fn test_raw_events() {
+        let events: Vec<String> = RawEvents::new(Cursor::new(STREAM))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(events.len(), 5);
+        assert!(events[0].contains("\"Hel\""));
+        assert_eq!(events[4], DONE);
+
+        let stream = "data: {\"broken\n\ndata: [DONE]\n\ndata: ignored\n\n";
+        let events: Vec<String> = RawEvents::new(Cursor::new(stream))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(events, ["{\"broken", DONE]);
+    }
+
+    /// Test that streams over the byte limit fail and streams at it do not
+    #[test]
+    fn test_stream_max_bytes() {
+        let events: Vec<_> = RawEvents::new(Cursor::new(STREAM))
+            .with_max_bytes(STREAM.len())
+            .collect();
+        assert!(events.iter().all(Result::is_ok));
+        let events: Vec<_> = RawEvents::new(Cursor::new(STREAM))
+            .with_max_bytes(STREAM.len() - 1)
+            .collect();
+        assert!(matches!(
+            events.last(),
+            Some(Err(OpenAIError::ResponseTooLarge(max))) if *max == STREAM.len() - 1
+        ));
+
+        assert!(
+            read_chunks_limited(Cursor::new(STREAM), Some(STREAM.len()), |_| {
+                ControlFlow::Continue(())
+            })
+            .is_ok()
+        );
+        let mut count = 0;
+        let result = read_chunks_limited(Cursor::new(STREAM), Some(16), |_| {
+            count += 1;
+            ControlFlow::Continue(())
+        });
+        assert!(matches!(result, Err(OpenAIError::ResponseTooLarge(16))));
+        assert_eq!(count, 0);
+    }
+
+    /// Decode a stream fed in pieces of the given size
+    fn decode_in_pieces(stream: &[u8], size: usize) -> Vec<ChatCompletionChunk> {
+        let mut decoder = SseDecoder::new();
+        let mut chunks: Vec<ChatCompletionChunk> = stream
+            .chunks(size)
+            .flat_map(|piece| decoder.feed(piece))
+            .map(Result::unwrap)
+            .collect();
+        chunks.extend(decoder.finish().map(Result::unwrap));
+        chunks
+    }
+
+    /// Test that splitting the stream anywhere gives the same chunks
+    #[test]
+    fn test_decoder_splits() {
+        let expected = decode_in_pieces(STREAM.as_bytes(), STREAM.len());
+        assert_eq!(expected.len(), 4);
+        for size in 1..STREAM.len() {
+            assert_eq!(decode_in_pieces(STREAM.as_bytes(), size), expected);
+        }
+    }
+
+    /// Test splitting a multibyte character between two feeds
+    #[test]
+    fn test_decoder_multibyte_split() {
+        let stream = concat!(
+            "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\"created\":1,",
+            "\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"日本\"},",
+            "\"finish_reason\":null}]}\n\n"
+        );
+        for size in 1..4 {
+            let chunks = decode_in_pieces(stream.as_bytes(), size);
+            assert_eq!(chunks[0].choices[0].delta.content.as_deref(), Some("日本"));
+        }
+    }
+
+    /// Test events whose JSON is split over several data lines, CRLF line endings,
+    /// a missing final blank line and data after the sentinel
+    #[test]
+    fn test_decoder_adversarial() {
+        let stream = concat!(
+            ":comment\r\n\r\n\n",
+            "event: message\r\n",
+            "data: {\"id\":\"c\",\"object\":\"chat.completion.chunk\",\r\n",
+            "data: \"created\":1,\"model\":\"gpt-4o\",\"choices\":[]}\r\n\r\n",
+            "data: {\"id\":\"d\",\"object\":\"chat.completion.chunk\",\"created\":1,",
+            "\"model\":\"gpt-4o\",\"choices\":[]}"
+        );
+        let chunks = decode_in_pieces(stream.as_bytes(), 7);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].id, "c");
+        assert_eq!(chunks[1].id, "d");
+
+        let mut decoder = SseDecoder::new();
+        let after_done = "data: [DONE]\n\ndata: {\"broken\n\n";
+        assert!(decoder.feed(after_done.as_bytes()).is_empty());
+        assert!(decoder.is_done());
+        assert!(decoder.finish().is_none());
+    }
+
+    /// Build a chunk whose first choice has the given delta and finish reason
+    fn chunk(
+        delta: serde_json::Value,
+        finish_reason: Option<&str>,
+    ) -> ChatCompletionChunk {
+        serde_json::from_value(serde_json::json!({
+            "id": "c",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]
+        }))
+        .unwrap()
+    }
+
+    /// Test that the contents of interleaved choices are kept apart
+    #[test]
+    fn test_interleaved_choices() {
+        let piece = |index: usize, content: &str, finish_reason: Option<&str>| {
+            let mut chunk =
+                chunk(serde_json::json!({ "content": content }), finish_reason);
+            chunk.choices[0].index = index;
+            chunk
+        };
+        let chunks = [
+            piece(0, "Red", None),
+            piece(1, "Blue", None),
+            piece(1, " sky", Some("stop")),
+            piece(0, " sun", None),
+        ];
+
+        let mut accumulator = StreamAccumulator::new();
+        let events: Vec<StreamEvent> =
+            chunks.iter().flat_map(|c| accumulator.events(c)).collect();
+        assert!(
+            matches!(&events[1], StreamEvent::Token { index: 1, text } if text == "Blue")
+        );
+        let contents: Vec<&str> = accumulator
+            .contents()
+            .values()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(contents, ["Red sun", "Blue sky"]);
+        assert_eq!(accumulator.content(), "Red sun");
+        // The second choice finishing does not finish the first one
+        assert!(matches!(
+            &events[3],
+            StreamEvent::FinishReason {
+                index: 1,
+                reason: FinishReason::Stop
+            }
+        ));
+        assert_eq!(accumulator.finish_reason(), None);
+        assert_eq!(accumulator.into_entry().content, "Red sun");
+
+        // Only the calls of the first choice are kept in its message
+        let call = |index: usize, id: &str| {
+            let mut chunk = chunk(
+                serde_json::json!({"tool_calls": [{
+                    "index": 0, "id": id, "type": "function",
+                    "function": {"name": "get_time", "arguments": "{}"}
+                }]}),
+                Some("tool_calls"),
+            );
+            chunk.choices[0].index = index;
+            chunk
+        };
+        let mut accumulator = StreamAccumulator::new();
+        let events: Vec<StreamEvent> = [call(1, "call_b"), call(0, "call_a")]
+            .iter()
+            .flat_map(|c| accumulator.events(c))
+            .collect();
+        assert!(matches!(
+            &events[0],
+            StreamEvent::ToolCall { index: 1, call } if call.id == "call_b"
+        ));
+        let calls = accumulator.into_entry().tool_calls.unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_a");
+    }
+
+    /// Test that time estimates follow the recent token rate
+    #[test]
+    fn test_estimate_remaining() {
+        let token = chunk(serde_json::json!({"content": "a"}), None);
+        let mut accumulator = StreamAccumulator::new();
+        let secs = Duration::from_secs;
+        assert_eq!(accumulator.estimate_remaining(secs(1), 100), None);
+
+        // 10 tokens per second for 4 seconds
+        for second in 1..=4 {
+            for _ in 0..10 {
+                accumulator.events(&token);
+            }
+            accumulator.estimate_remaining(secs(second), 100);
+        }
+        assert_eq!(accumulator.estimate_remaining(secs(4), 100), Some(secs(6)));
+
+        // Then 20 per second, only the last 3 seconds count
+        for second in 5..=7 {
+            for _ in 0..20 {
+                accumulator.events(&token);
+            }
+            accumulator.estimate_remaining(secs(second), 200);
+        }
+        assert_eq!(accumulator.estimate_remaining(secs(7), 200), Some(secs(5)));
+
+        accumulator.events(&chunk(serde_json::json!({}), Some("stop")));
+        assert_eq!(
+            accumulator.estimate_remaining(secs(8), 200),
+            Some(Duration::ZERO)
+        );
+    }
+
+    /// Test rebuilding a message with content and tool calls from its chunks
+    #[test]
+    fn test_accumulate_entry() {
+        let chunks = [
+            chunk(
+                serde_json::json!({"role": "assistant", "content": "Let me "}),
+                None,
+            ),
+            chunk(serde_json::json!({"content": "check."}), None),
+            chunk(
+                serde_json::json!({"tool_calls": [{
+                    "index": 0, "id": "call_a", "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":"}
+                }]}),
+                None,
+            ),
+            chunk(
+                serde_json::json!({"tool_calls": [{
+                    "index": 0, "function": {"arguments": "\"Paris\"}"}
+                }]}),
+                None,
+            ),
+            chunk(serde_json::json!({}), Some("tool_calls")),
+        ];
+        let mut accumulator = StreamAccumulator::new();
+        let events: Vec<_> =
+            chunks.iter().flat_map(|c| accumulator.events(c)).collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(accumulator.content(), "Let me check.");
+        assert_eq!(accumulator.finish_reason(), Some(&FinishReason::ToolCalls));
+
+        let entry = accumulator.into_entry();
+        assert_eq!(entry.role, ChatRole::Assistant);
+        assert_eq!(entry.content, "Let me check.");
+        let calls = entry.tool_calls.unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.arguments, "{\"city\":\"Paris\"}");
+    }
+
+    /// Test that a finish event is only sent once the finish reason is set
+    #[test]
+    fn test_null_finish_reasons() {
+        let mut assembler = StreamAssembler::new();
+        let mut events = Vec::new();
+        for piece in ["{", "\"a\"", ": 1"] {
+            let chunk = chunk(serde_json::json!({ "content": piece }), None);
+            assert_eq!(chunk.choices[0].finish_reason, None);
+            events.extend(assembler.events(&chunk));
+        }
+        let missing: ChatCompletionChunk = serde_json::from_str(
+            r#"{"id": "c", "object": "chat.completion.chunk", "created": 1,
+                "model": "gpt-4o", "choices": [{"index": 0, "delta": {"content": "}"}}]}"#,
+        )
+        .unwrap();
+        events.extend(assembler.events(&missing));
+        assert!(events
+            .iter()
+            .all(|e| matches!(e, StreamEvent::Token { .. })));
+
+        let last = chunk(serde_json::json!({}), Some("stop"));
+        assert!(matches!(
+            assembler.events(&last).as_slice(),
+            [StreamEvent::FinishReason {
+                reason: FinishReason::Stop,
+                ..
+            }]
+        ));
+    }
+
+    /// Test assembling tool calls whose arguments are split over several chunks
+    #[test]
+    fn test_assemble_tool_calls() {
+        let chunks = [
+            chunk(
+                serde_json::json!({"role": "assistant", "tool_calls": [{
+                    "index": 0, "id": "call_a", "type": "function",
+                    "function": {"name": "get_weather", "arguments": ""}
+                }]}),
+                None,
+            ),
+            chunk(
+                serde_json::json!({"tool_calls": [
+                    {"index": 0, "function": {"arguments": "{\"ci"}}
+                ]}),
+                None,
+            ),
+            chunk(
+                serde_json::json!({"tool_calls": [
+                    {"index": 0, "function": {"arguments": "ty\": \"Paris\"}"}}
+                ]}),
+                None,
+            ),
+            chunk(
+                serde_json::json!({"tool_calls": [{
+                    "index": 1, "id": "call_b", "type": "function",
+                    "function": {"name": "get_time", "arguments": "{}"}
+                }]}),
+                None,
+            ),
+            chunk(serde_json::json!({}), Some("tool_calls")),
+        ];
+
+        let mut assembler = StreamAssembler::new();
+        let events: Vec<Vec<StreamEvent>> =
+            chunks.iter().map(|chunk| assembler.events(chunk)).collect();
+        assert!(events[..3].iter().all(Vec::is_empty));
+        assert!(matches!(
+            &events[3][..],
+            [StreamEvent::ToolCall { index: 0, call }]
+                if call.id == "call_a"
+                    && call.function.name == "get_weather"
+                    && call.function.arguments == "{\"city\": \"Paris\"}"
+        ));
+        assert!(matches!(
+            &events[4][..],
+            [
+                StreamEvent::ToolCall { call, .. },
+                StreamEvent::FinishReason {
+                    reason: FinishReason::ToolCalls,
+                    ..
+                }
+            ] if call.id == "call_b" && call.function.arguments == "{}"
+        ));
+        assert!(assembler.finish().is_empty());
+    }
+
+    /// Test that reading stops as soon as the callback breaks
+    #[test]
+    fn test_read_chunks_until() {
+        let mut count = 0;
+        read_chunks_until(Cursor::new(STREAM), |_| {
+            count += 1;
+            ControlFlow::Break(())
+        })
+        .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    /// Test that a malformed chunk is reported with its payload
+    #[test]
+    fn test_read_malformed_chunk() {
+        let result = read_chunks(Cursor::new("data: {\"id\":\n\n"), |_| {});
+        assert!(
+            matches!(result, Err(OpenAIError::Decode { body, .. }) if body == "{\"id\":")
+        );
+    }
+}
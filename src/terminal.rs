@@ -0,0 +1,193 @@
+use crate::openai::{ChatEntry, ChatLog, ChatRole};
+use std::io::{self, Write};
+
+/// ANSI escape that resets all styling
+const RESET: &str = "\x1b[0m";
+
+/// The ANSI style used for each role
+fn role_style(role: &ChatRole) -> &'static str {
+    match role {
+        ChatRole::System | ChatRole::Developer => "\x1b[2m",
+        ChatRole::User => "\x1b[36m",
+        ChatRole::Assistant => "\x1b[32m",
+        ChatRole::Tool => "\x1b[33m",
+    }
+}
+
+/// Wrap text to the given width on word boundaries, keeping existing line breaks.
+/// Words longer than the width are kept whole on their own line.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let needed = if line.is_empty() { 0 } else { 1 } + word.chars().count();
+            if !line.is_empty() && line.chars().count() + needed > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+impl ChatEntry {
+    /// A one line preview of the content, with runs of whitespace and newlines
+    /// collapsed to single spaces. Content longer than `max_chars` characters is
+    /// cut on a character boundary and ends with `…`, within the limit.
+    pub fn preview(&self, max_chars: usize) -> String {
+        let collapsed = self
+            .content
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if collapsed.chars().count() <= max_chars {
+            return collapsed;
+        }
+        let mut preview: String = collapsed
+            .chars()
+            .take(max_chars.saturating_sub(1))
+            .collect();
+        if max_chars > 0 {
+            preview.push('…');
+        }
+        preview
+    }
+}
+
+impl ChatLog {
+    /// One `role: preview` line per entry, see [`ChatEntry::preview`]
+    pub fn preview_lines(&self, max_chars: usize) -> Vec<String> {
+        self.entries()
+            .iter()
+            .map(|entry| format!("{}: {}", entry.role.name(), entry.preview(max_chars)))
+            .collect()
+    }
+
+    /// Print the conversation, one `role: content` block per entry. When
+    /// `use_color` is set every entry is colored by role with ANSI escapes
+    /// (system dim, user cyan, assistant green); deciding whether the output is a
+    /// terminal is left to the caller.
+    pub fn print_colored(
+        &self,
+        out: &mut impl Write,
+        use_color: bool,
+    ) -> io::Result<()> {
+        self.print(out, use_color, None)
+    }
+
+    /// Same as [`ChatLog::print_colored`], but wrapping lines to the given width
+    pub fn print_colored_wrapped(
+        &self,
+        out: &mut impl Write,
+        use_color: bool,
+        width: usize,
+    ) -> io::Result<()> {
+        self.print(out, use_color, Some(width))
+    }
+
+    /// Print the conversation, optionally colored and wrapped
+    fn print(
+        &self,
+        out: &mut impl Write,
+        use_color: bool,
+        width: Option<usize>,
+    ) -> io::Result<()> {
+        for entry in self.entries() {
+            let text = format!("{}: {}", entry.role.name(), entry.content);
+            let lines = match width {
+                Some(width) => wrap(&text, width),
+                None => vec![text],
+            };
+            if use_color {
+                write!(out, "{}", role_style(&entry.role))?;
+            }
+            for (i, line) in lines.iter().enumerate() {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+                write!(out, "{}", line)?;
+            }
+            if use_color {
+                write!(out, "{}", RESET)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a small log to print
+    fn log() -> ChatLog {
+        serde_json::from_str(
+            r#"[
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello there, how are you?"}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    /// Test printing without colors
+    #[test]
+    fn test_print_plain() {
+        let mut out = Vec::new();
+        log().print_colored(&mut out, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "system: Be brief.\nuser: Hi\nassistant: Hello there, how are you?\n"
+        );
+    }
+
+    /// Test printing with colors
+    #[test]
+    fn test_print_colored() {
+        let mut out = Vec::new();
+        log().print_colored(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[2msystem: Be brief.\x1b[0m\n"));
+        assert!(out.contains("\x1b[36muser: Hi\x1b[0m\n"));
+        assert!(out.contains("\x1b[32massistant: Hello there, how are you?\x1b[0m\n"));
+    }
+
+    /// Test one line previews of entries
+    #[test]
+    fn test_preview() {
+        let entry = ChatEntry::new(ChatRole::User, "  Hello\n\n  there,\tfriend ");
+        assert_eq!(entry.preview(100), "Hello there, friend");
+        assert_eq!(entry.preview(8), "Hello t…");
+        assert_eq!(entry.preview(0), "");
+
+        let entry = ChatEntry::new(ChatRole::User, "日本語のテキスト");
+        assert_eq!(entry.preview(4), "日本語…");
+
+        assert_eq!(
+            log().preview_lines(10),
+            vec!["system: Be brief.", "user: Hi", "assistant: Hello the…"]
+        );
+    }
+
+    /// Test wrapping long entries
+    #[test]
+    fn test_print_wrapped() {
+        let log: ChatLog = serde_json::from_str(
+            r#"[{"role": "assistant", "content": "Hello there, how are you?"}]"#,
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        log.print_colored_wrapped(&mut out, false, 16).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "assistant: Hello\nthere, how are\nyou?\n"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+#[cfg(feature = "blocking")]
+use crate::client::OpenAI;
+use serde_json::{json, Value};
+#[cfg(feature = "blocking")]
+use std::io::{Read, Write};
+#[cfg(feature = "blocking")]
+use std::net::TcpListener;
+#[cfg(feature = "blocking")]
+use std::sync::mpsc::{self, Receiver};
+#[cfg(feature = "blocking")]
+use std::thread;
+
+/// The JSON body of a completion with a single choice, as the api sends it
+pub(crate) fn completion_body(
+    model: &str,
+    content: &str,
+    finish_reason: &str,
+) -> String {
+    completion_value(model, content, finish_reason).to_string()
+}
+
+/// The JSON body of a completion like [`completion_body`], with the usage of
+/// the given tokens
+pub(crate) fn completion_body_with_usage(
+    model: &str,
+    content: &str,
+    finish_reason: &str,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+) -> String {
+    let mut body = completion_value(model, content, finish_reason);
+    body["usage"] = json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens
+    });
+    body.to_string()
+}
+
+/// The JSON value of a completion with a single choice
+fn completion_value(model: &str, content: &str, finish_reason: &str) -> Value {
+    json!({
+        "id": "chatcmpl-123",
+        "object": "chat.completion",
+        "created": 1677652288,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": finish_reason
+        }]
+    })
+}
+
+/// A client of the api at the given url
+#[cfg(feature = "blocking")]
+pub(crate) fn test_client(url: String) -> OpenAI {
+    OpenAI::builder("key".to_string())
+        .base_url(url)
+        .build()
+        .unwrap()
+}
+
+/// A request received by a test server
+#[cfg(feature = "blocking")]
+#[derive(Debug)]
+pub(crate) struct Captured {
+    /// The request line and headers
+    pub head: String,
+    /// The body of the request
+    pub body: String,
+}
+
+#[cfg(feature = "blocking")]
+impl Captured {
+    /// The value of a header, whatever the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// The body of the request, decoded as JSON
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// Serve a single response on a local port, returning its url
+#[cfg(feature = "blocking")]
+pub(crate) fn serve(
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+) -> String {
+    serve_capture(status, content_type, body).0
+}
+
+/// Serve a single response on a local port like [`serve`], also returning
+/// the request it answers
+#[cfg(feature = "blocking")]
+pub(crate) fn serve_capture(
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+) -> (String, Receiver<Captured>) {
+    serve_sequence(vec![(status, content_type, body)])
+}
+
+/// Serve a single streamed response on a local port, returning its url
+#[cfg(feature = "blocking")]
+pub(crate) fn serve_stream(body: String) -> String {
+    serve("200 OK", "text/event-stream", body)
+}
+
+/// Serve the given responses on a local port, one connection each and in
+/// order, returning the url and the requests as they are answered
+#[cfg(feature = "blocking")]
+pub(crate) fn serve_sequence(
+    responses: Vec<(&'static str, &'static str, String)>,
+) -> (String, Receiver<Captured>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, content_type, body) in responses {
+            let Ok((mut socket, _)) = listener.accept() else {
+                return;
+            };
+            let _ = sender.send(read_request(&mut socket));
+            // Closing the connection makes the client open a new one for the
+            // next response
+            let head = format!(
+                "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            );
+            let _ = socket.write_all(head.as_bytes());
+            let _ = socket.write_all(body.as_bytes());
+        }
+    });
+    (url, receiver)
+}
+
+/// Read the head of a request, then as much body as it announces
+#[cfg(feature = "blocking")]
+fn read_request(socket: &mut impl Read) -> Captured {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    let body_start = loop {
+        match socket.read(&mut buffer) {
+            Ok(0) | Err(_) => break request.len(),
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let mut captured = Captured {
+        head: String::from_utf8_lossy(&request[..body_start]).into_owned(),
+        body: String::new(),
+    };
+    let length = captured
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    while request.len() < body_start + length {
+        match socket.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+    }
+    captured.body = String::from_utf8_lossy(&request[body_start..]).into_owned();
+    captured
+}
@@ -0,0 +1,505 @@
+use crate::error::ExtractError;
+use serde::de::DeserializeOwned;
+
+/// Role labels that models sometimes echo at the start of a reply
+const ROLE_LABELS: [&str; 2] = ["assistant:", "ai:"];
+
+/// Abbreviations whose period does not end a sentence, without the last period
+const ABBREVIATIONS: [&str; 17] = [
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "etc", "e.g", "i.e",
+    "inc", "ltd", "fig", "approx", "cf",
+];
+
+/// Abbreviations that are also common words, only taken as abbreviations when
+/// a number follows, as in `No. 5`
+const NUMBER_ABBREVIATIONS: [&str; 1] = ["no"];
+
+/// Remove a role label the model echoed at the start of its reply.
+///
+/// The label is one of `Assistant:` or `AI:`, matched ignoring ASCII case, and
+/// may be preceded by whitespace. The label and the whitespace right after it
+/// are removed, anything else is returned untouched. This can strip legitimate
+/// content that happens to start like a label, so it is never applied by default.
+pub fn strip_role_prefix(content: &str) -> &str {
+    let trimmed = content.trim_start();
+    for label in ROLE_LABELS {
+        let matches = trimmed
+            .get(..label.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(label));
+        if matches {
+            return trimmed[label.len()..].trim_start();
+        }
+    }
+    content
+}
+
+/// Join a prefill with the reply generated after it. Providers that continue a
+/// trailing assistant message reply with the rest of it, others reply from the
+/// start and may repeat the prefill, so a reply that already starts with the
+/// prefill is returned as is.
+pub fn join_prefill(prefill: &str, reply: &str) -> String {
+    if reply.starts_with(prefill) {
+        reply.to_string()
+    } else {
+        format!("{}{}", prefill, reply)
+    }
+}
+
+/// A fenced code block found in a reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// The language tag after the opening fence, if any
+    pub language: Option<String>,
+    /// The code, without the fences and without a trailing newline
+    pub code: String,
+}
+
+/// A code block that has been opened but not closed yet
+struct OpenBlock<'a> {
+    /// The amount of backticks of the opening fence
+    ticks: usize,
+    /// The indentation of the opening fence, removed from the code lines
+    indent: usize,
+    /// The language tag of the opening fence
+    language: Option<String>,
+    /// The code lines seen so far
+    lines: Vec<&'a str>,
+}
+
+impl OpenBlock<'_> {
+    /// Finish the block with the lines seen so far
+    fn close(self) -> CodeBlock {
+        CodeBlock {
+            language: self.language,
+            code: self.lines.join("\n"),
+        }
+    }
+}
+
+/// The length of the leading spaces and tabs of a line
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Parse a line as a code fence, returning its amount of backticks and what
+/// follows them
+fn parse_fence(line: &str) -> Option<(usize, &str)> {
+    let trimmed = &line[indentation(line)..];
+    let ticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+    (ticks >= 3).then(|| (ticks, &trimmed[ticks..]))
+}
+
+/// Extract the code blocks fenced with three or more backticks from a reply.
+///
+/// The fences may be indented, in which case up to the same indentation is
+/// removed from every code line. A block is closed by a fence of at least as
+/// many backticks with nothing after it, so a block opened with four backticks
+/// can contain fences of three. A block that is never closed runs to the end of
+/// the content.
+pub fn extract_code_blocks(content: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut open: Option<OpenBlock> = None;
+    for line in content.lines() {
+        let fence = parse_fence(line);
+        match (open.as_mut(), fence) {
+            (Some(block), Some((ticks, rest)))
+                if ticks >= block.ticks && rest.trim().is_empty() =>
+            {
+                blocks.extend(open.take().map(OpenBlock::close));
+            }
+            (Some(block), _) => {
+                let indent = indentation(line).min(block.indent);
+                block.lines.push(&line[indent..]);
+            }
+            (None, Some((ticks, rest))) => {
+                open = Some(OpenBlock {
+                    ticks,
+                    indent: indentation(line),
+                    language: rest.split_whitespace().next().map(str::to_string),
+                    lines: Vec::new(),
+                });
+            }
+            (None, None) => {}
+        }
+    }
+    blocks.extend(open.map(OpenBlock::close));
+    blocks
+}
+
+/// Whether a word followed by a period and then by `rest` is an abbreviation or
+/// the initial of a name rather than the end of a sentence
+fn is_abbreviation(word: &str, rest: &str) -> bool {
+    let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+    let mut chars = word.chars();
+    let initial = matches!(
+        (chars.next(), chars.next()),
+        (Some(c), None) if c.is_uppercase() && c != 'I'
+    );
+    let numbered = rest.trim_start().starts_with(|c: char| c.is_ascii_digit())
+        && NUMBER_ABBREVIATIONS
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(word));
+    initial || numbered || ABBREVIATIONS.iter().any(|a| a.eq_ignore_ascii_case(word))
+}
+
+/// Split prose into sentences, appending them to `sentences`
+fn split_prose(text: &str, sentences: &mut Vec<String>) {
+    let mut push = |sentence: &str| {
+        if !sentence.trim().is_empty() {
+            sentences.push(sentence.trim().to_string());
+        }
+    };
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?' | '…') {
+            continue;
+        }
+        // Take the whole run of punctuation and closing quotes or brackets
+        let mut end = index + c.len_utf8();
+        let mut periods = usize::from(c == '.');
+        let mut ellipsis = c == '…';
+        while let Some(&(next_index, next)) = chars.peek() {
+            match next {
+                '.' => periods += 1,
+                '…' => ellipsis = true,
+                '!' | '?' | '"' | '\'' | '”' | '’' | ')' | ']' => {}
+                _ => break,
+            }
+            end = next_index + next.len_utf8();
+            chars.next();
+        }
+        let at_break = text[end..].chars().next().is_none_or(char::is_whitespace);
+        if !at_break || ellipsis || periods > 1 {
+            continue;
+        }
+        let only_period =
+            text[index..end].trim_end_matches(['"', '\'', '”', '’', ')', ']']);
+        if only_period == "." {
+            let word = text[start..index].split_whitespace().last();
+            if word.is_some_and(|word| is_abbreviation(word, &text[end..])) {
+                continue;
+            }
+        }
+        push(&text[start..end]);
+        start = end;
+    }
+    push(&text[start..]);
+}
+
+/// Split a reply into sentences, for example to feed text-to-speech one
+/// sentence at a time.
+///
+/// Sentences end at `.`, `!` or `?` followed by whitespace, keeping the
+/// punctuation and any closing quotes or brackets after it. Periods of
+/// abbreviations such as `e.g.` or `Dr.`, of initials, of decimals and of
+/// ellipses do not end a sentence, while a blank line always does. Fenced code
+/// blocks are kept whole, fences included, as a single item. Line breaks inside
+/// a sentence become spaces.
+pub fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut prose = String::new();
+    let mut block: Option<(usize, Vec<&str>)> = None;
+    for line in text.lines() {
+        let fence = parse_fence(line);
+        match (block.as_mut(), fence) {
+            (Some((ticks, lines)), Some((closing, rest)))
+                if closing >= *ticks && rest.trim().is_empty() =>
+            {
+                lines.push(line);
+                sentences.extend(block.take().map(|(_, lines)| lines.join("\n")));
+            }
+            (Some((_, lines)), _) => lines.push(line),
+            (None, Some((ticks, _))) => {
+                split_prose(&prose, &mut sentences);
+                prose.clear();
+                block = Some((ticks, vec![line]));
+            }
+            (None, None) if line.trim().is_empty() => {
+                split_prose(&prose, &mut sentences);
+                prose.clear();
+            }
+            (None, None) => {
+                if !prose.is_empty() {
+                    prose.push(' ');
+                }
+                prose.push_str(line.trim());
+            }
+        }
+    }
+    sentences.extend(block.map(|(_, lines)| lines.join("\n")));
+    split_prose(&prose, &mut sentences);
+    sentences
+}
+
+/// Find the first balanced JSON object or array in a text, skipping brackets
+/// inside strings. Starts whose brackets never balance are skipped.
+fn find_json(text: &str) -> Option<&str> {
+    let starts = text.match_indices(['{', '[']).map(|(index, _)| index);
+    starts.into_iter().find_map(|start| {
+        let mut closers = Vec::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        for (offset, c) in text[start..].char_indices() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => in_string = true,
+                '{' => closers.push('}'),
+                '[' => closers.push(']'),
+                '}' | ']' => {
+                    if closers.pop() != Some(c) {
+                        return None;
+                    }
+                    if closers.is_empty() {
+                        return Some(&text[start..start + offset + 1]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+/// Extract and deserialize JSON that a model wrapped in prose or code fences.
+///
+/// If the reply has fenced code blocks they are searched first, in order, then
+/// the whole reply. The first balanced `{...}` or `[...]` found is decoded, and
+/// is reported in the error if it does not match the expected type.
+pub fn extract_json<T: DeserializeOwned>(content: &str) -> Result<T, ExtractError> {
+    let blocks = extract_code_blocks(content);
+    let json = blocks
+        .iter()
+        .find_map(|block| find_json(&block.code))
+        .or_else(|| find_json(content))
+        .ok_or(ExtractError::NotFound)?;
+    serde_json::from_str(json).map_err(|source| ExtractError::Invalid {
+        json: json.to_string(),
+        source,
+    })
+}
+
+/// Close the strings, objects and arrays left open in JSON that was cut short,
+/// for example by the token limit, returning `None` if the JSON needs no repair
+/// or cannot be repaired.
+///
+/// The repair is conservative and only ever appends to the end: a dangling
+/// escape is dropped and the open string closed, then a trailing comma is
+/// dropped or a key without value gets `null`, then the open brackets are
+/// closed. Mismatched brackets or a cut inside a literal such as `tru` are not
+/// guessed at, and a repair is only returned if it parses. A number at the end
+/// is kept as written, since it cannot be told apart from a complete one, so
+/// it may be missing digits. The repaired JSON is valid but may be missing
+/// whatever the model did not get to write.
+pub fn repair_json(json: &str) -> Option<String> {
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in json.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' if closers.pop() != Some(c) => return None,
+            _ => {}
+        }
+    }
+    if !in_string && closers.is_empty() {
+        return None;
+    }
+
+    let mut repaired = json.to_string();
+    if in_string {
+        if escaped {
+            repaired.pop();
+        }
+        repaired.push('"');
+    } else {
+        repaired.truncate(repaired.trim_end().len());
+        if repaired.ends_with(',') {
+            repaired.pop();
+        } else if repaired.ends_with(':') {
+            repaired.push_str("null");
+        }
+    }
+    let closing: String = closers.iter().rev().collect();
+    let parses = |json: &str| serde_json::from_str::<serde_json::Value>(json).is_ok();
+    // A string cut in an object may be a key still waiting for its value
+    [
+        format!("{}{}", repaired, closing),
+        format!("{}:null{}", repaired, closing),
+    ]
+    .into_iter()
+    .find(|candidate| parses(candidate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that only leading role labels are stripped
+    #[test]
+    fn test_strip_role_prefix() {
+        assert_eq!(strip_role_prefix("Assistant: Hello"), "Hello");
+        assert_eq!(strip_role_prefix("  AI:\nHello"), "Hello");
+        assert_eq!(strip_role_prefix("assistant:Hello"), "Hello");
+        assert_eq!(strip_role_prefix("ASSISTANT: Hi"), "Hi");
+        assert_eq!(strip_role_prefix("  Hello"), "  Hello");
+        assert_eq!(strip_role_prefix("AIs are: many"), "AIs are: many");
+        assert_eq!(
+            strip_role_prefix("Said the Assistant: hi"),
+            "Said the Assistant: hi"
+        );
+        assert_eq!(strip_role_prefix("日本"), "日本");
+        assert_eq!(strip_role_prefix(""), "");
+    }
+
+    /// Test splitting sentences around abbreviations, decimals and code
+    #[test]
+    fn test_split_sentences() {
+        assert_eq!(
+            split_sentences("Hello there! How are you? I'm fine."),
+            vec!["Hello there!", "How are you?", "I'm fine."]
+        );
+        assert_eq!(
+            split_sentences(
+                "Dr. Smith paid $3.50, e.g. for J. R. R. Tolkien's book. Done"
+            ),
+            vec![
+                "Dr. Smith paid $3.50, e.g. for J. R. R. Tolkien's book.",
+                "Done"
+            ]
+        );
+        assert_eq!(
+            split_sentences("Well... maybe. He said \"stop.\" Then\nhe left."),
+            vec!["Well... maybe.", "He said \"stop.\"", "Then he left."]
+        );
+        assert_eq!(
+            split_sentences("Run this:\n```sh\necho a. b.\n```\nIt prints. Try it."),
+            vec![
+                "Run this:",
+                "```sh\necho a. b.\n```",
+                "It prints.",
+                "Try it."
+            ]
+        );
+        assert_eq!(
+            split_sentences("I said no. Then I left. See No. 5 below."),
+            vec!["I said no.", "Then I left.", "See No. 5 below."]
+        );
+        assert_eq!(split_sentences("Title\n\nBody."), vec!["Title", "Body."]);
+        assert!(split_sentences("  ").is_empty());
+    }
+
+    /// Test joining prefills with continued and restarted replies
+    #[test]
+    fn test_join_prefill() {
+        assert_eq!(join_prefill("{", "\"a\": 1}"), "{\"a\": 1}");
+        assert_eq!(join_prefill("{", "{\"a\": 1}"), "{\"a\": 1}");
+        assert_eq!(join_prefill("", "Hi"), "Hi");
+    }
+
+    /// Test extracting JSON from prose and code fences
+    #[test]
+    fn test_extract_json() {
+        let value: serde_json::Value =
+            extract_json("Sure! Here it is: {\"a\": [1, 2], \"b\": \"}]\\\"\"} Enjoy.")
+                .unwrap();
+        assert_eq!(value, serde_json::json!({"a": [1, 2], "b": "}]\""}));
+
+        let fenced = "Result:\n```json\n[1, 2, 3]\n```\nThe {braces} above";
+        let numbers: Vec<u32> = extract_json(fenced).unwrap();
+        assert_eq!(numbers, vec![1, 2, 3]);
+
+        let skipped: Vec<u32> = extract_json("a ] mismatched { ] then [4]").unwrap();
+        assert_eq!(skipped, vec![4]);
+
+        let result = extract_json::<serde_json::Value>("no json here");
+        assert!(matches!(result, Err(ExtractError::NotFound)));
+        let result = extract_json::<Vec<u32>>("{\"a\": 1}");
+        assert!(
+            matches!(result, Err(ExtractError::Invalid { json, .. }) if json == "{\"a\": 1}")
+        );
+    }
+
+    /// Test closing JSON cut at various points and refusing to guess
+    #[test]
+    fn test_repair_json() {
+        use serde_json::json;
+        let repair = |json| {
+            repair_json(json).map(|repaired| {
+                serde_json::from_str::<serde_json::Value>(&repaired).unwrap()
+            })
+        };
+        assert_eq!(repair(r#"{"a": "hel"#), Some(json!({"a": "hel"})));
+        assert_eq!(repair(r#"{"a": "x\"#), Some(json!({"a": "x"})));
+        assert_eq!(
+            repair(r#"{"a": [1, {"b": 2}, "#),
+            Some(json!({"a": [1, {"b": 2}]}))
+        );
+        assert_eq!(repair(r#"{"a": 1, "b"#), Some(json!({"a": 1, "b": null})));
+        assert_eq!(repair(r#"{"a": 1, "b":"#), Some(json!({"a": 1, "b": null})));
+        // A number at the end is kept, whether or not it was cut
+        assert_eq!(repair("[[1, 2], [3"), Some(json!([[1, 2], [3]])));
+
+        assert_eq!(repair_json(r#"{"a": 1}"#), None);
+        assert_eq!(repair_json(r#"{"a": [1}"#), None);
+        assert_eq!(repair_json(r#"{"a": tr"#), None);
+        assert_eq!(repair_json(r#"["\u00"#), None);
+    }
+
+    /// Test extracting fenced code blocks
+    #[test]
+    fn test_extract_code_blocks() {
+        let content = "Here:\n```rust\nfn main() {}\n```\nand\n```\n\nplain\n```\n";
+        assert_eq!(
+            extract_code_blocks(content),
+            vec![
+                CodeBlock {
+                    language: Some("rust".to_string()),
+                    code: "fn main() {}".to_string(),
+                },
+                CodeBlock {
+                    language: None,
+                    code: "\nplain".to_string(),
+                },
+            ]
+        );
+        assert!(extract_code_blocks("no code here").is_empty());
+    }
+
+    /// Test nested, indented and unterminated fences
+    #[test]
+    fn test_extract_tricky_code_blocks() {
+        let nested = "````md\n```py\nprint(1)\n```\n````";
+        let blocks = extract_code_blocks(nested);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].language.as_deref(), Some("md"));
+        assert_eq!(blocks[0].code, "```py\nprint(1)\n```");
+
+        let indented = "1. Run:\n   ```sh\n   cargo test\n     --all\n   ```";
+        let blocks = extract_code_blocks(indented);
+        assert_eq!(blocks[0].code, "cargo test\n  --all");
+
+        let unterminated = "```js\nlet a = 1;\nlet b = 2;";
+        let blocks = extract_code_blocks(unterminated);
+        assert_eq!(blocks[0].code, "let a = 1;\nlet b = 2;");
+    }
+}
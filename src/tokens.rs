@@ -0,0 +1,496 @@
+use crate::error::OpenAIError;
+use crate::model::Model;
+use crate::openai::{ChatCompletionRequest, ChatLog, CompletionUsage};
+use crate::tools::Tool;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+#[cfg(feature = "tiktoken")]
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+
+/// The most choices one chat completion request can ask for with `n`
+pub const MAX_CHOICES: usize = 128;
+
+/// Counts the tokens of texts, trading accuracy for speed as the implementation
+/// sees fit
+pub trait TokenCounter {
+    /// Count the tokens of a text for the given model
+    fn count(&self, text: &str, model: &Model) -> usize;
+}
+
+/// A cheap estimate of one token every four characters, rounded up
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicCounter;
+
+impl TokenCounter for HeuristicCounter {
+    fn count(&self, text: &str, _model: &Model) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// Exact counting with the tiktoken encoding of the model, see [`count_tokens`]
+#[cfg(feature = "tiktoken")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BpeCounter;
+
+#[cfg(feature = "tiktoken")]
+impl TokenCounter for BpeCounter {
+    fn count(&self, text: &str, model: &Model) -> usize {
+        count_tokens(text, model)
+    }
+}
+
+/// The counter used when none is configured: [`BpeCounter`] with the `tiktoken`
+/// feature, [`HeuristicCounter`] without it
+#[cfg(feature = "tiktoken")]
+pub static DEFAULT_COUNTER: &(dyn TokenCounter + Send + Sync) = &BpeCounter;
+
+/// The counter used when none is configured: [`BpeCounter`] with the `tiktoken`
+/// feature, [`HeuristicCounter`] without it
+#[cfg(not(feature = "tiktoken"))]
+pub static DEFAULT_COUNTER: &(dyn TokenCounter + Send + Sync) = &HeuristicCounter;
+
+/// A boxed [`DEFAULT_COUNTER`]
+pub fn default_counter() -> Box<dyn TokenCounter + Send + Sync> {
+    #[cfg(feature = "tiktoken")]
+    return Box::new(BpeCounter);
+    #[cfg(not(feature = "tiktoken"))]
+    return Box::new(HeuristicCounter);
+}
+
+/// Count the tokens of a text with the encoding used by the given model. Models
+/// unknown to tiktoken are counted with `cl100k_base`.
+#[cfg(feature = "tiktoken")]
+pub fn count_tokens(text: &str, model: &Model) -> usize {
+    let bpe = match get_tokenizer(model.name()) {
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        _ => tiktoken_rs::cl100k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+impl ChatLog {
+    /// Count the tokens of the contents of all the entries. The few tokens the
+    /// api adds around every message are not included.
+    pub fn count_tokens(&self, counter: &dyn TokenCounter, model: &Model) -> usize {
+        self.entries()
+            .iter()
+            .map(|entry| counter.count(&entry.content, model))
+            .sum()
+    }
+
+    /// Add the content of a text file as a user message headed by
+    /// `--- file: <path> ---`, if the message fits in `budget` tokens counted
+    /// with the [`DEFAULT_COUNTER`]. Fails with [`OpenAIError::ContextTooLong`]
+    /// if it does not, and with an [`io::ErrorKind::InvalidData`] error if the
+    /// file is not valid UTF-8.
+    pub fn add_file(
+        &mut self,
+        path: &Path,
+        model: &Model,
+        budget: usize,
+    ) -> Result<(), OpenAIError> {
+        self.add_file_with(DEFAULT_COUNTER, path, model, budget)
+    }
+
+    /// Add the content of a text file like [`ChatLog::add_file`], counting the
+    /// tokens of the message with the given counter
+    pub fn add_file_with(
+        &mut self,
+        counter: &dyn TokenCounter,
+        path: &Path,
+        model: &Model,
+        budget: usize,
+    ) -> Result<(), OpenAIError> {
+        let bytes = fs::read(path)?;
+        let content = String::from_utf8(bytes).map_err(|_| {
+            let message = format!("{} is not valid UTF-8", path.display());
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })?;
+        let message = format!("--- file: {} ---\n{}", path.display(), content);
+        let tokens = counter.count(&message, model);
+        if tokens > budget {
+            return Err(OpenAIError::ContextTooLong {
+                tokens,
+                max: budget,
+            });
+        }
+        self.push_user(message);
+        Ok(())
+    }
+}
+
+/// Estimate the prompt tokens of tool definitions, counted with the
+/// [`DEFAULT_COUNTER`]. The api adds the schemas of the tools to the prompt, so
+/// they count against the context window like messages do.
+pub fn count_tools_tokens(tools: &[Tool], model: &Model) -> usize {
+    count_tools_tokens_with(DEFAULT_COUNTER, tools, model)
+}
+
+/// Estimate the prompt tokens of tool definitions with the given counter
+pub fn count_tools_tokens_with(
+    counter: &dyn TokenCounter,
+    tools: &[Tool],
+    model: &Model,
+) -> usize {
+    count_schema_tokens(counter, tools, model)
+}
+
+/// Estimate the tokens of definitions from their JSON, or zero if there are none.
+/// The api renders them in its own compact format, which the JSON
+/// approximates from above.
+pub(crate) fn count_schema_tokens<T: Serialize>(
+    counter: &dyn TokenCounter,
+    definitions: &[T],
+    model: &Model,
+) -> usize {
+    if definitions.is_empty() {
+        return 0;
+    }
+    let json =
+        serde_json::to_string(definitions).expect("definitions always serialize");
+    counter.count(&json, model)
+}
+
+impl ChatCompletionRequest {
+    /// Estimate the prompt tokens of the whole request: the contents of its
+    /// messages and the definitions of its tools or legacy functions
+    pub fn count_tokens(&self, counter: &dyn TokenCounter) -> usize {
+        let model = self.model();
+        self.messages().count_tokens(counter, model)
+            + count_schema_tokens(counter, self.tools(), model)
+            + count_schema_tokens(counter, self.functions(), model)
+    }
+}
+
+/// The signed difference in estimated prompt tokens between two logs, negative
+/// when `after` is shorter, counted with the [`DEFAULT_COUNTER`]
+pub fn token_delta(before: &ChatLog, after: &ChatLog, model: &Model) -> isize {
+    token_delta_with(DEFAULT_COUNTER, before, after, model)
+}
+
+/// The signed difference in prompt tokens between two logs, counted with the
+/// given counter
+pub fn token_delta_with(
+    counter: &dyn TokenCounter,
+    before: &ChatLog,
+    after: &ChatLog,
+    model: &Model,
+) -> isize {
+    let before = before.count_tokens(counter, model) as isize;
+    let after = after.count_tokens(counter, model) as isize;
+    after - before
+}
+
+/// The signed difference in USD of the prompt cost of one call between two logs,
+/// or `None` if the model has no known pricing
+pub fn cost_delta(before: &ChatLog, after: &ChatLog, model: &Model) -> Option<f64> {
+    let pricing = model.pricing()?;
+    let delta = token_delta(before, after, model);
+    Some(delta as f64 * pricing.prompt / 1_000_000.0)
+}
+
+/// The worst case of a generation continued over several calls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContinuationPreview {
+    /// The calls made, the first one and every continuation
+    pub calls: u32,
+    /// The prompt tokens of all the calls
+    pub prompt_tokens: u64,
+    /// The completion tokens of all the calls
+    pub completion_tokens: u64,
+    /// The cost in USD of all the calls, or `None` if the model has no known
+    /// pricing
+    pub cost_usd: Option<f64>,
+}
+
+impl ContinuationPreview {
+    /// The tokens of all the calls
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// The upper bound of the tokens and cost of a reply continued up to
+/// `max_continuations` times, every call generating `max_tokens`.
+///
+/// Every continuation sends the log again with the reply so far, so its prompt
+/// grows by `max_tokens` per call. Prompts are capped by the context window of
+/// the model when it is known, since longer ones would be rejected.
+pub fn continuation_preview(
+    counter: &dyn TokenCounter,
+    log: &ChatLog,
+    model: &Model,
+    max_tokens: u32,
+    max_continuations: u32,
+) -> ContinuationPreview {
+    let base = log.count_tokens(counter, model) as u64;
+    let max_prompt = model
+        .context_window()
+        .map(|window| u64::from(window.saturating_sub(max_tokens)));
+    let calls = max_continuations + 1;
+    let prompt_tokens = (0..u64::from(calls))
+        .map(|call| {
+            let prompt = base + call * u64::from(max_tokens);
+            max_prompt.map_or(prompt, |max| prompt.min(max))
+        })
+        .sum();
+    let completion_tokens = u64::from(calls) * u64::from(max_tokens);
+    let cost_usd = model.pricing().map(|pricing| {
+        (prompt_tokens as f64 * pricing.prompt
+            + completion_tokens as f64 * pricing.completion)
+            / 1_000_000.0
+    });
+    ContinuationPreview {
+        calls,
+        prompt_tokens,
+        completion_tokens,
+        cost_usd,
+    }
+}
+
+/// How many choices of about `est_completion_tokens` each one request for
+/// `prompt_tokens` can ask for with `n` without costing more than
+/// `budget_usd`. The prompt is paid once however many choices there are.
+///
+/// Returns 0 if the model has no known pricing or even one choice goes over
+/// the budget. The result is capped at [`MAX_CHOICES`], the most the api
+/// accepts, which is also what choices that cost nothing get.
+pub fn max_choices_for_budget(
+    prompt_tokens: usize,
+    est_completion_tokens: usize,
+    budget_usd: f64,
+    model: &Model,
+) -> usize {
+    let Some(pricing) = model.pricing() else {
+        return 0;
+    };
+    let prompt_cost = prompt_tokens as f64 * pricing.prompt / 1_000_000.0;
+    let choice_cost = est_completion_tokens as f64 * pricing.completion / 1_000_000.0;
+    // Allow for rounding, so that a budget of exactly one choice affords it
+    let remaining = budget_usd - prompt_cost + 1e-12;
+    if remaining < choice_cost || remaining <= 0.0 {
+        0
+    } else if choice_cost <= 0.0 {
+        MAX_CHOICES
+    } else {
+        // The cast saturates for budgets too large to count
+        ((remaining / choice_cost).floor() as usize).min(MAX_CHOICES)
+    }
+}
+
+/// The usage and cost of one completion of a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnCost {
+    /// The model that served the completion
+    pub model: Model,
+    /// The usage of the completion
+    pub usage: CompletionUsage,
+    /// The cost in USD of the completion, or `None` if the model has no known
+    /// pricing
+    pub cost_usd: Option<f64>,
+}
+
+/// The running usage and cost of a session, one turn per completion
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SessionCost {
+    /// The completions so far, in order
+    turns: Vec<TurnCost>,
+}
+
+impl SessionCost {
+    /// Create an empty session
+    pub fn new() -> SessionCost {
+        SessionCost::default()
+    }
+
+    /// Add the usage of a completion served by a model
+    pub fn record(&mut self, model: impl Into<Model>, usage: CompletionUsage) {
+        let model = model.into();
+        let cost_usd = usage.cost_usd(&model);
+        self.turns.push(TurnCost {
+            model,
+            usage,
+            cost_usd,
+        });
+    }
+
+    /// The completions so far, in order
+    pub fn turns(&self) -> &[TurnCost] {
+        &self.turns
+    }
+
+    /// The cost in USD of the session so far. Turns of models without known
+    /// pricing are not counted, see [`SessionCost::is_partial`].
+    pub fn total_cost_usd(&self) -> f64 {
+        self.turns.iter().filter_map(|turn| turn.cost_usd).sum()
+    }
+
+    /// Whether some turns are missing from the total cost, having no known
+    /// pricing
+    pub fn is_partial(&self) -> bool {
+        self.turns.iter().any(|turn| turn.cost_usd.is_none())
+    }
+
+    /// The tokens of the session so far, prompts and completions
+    pub fn total_tokens(&self) -> u64 {
+        let tokens = self
+            .turns
+            .iter()
+            .map(|turn| u64::from(turn.usage.total_tokens));
+        tokens.sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test counting tokens with the encodings of different models
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(count_tokens("Hello, world!", &Model::Gpt35Turbo), 4);
+        assert_eq!(count_tokens("Hello, world!", &Model::Gpt4o), 4);
+        assert_eq!(count_tokens("", &Model::Other("llama".to_string())), 0);
+    }
+
+    /// Test the heuristic counter on a chat log
+    #[test]
+    fn test_heuristic_counter() {
+        let counter = HeuristicCounter;
+        assert_eq!(counter.count("", &Model::Gpt4o), 0);
+        assert_eq!(counter.count("abcde", &Model::Gpt4o), 2);
+        assert_eq!(counter.count("日本語です", &Model::Gpt4o), 2);
+
+        let mut log = ChatLog::new();
+        log.push_system("abcd");
+        log.push_user("abcdefgh");
+        assert_eq!(log.count_tokens(&counter, &Model::Gpt4o), 3);
+    }
+
+    /// Test attaching files within and over the budget
+    #[test]
+    fn test_add_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = dir.path().join("notes.txt");
+        fs::write(&notes, "Buy milk.").unwrap();
+        let binary = dir.path().join("image.png");
+        fs::write(&binary, [0x89, 0xff, 0xfe]).unwrap();
+
+        let mut log = ChatLog::new();
+        log.add_file(&notes, &Model::Gpt4o, 1000).unwrap();
+        let content = &log.entries()[0].content;
+        assert!(content.starts_with("--- file: "));
+        assert!(content.ends_with("notes.txt ---\nBuy milk."));
+
+        let result = log.add_file(&notes, &Model::Gpt4o, 2);
+        assert!(matches!(
+            result,
+            Err(OpenAIError::ContextTooLong { max: 2, .. })
+        ));
+        let result = log.add_file(&binary, &Model::Gpt4o, 1000);
+        assert!(
+            matches!(result, Err(OpenAIError::Io(e)) if e.kind() == io::ErrorKind::InvalidData)
+        );
+        assert_eq!(log.len(), 1);
+
+        let message = format!("--- file: {} ---\nBuy milk.", notes.display());
+        let tokens = HeuristicCounter.count(&message, &Model::Gpt4o);
+        let result =
+            log.add_file_with(&HeuristicCounter, &notes, &Model::Gpt4o, tokens - 1);
+        assert!(matches!(
+            result,
+            Err(OpenAIError::ContextTooLong { tokens: t, .. }) if t == tokens
+        ));
+        log.add_file_with(&HeuristicCounter, &notes, &Model::Gpt4o, tokens)
+            .unwrap();
+        assert_eq!(log.len(), 2);
+    }
+
+    /// Test that tool definitions are part of the request estimate
+    #[test]
+    fn test_count_tools_tokens() {
+        let counter = HeuristicCounter;
+        let model = Model::Gpt4o;
+        let tools = vec![Tool::function(
+            "get_weather",
+            "Get the weather of a city",
+            serde_json::json!({
+                "type": "object",
+                "properties": {"city": {"type": "string"}}
+            }),
+        )];
+        let json = serde_json::to_string(&tools).unwrap();
+        let tool_tokens = count_tools_tokens_with(&counter, &tools, &model);
+        assert_eq!(tool_tokens, json.len().div_ceil(4));
+        assert_eq!(count_tools_tokens_with(&counter, &[], &model), 0);
+
+        let mut log = ChatLog::new();
+        log.push_user("What is the weather in Paris?");
+        let request = ChatCompletionRequest::new(model.clone(), log.clone());
+        let message_tokens = log.count_tokens(&counter, &model);
+        assert_eq!(request.count_tokens(&counter), message_tokens);
+        let request = request.with_tools(tools.clone());
+        assert_eq!(request.count_tokens(&counter), message_tokens + tool_tokens);
+        let request = request.use_legacy_functions(true);
+        assert!(request.count_tokens(&counter) > message_tokens);
+    }
+
+    /// Test the worst case of a continued generation
+    #[test]
+    fn test_continuation_preview() {
+        let mut log = ChatLog::new();
+        log.push_user("a".repeat(400));
+
+        let preview =
+            continuation_preview(&HeuristicCounter, &log, &Model::Gpt4o, 1000, 2);
+        assert_eq!(preview.calls, 3);
+        assert_eq!(preview.prompt_tokens, 100 + 1100 + 2100);
+        assert_eq!(preview.completion_tokens, 3000);
+        assert_eq!(preview.total_tokens(), 6300);
+        let cost = preview.cost_usd.unwrap();
+        assert!((cost - (3300.0 * 2.5 + 3000.0 * 10.0) / 1_000_000.0).abs() < 1e-12);
+
+        let preview =
+            continuation_preview(&HeuristicCounter, &log, &Model::Gpt4, 4000, 2);
+        assert_eq!(preview.prompt_tokens, 100 + 4100 + 4192);
+        let preview =
+            continuation_preview(&HeuristicCounter, &log, &Model::from("x"), 10, 0);
+        assert_eq!(preview.cost_usd, None);
+    }
+
+    /// Test how many choices fit a budget once the prompt is paid
+    #[test]
+    fn test_max_choices_for_budget() {
+        let model = Model::Gpt4oMini;
+        // $0.00015 for the prompt and $0.0003 for every choice
+        assert_eq!(max_choices_for_budget(1000, 500, 0.01, &model), 32);
+        assert_eq!(max_choices_for_budget(1000, 500, 0.05, &model), MAX_CHOICES);
+        assert_eq!(max_choices_for_budget(1000, 500, 0.00045, &model), 1);
+        assert_eq!(max_choices_for_budget(1000, 500, 0.0004, &model), 0);
+        assert_eq!(max_choices_for_budget(1000, 500, 0.0001, &model), 0);
+        assert_eq!(max_choices_for_budget(1000, 0, 0.001, &model), MAX_CHOICES);
+        assert_eq!(max_choices_for_budget(1000, 0, 0.0001, &model), 0);
+        assert_eq!(max_choices_for_budget(10, 10, 1.0, &Model::from("x")), 0);
+    }
+
+    /// Test the token and cost difference of a shortened prompt
+    #[test]
+    fn test_token_delta() {
+        let mut before = ChatLog::new();
+        before.push_system("a".repeat(400));
+        let mut after = ChatLog::new();
+        after.push_system("a".repeat(40));
+
+        let counter = HeuristicCounter;
+        let model = Model::Gpt4o;
+        assert_eq!(token_delta_with(&counter, &before, &after, &model), -90);
+        assert_eq!(token_delta_with(&counter, &after, &before, &model), 90);
+        assert!(token_delta(&before, &after, &model) < 0);
+        assert!(cost_delta(&before, &after, &model).unwrap() < 0.0);
+        assert_eq!(cost_delta(&before, &after, &Model::from("llama")), None);
+    }
+}
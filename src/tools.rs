@@ -0,0 +1,189 @@
+use crate::error::ToolError;
+use serde::{Deserialize, Serialize};
+
+/// The kinds of tools that can be given to a model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ToolType {
+    /// A function the model can call
+    #[serde(rename = "function")]
+    Function,
+}
+
+/// A function the model can call, described by a JSON schema of its parameters
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    /// The name of the function
+    pub name: String,
+    /// What the function does, used by the model to decide when to call it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The JSON schema of the function parameters
+    pub parameters: serde_json::Value,
+}
+
+/// A tool the model can use
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    /// The kind of the tool
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    /// The function of the tool
+    pub function: FunctionDefinition,
+}
+
+impl Tool {
+    /// Create a function tool
+    pub fn function(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Tool {
+        Tool {
+            kind: ToolType::Function,
+            function: FunctionDefinition {
+                name: name.into(),
+                description: Some(description.into()),
+                parameters,
+            },
+        }
+    }
+
+    /// Check that the parameters are a JSON schema object the api accepts:
+    /// an object with a `type`, whose `properties`, if any, are an object of
+    /// schema objects, and whose `required` names, if any, are properties. An
+    /// empty object means the function takes no parameters and is accepted.
+    /// The clients check the tools of every request before sending it.
+    pub fn validate(&self) -> Result<(), ToolError> {
+        let tool = || self.function.name.clone();
+        let parameters = self.function.parameters.as_object();
+        let parameters =
+            parameters.ok_or_else(|| ToolError::NotAnObject { tool: tool() })?;
+        if parameters.is_empty() {
+            return Ok(());
+        }
+        if !parameters.contains_key("type") {
+            return Err(ToolError::MissingType { tool: tool() });
+        }
+        let properties = match parameters.get("properties") {
+            None => None,
+            Some(properties) => Some(
+                properties
+                    .as_object()
+                    .ok_or_else(|| ToolError::InvalidProperties { tool: tool() })?,
+            ),
+        };
+        for (property, schema) in properties.into_iter().flatten() {
+            if !schema.is_object() {
+                return Err(ToolError::InvalidProperty {
+                    tool: tool(),
+                    property: property.clone(),
+                });
+            }
+        }
+        let Some(required) = parameters.get("required") else {
+            return Ok(());
+        };
+        let required = required
+            .as_array()
+            .ok_or_else(|| ToolError::InvalidRequired { tool: tool() })?;
+        for name in required {
+            let name = name
+                .as_str()
+                .ok_or_else(|| ToolError::InvalidRequired { tool: tool() })?;
+            if !properties.is_some_and(|properties| properties.contains_key(name)) {
+                return Err(ToolError::UnknownRequired {
+                    tool: tool(),
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A function call requested by the model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    /// The name of the function to call
+    pub name: String,
+    /// The arguments of the call, as a JSON encoded string
+    pub arguments: String,
+}
+
+/// A tool call requested by the model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    /// The id of the call, used to match it with its result
+    pub id: String,
+    /// The kind of tool being called
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    /// The function being called
+    pub function: FunctionCall,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Test accepting valid schemas and pointing at the faults of invalid ones
+    #[test]
+    fn test_validate() {
+        let tool =
+            |parameters| Tool::function("weather", "Get the weather", parameters);
+        let valid = json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"]
+        });
+        assert!(tool(valid).validate().is_ok());
+        assert!(tool(json!({"type": "object"})).validate().is_ok());
+        // No parameters at all
+        assert!(tool(json!({})).validate().is_ok());
+
+        let invalid = [
+            (
+                json!("object"),
+                ToolError::NotAnObject {
+                    tool: "weather".into(),
+                },
+            ),
+            (
+                json!({"properties": {}}),
+                ToolError::MissingType {
+                    tool: "weather".into(),
+                },
+            ),
+            (
+                json!({"type": "object", "properties": []}),
+                ToolError::InvalidProperties {
+                    tool: "weather".into(),
+                },
+            ),
+            (
+                json!({"type": "object", "properties": {"city": "string"}}),
+                ToolError::InvalidProperty {
+                    tool: "weather".into(),
+                    property: "city".into(),
+                },
+            ),
+            (
+                json!({"type": "object", "required": "city"}),
+                ToolError::InvalidRequired {
+                    tool: "weather".into(),
+                },
+            ),
+            (
+                json!({"type": "object", "properties": {}, "required": ["city"]}),
+                ToolError::UnknownRequired {
+                    tool: "weather".into(),
+                    name: "city".into(),
+                },
+            ),
+        ];
+        for (parameters, error) in invalid {
+            assert_eq!(tool(parameters).validate(), Err(error));
+        }
+    }
+}